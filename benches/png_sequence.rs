@@ -0,0 +1,82 @@
+//! Writes a PNG sequence of 100 frames with one thread and with all of them.
+//!
+//! Run with `cargo bench --bench png_sequence`.
+
+use std::time::{Duration, Instant};
+
+use pointillist::{
+    dots::DotFrame,
+    render::RenderOptions,
+    sequence::{ExistingFrames, write_png_sequence},
+};
+
+const FRAMES: usize = 100;
+const RUNS: u32 = 3;
+
+/// Rings that grow from the center, so every frame compresses a little differently.
+fn frames() -> Vec<DotFrame> {
+    let (width, height) = (48, 32);
+    (0..FRAMES)
+        .map(|i| {
+            let buffer = (0..width * height)
+                .map(|p| {
+                    let (x, y) = ((p % width) as f32 - 24.0, (p / width) as f32 - 16.0);
+                    ((x.hypot(y) - i as f32 * 0.3).sin() * 127.0 + 128.0) as usize
+                })
+                .collect();
+            DotFrame {
+                width: width as u16,
+                height: height as u16,
+                buffer,
+                colors: vec![(255, 255, 255); width * height],
+                alpha: vec![255; width * height],
+                negative: Vec::new(),
+                delay: None,
+            }
+        })
+        .collect()
+}
+
+fn bench(frames: &[DotFrame], options: &RenderOptions, threads: usize) -> Duration {
+    let dir = std::env::temp_dir().join(format!("pointillist-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("frame.png");
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        let existing = ExistingFrames::Overwrite;
+        write_png_sequence(
+            path.to_str().unwrap(),
+            frames,
+            options,
+            existing,
+            threads,
+            None,
+        )
+        .unwrap();
+    }
+    let elapsed = start.elapsed() / RUNS;
+    std::fs::remove_dir_all(dir).unwrap();
+    elapsed
+}
+
+fn main() {
+    let frames = frames();
+    let options = RenderOptions {
+        start_radius: 4,
+        end_radius: 4,
+        spacing: 10,
+        max_value: 255,
+        ..RenderOptions::default()
+    };
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+
+    let serial = bench(&frames, &options, 1);
+    let parallel = bench(&frames, &options, threads);
+    println!("{FRAMES} frames, serial:       {:>8.1?}", serial);
+    println!(
+        "{FRAMES} frames, {threads:>2} threads: {:>8.1?} ({:.1}x)",
+        parallel,
+        serial.as_secs_f64() / parallel.as_secs_f64()
+    );
+}
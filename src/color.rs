@@ -0,0 +1,279 @@
+//! Color space conversions and the brightness measures used as dot metrics.
+//!
+//! Channels are `u8` sRGB values, hues are in degrees (0.0..360.0) and saturation, value and
+//! lightness are in the range 0.0..=1.0, except for CIELAB which uses its usual ranges.
+//! Converting a color to HSV or HSL and back gives exactly the original channels again.
+
+/// An sRGB color.
+pub type Rgb = (u8, u8, u8);
+
+/// An sRGB color with its opacity, the pixels of the frames.
+pub type Rgba = (u8, u8, u8, u8);
+
+pub const TRANSPARENT: Rgba = (0, 0, 0, 0);
+
+/// GIFs only know fully opaque or fully transparent pixels.
+pub fn is_opaque((_, _, _, a): Rgba) -> bool {
+    a >= 128
+}
+
+#[inline(always)]
+pub fn human_perceived_brightness(r: u8, g: u8, b: u8) -> u8 {
+    weighted_brightness(r, g, b, (0.299, 0.587, 0.114))
+}
+
+/// The root of the weighted sum of the squared channels, the weights should sum up to 1.
+#[inline(always)]
+pub fn weighted_brightness(r: u8, g: u8, b: u8, (wr, wg, wb): (f32, f32, f32)) -> u8 {
+    (wr * (r as f32).powi(2) + wg * (g as f32).powi(2) + wb * (b as f32).powi(2))
+        .sqrt()
+        .round() as u8
+}
+
+/// Converts an sRGB channel value into linear light in the range 0.0..=1.0.
+#[inline(always)]
+pub fn srgb_to_linear(c: u8) -> f32 {
+    let c = c as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts linear light in the range 0.0..=1.0 back into an sRGB channel value.
+#[inline(always)]
+pub fn linear_to_srgb(c: f32) -> u8 {
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    };
+    (c.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// The hue, chroma and largest channel of a color, shared by HSV and HSL.
+/// Grays have a chroma of zero and get a hue of zero.
+fn hue_chroma_max((r, g, b): Rgb) -> (f32, f32, f32) {
+    let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let chroma = max - r.min(g).min(b);
+
+    let hue = if chroma == 0.0 {
+        0.0
+    } else if max == r {
+        (g - b) / chroma
+    } else if max == g {
+        (b - r) / chroma + 2.0
+    } else {
+        (r - g) / chroma + 4.0
+    };
+    ((hue * 60.0).rem_euclid(360.0), chroma, max)
+}
+
+/// Builds a color from its hue and chroma, `m` is added to every channel.
+fn from_hue_chroma(hue: f32, chroma: f32, m: f32) -> Rgb {
+    let hue = hue.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let channel = |c: f32| ((c + m).clamp(0.0, 1.0) * 255.0).round() as u8;
+    (channel(r), channel(g), channel(b))
+}
+
+/// Converts a color into hue, saturation and value.
+pub fn rgb_to_hsv(color: Rgb) -> (f32, f32, f32) {
+    let (hue, chroma, max) = hue_chroma_max(color);
+    let saturation = if max == 0.0 { 0.0 } else { chroma / max };
+    (hue, saturation, max)
+}
+
+/// Converts hue, saturation and value back into a color.
+pub fn hsv_to_rgb((h, s, v): (f32, f32, f32)) -> Rgb {
+    let chroma = v * s;
+    from_hue_chroma(h, chroma, v - chroma)
+}
+
+/// Converts a color into hue, saturation and lightness.
+pub fn rgb_to_hsl(color: Rgb) -> (f32, f32, f32) {
+    let (hue, chroma, max) = hue_chroma_max(color);
+    let lightness = max - chroma / 2.0;
+    let saturation = if lightness == 0.0 || lightness == 1.0 {
+        0.0
+    } else {
+        chroma / (1.0 - (2.0 * lightness - 1.0).abs())
+    };
+    (hue, saturation, lightness)
+}
+
+/// Converts hue, saturation and lightness back into a color.
+pub fn hsl_to_rgb((h, s, l): (f32, f32, f32)) -> Rgb {
+    let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+    from_hue_chroma(h, chroma, l - chroma / 2.0)
+}
+
+/// Rotates the hue of a color by `degrees` around the HSV color wheel.
+pub fn rotate_hue(color: Rgb, degrees: f32) -> Rgb {
+    let (h, s, v) = rgb_to_hsv(color);
+    if s == 0.0 {
+        return color; // Grays have no hue
+    }
+    hsv_to_rgb((h + degrees, s, v))
+}
+
+/// Composites a color with the opacity `alpha` over an opaque background.
+pub fn flatten((r, g, b): Rgb, alpha: u8, (br, bg, bb): Rgb) -> Rgb {
+    let a = alpha as f32 / 255.0;
+    let mix = |c: u8, background: u8| (c as f32 * a + background as f32 * (1.0 - a)).round() as u8;
+    (mix(r, br), mix(g, bg), mix(b, bb))
+}
+
+/// The CIELAB companding function, linear near black.
+fn lab_f(t: f32) -> f32 {
+    if t > 216.0 / 24389.0 {
+        t.cbrt()
+    } else {
+        (24389.0 / 27.0 * t + 16.0) / 116.0
+    }
+}
+
+/// Converts a color into CIELAB L*a*b* with a D65 white point, L* is in 0.0..=100.0.
+pub fn rgb_to_lab((r, g, b): Rgb) -> (f32, f32, f32) {
+    let (r, g, b) = (srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b));
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.119192 * g + 0.9503041 * b) / 1.08883;
+
+    let (fx, fy, fz) = (lab_f(x), lab_f(y), lab_f(z));
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+/// Computes the CIELAB L* (0.0..=100.0) of an sRGB color with a D65 white point.
+///
+/// Only the Y component of XYZ is needed for the lightness, so the conversion skips X and Z.
+pub fn lab_lightness(r: u8, g: u8, b: u8) -> f32 {
+    let y = 0.2126 * srgb_to_linear(r) + 0.7152 * srgb_to_linear(g) + 0.0722 * srgb_to_linear(b);
+    if y > 216.0 / 24389.0 {
+        116.0 * y.cbrt() - 16.0
+    } else {
+        y * 24389.0 / 27.0
+    }
+}
+
+/// Parses a hex color like `ff8800` or `#ff8800`.
+pub fn parse_color(s: &str) -> Result<Rgb, String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("Expected a color like RRGGBB, got: {}", s));
+    }
+    let channel = |i: usize| {
+        u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("Invalid color: {}", s))
+    };
+    Ok((channel(0)?, channel(2)?, channel(4)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every 24 bit color.
+    fn all_colors() -> impl Iterator<Item = Rgb> {
+        (0..=0xffffffu32).map(|c| ((c >> 16) as u8, (c >> 8) as u8, c as u8))
+    }
+
+    #[test]
+    fn hsv_round_trips_every_color() {
+        for color in all_colors() {
+            assert_eq!(hsv_to_rgb(rgb_to_hsv(color)), color);
+        }
+    }
+
+    #[test]
+    fn hsl_round_trips_every_color() {
+        for color in all_colors() {
+            assert_eq!(hsl_to_rgb(rgb_to_hsl(color)), color);
+        }
+    }
+
+    #[test]
+    fn hsv_and_hsl_of_known_colors() {
+        assert_eq!(rgb_to_hsv((255, 0, 0)), (0.0, 1.0, 1.0));
+        assert_eq!(rgb_to_hsv((0, 0, 255)), (240.0, 1.0, 1.0));
+        assert_eq!(rgb_to_hsl((0, 255, 0)), (120.0, 1.0, 0.5));
+        // Grays have no hue or saturation
+        assert_eq!(rgb_to_hsl((255, 255, 255)), (0.0, 0.0, 1.0));
+        assert_eq!(rgb_to_hsv((0, 0, 0)), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn hues_wrap_around() {
+        assert_eq!(hsv_to_rgb((360.0, 1.0, 1.0)), (255, 0, 0));
+        assert_eq!(hsl_to_rgb((-120.0, 1.0, 0.5)), (0, 0, 255));
+    }
+
+    #[test]
+    fn lightness_of_known_colors() {
+        let close = |(r, g, b): Rgb, expected: f32| {
+            let lightness = lab_lightness(r, g, b);
+            assert!(
+                (lightness - expected).abs() < 0.05,
+                "{lightness} for {r},{g},{b}"
+            );
+        };
+        close((0, 0, 0), 0.0);
+        close((255, 255, 255), 100.0);
+        // Half the channel value is far brighter than half the lightness
+        close((128, 128, 128), 53.59);
+        close((119, 119, 119), 50.03);
+        close((255, 0, 0), 53.24);
+        close((0, 255, 0), 87.73);
+        close((0, 0, 255), 32.30);
+    }
+
+    #[test]
+    fn red_weights_give_the_red_channel() {
+        for (r, g, b) in [(0, 255, 255), (17, 3, 200), (255, 0, 0), (128, 128, 128)] {
+            assert_eq!(weighted_brightness(r, g, b, (1.0, 0.0, 0.0)), r);
+        }
+        assert_eq!(
+            weighted_brightness(10, 20, 30, (0.299, 0.587, 0.114)),
+            human_perceived_brightness(10, 20, 30)
+        );
+    }
+
+    #[test]
+    fn lab_of_known_colors() {
+        let close = |color: Rgb, (l, a, b): (f32, f32, f32)| {
+            let lab = rgb_to_lab(color);
+            let off = (lab.0 - l)
+                .abs()
+                .max((lab.1 - a).abs())
+                .max((lab.2 - b).abs());
+            assert!(off < 0.1, "{lab:?} for {color:?}");
+        };
+        close((0, 0, 0), (0.0, 0.0, 0.0));
+        close((255, 255, 255), (100.0, 0.0, 0.0));
+        close((128, 128, 128), (53.59, 0.0, 0.0));
+        close((255, 0, 0), (53.24, 80.09, 67.20));
+        close((0, 255, 0), (87.73, -86.18, 83.18));
+        close((0, 0, 255), (32.30, 79.19, -107.86));
+    }
+
+    #[test]
+    fn half_transparent_white_flattens_to_gray() {
+        let (r, g, b) = flatten((255, 255, 255), 128, (0, 0, 0));
+        assert_eq!((r, g, b), (128, 128, 128));
+        assert_eq!(human_perceived_brightness(r, g, b), 128);
+        assert_eq!(
+            flatten((255, 255, 255), 128, (255, 255, 255)),
+            (255, 255, 255)
+        );
+        assert_eq!(flatten((10, 20, 30), 0, (40, 50, 60)), (40, 50, 60));
+    }
+}
@@ -0,0 +1,1084 @@
+//! Clustering the pixels of a frame into blocks and reducing every block to the value of its dot.
+
+use std::{collections::HashMap, ops::Range};
+
+use crate::{
+    color::{
+        Rgba, hsv_to_rgb, human_perceived_brightness, lab_lightness, linear_to_srgb, srgb_to_linear,
+    },
+    dots::DotFrame,
+    input::{DepthMap, GifFrame},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[cfg_attr(
+    feature = "wasm",
+    derive(serde::Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum Metric {
+    /// Human perceived brightness, a fast approximation of lightness
+    Brightness,
+    /// CIELAB L*, perceptually uniform but more expensive to compute
+    #[cfg_attr(feature = "cli", value(alias = "lab-lightness"))]
+    Lab,
+    /// How far the color is from gray, so vivid areas get the big dots
+    #[cfg_attr(feature = "cli", value(alias = "saturation"))]
+    Chroma,
+    /// Warm reds and yellows get big dots and cool blues small ones
+    Temperature,
+    /// The opacity of the pixel, for stippling logos and cutouts by their shape
+    Alpha,
+    /// The gray level of a depth map, with every channel counting the same so none of the
+    /// 256 levels a GIF has are lost. Bright and near gets the big dots, see --depth-invert
+    Depth,
+    /// How much the brightness of a block changed since the previous frame, so only the
+    /// moving parts get dots
+    Motion,
+    /// How much the brightness varies inside a block, so detailed regions get the big dots
+    #[cfg_attr(feature = "cli", value(alias = "entropy"))]
+    Variance,
+    /// The median instead of the mean brightness of a block, so single specks don't count
+    MedianBrightness,
+    /// How strong the edges in a block are, with the direction of the edges as the hue of
+    /// the circles
+    SobelDirection,
+    /// How sharp the fine detail in a block is, from the Laplacian of the brightness, so
+    /// specks and texture get the big dots while smooth gradients stay small
+    Laplacian,
+    /// How much neighboring pixels inside a block differ, so text and fine line art that
+    /// average out to a mid gray still get big dots while smooth gradients don't
+    #[cfg_attr(feature = "cli", value(alias = "checkerboard-contrast"))]
+    Contrast,
+    /// The raw value of a single channel or one bit of it, see --channel and --bit-plane
+    #[cfg_attr(feature = "cli", value(alias = "from-channel"))]
+    Channel,
+    /// A weighted sum of other metrics, see --blend
+    Blend,
+}
+
+impl Metric {
+    /// How the keys of the pixels in a block are combined into the value of its dot.
+    pub fn reduction(self) -> Reduction {
+        match self {
+            Metric::Variance => Reduction::Deviation,
+            Metric::MedianBrightness => Reduction::Median,
+            _ => Reduction::Mean,
+        }
+    }
+
+    /// Computes the key of a pixel, scaled to the range 0..=255.
+    ///
+    /// The color based metrics are scaled by the alpha, so partially transparent pixels count less.
+    pub fn key(self, &(r, g, b, a): &Rgba) -> u8 {
+        let opacity = a as f32 / 255.0;
+        match self {
+            Metric::Brightness => (human_perceived_brightness(r, g, b) as f32 * opacity) as u8,
+            Metric::Lab => (lab_lightness(r, g, b) * 2.55 * opacity).round() as u8,
+            Metric::Chroma => {
+                let chroma = r.max(g).max(b) - r.min(g).min(b);
+                (chroma as f32 * opacity) as u8
+            }
+            Metric::Temperature => {
+                // Red minus blue, shifted so neutral colors end up in the middle
+                let warmth = (r as f32 - b as f32 + 255.0) / 2.0;
+                (warmth * opacity).round() as u8
+            }
+            Metric::Alpha => a,
+            // The red channel, unless --channel picks another one
+            Metric::Channel => r,
+            Metric::Depth => {
+                let gray = (r as u16 + g as u16 + b as u16) as f32 / 3.0;
+                (gray * opacity).round() as u8
+            }
+            // The brightness that the block values are derived from afterwards, see
+            // [`motion_frames`], [`edge_directions`], [`sharpness`] and [`local_contrast`]
+            Metric::Motion
+            | Metric::Variance
+            | Metric::MedianBrightness
+            | Metric::SobelDirection
+            | Metric::Laplacian
+            | Metric::Contrast => (human_perceived_brightness(r, g, b) as f32 * opacity) as u8,
+            Metric::Blend => unreachable!("blended keys come from `Blend::key`"),
+        }
+    }
+
+    /// Whether the key of a pixel is the value of the metric, rather than something the values
+    /// of the blocks are derived from.
+    fn is_per_pixel(self) -> bool {
+        matches!(
+            self,
+            Metric::Brightness
+                | Metric::Lab
+                | Metric::Chroma
+                | Metric::Temperature
+                | Metric::Alpha
+                | Metric::Depth
+                | Metric::Channel
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Channel {
+    R,
+    G,
+    B,
+    A,
+}
+
+impl Channel {
+    /// The value of the channel of a pixel, or 255 and 0 for whether bit `bit_plane` is set.
+    pub fn key(self, &(r, g, b, a): &Rgba, bit_plane: Option<u8>) -> u8 {
+        let value = match self {
+            Channel::R => r,
+            Channel::G => g,
+            Channel::B => b,
+            Channel::A => a,
+        };
+        match bit_plane {
+            Some(bit) if value >> bit & 1 == 1 => 255,
+            Some(_) => 0,
+            None => value,
+        }
+    }
+}
+
+/// A weighted sum of the keys of several metrics, see `--blend`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Blend {
+    /// The metrics with their weights, which sum up to 1
+    parts: Vec<(Metric, f32)>,
+}
+
+impl Blend {
+    /// Parses comma separated metrics with their weights like `brightness:0.7,chroma:0.3`.
+    #[cfg(feature = "cli")]
+    pub fn parse(s: &str) -> Result<Blend, String> {
+        use clap::ValueEnum;
+
+        let parts = s
+            .split(',')
+            .map(|part| {
+                let (name, weight) = part
+                    .split_once(':')
+                    .ok_or_else(|| format!("Expected METRIC:WEIGHT, got: {}", part))?;
+                let metric = Metric::from_str(name.trim(), true)?;
+                if !metric.is_per_pixel() {
+                    return Err(format!("The {} metric can't be blended", name.trim()));
+                }
+                let weight = weight
+                    .trim()
+                    .parse::<f32>()
+                    .map_err(|_| format!("Invalid weight: {}", weight))?;
+                Ok((metric, weight))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Blend::new(parts)
+    }
+
+    /// Normalizes the weights of per pixel metrics so they sum up to 1.
+    pub fn new(parts: Vec<(Metric, f32)>) -> Result<Blend, String> {
+        for &(metric, weight) in &parts {
+            if !metric.is_per_pixel() {
+                return Err(format!("The {:?} metric can't be blended", metric));
+            }
+            if !(weight >= 0.0 && weight.is_finite()) {
+                return Err(format!("Invalid weight: {}", weight));
+            }
+        }
+        let sum: f32 = parts.iter().map(|&(_, w)| w).sum();
+        if sum == 0.0 {
+            return Err("At least one weight has to be positive".to_string());
+        }
+        Ok(Blend {
+            parts: parts.into_iter().map(|(m, w)| (m, w / sum)).collect(),
+        })
+    }
+
+    /// The weighted sum of the keys of the pixel, scaled to the range 0..=255.
+    pub fn key(&self, pixel: &Rgba) -> u8 {
+        let key: f32 = self
+            .parts
+            .iter()
+            .map(|&(metric, weight)| metric.key(pixel) as f32 * weight)
+            .sum();
+        key.round().min(255.0) as u8
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reduction {
+    /// The average key
+    Mean,
+    /// Twice the standard deviation of the keys, computed in the same pass
+    Deviation,
+    /// The middle key, the upper one of the two for an even count
+    Median,
+    /// The average key of the opaque pixels, weighted by their opacity
+    AlphaWeightedMean,
+}
+
+/// How many blocks a frame of the given size is split into, partial blocks included.
+pub fn grid_size(
+    width: u16,
+    height: u16,
+    block_size: usize,
+    grid_offset: (i32, i32),
+) -> (usize, usize) {
+    let start_x = grid_start(grid_offset.0, block_size);
+    let start_y = grid_start(grid_offset.1, block_size);
+    (
+        (width as usize + (-start_x) as usize).div_ceil(block_size),
+        (height as usize + (-start_y) as usize).div_ceil(block_size),
+    )
+}
+
+/// Returns where the first (possibly partial) block starts when the block grid is shifted
+/// by `offset` pixels, always in the range `-block_size < start <= 0`.
+pub fn grid_start(offset: i32, block_size: usize) -> isize {
+    let shift = (offset as isize).rem_euclid(block_size as isize);
+    if shift == 0 {
+        0
+    } else {
+        shift - block_size as isize
+    }
+}
+
+/// Clusters the pixels of a frame into blocks and reduces every block to a single value.
+pub fn convert_frame(
+    frame: &GifFrame,
+    block_size: usize,
+    grid_offset: (i32, i32),
+    reduction: Reduction,
+    color_mode: ColorMode,
+    color_space: ColorSpace,
+    key_func: &impl Fn(&(u8, u8, u8, u8)) -> usize,
+) -> DotFrame {
+    // We want to cluster the pixels into blocks of size block_size x block_size
+    // and calculate the average brightness of each block.
+    let width = frame.width as usize;
+    let mut blocks = Vec::new();
+    let mut colors = Vec::new();
+    let mut alphas = Vec::new();
+    // Opacity and opacity weighted color sums of the pixels per quantized color
+    // The keys of the block for the median
+    let mut keys = Vec::new();
+    let encoding = color_space.encoding();
+    let mut buckets: HashMap<(u8, u8, u8), (u64, [u64; 3])> = HashMap::new();
+
+    let (start_x, start_y) = (
+        grid_start(grid_offset.0, block_size),
+        grid_start(grid_offset.1, block_size),
+    );
+
+    for y in (start_y..frame.height as isize).step_by(block_size) {
+        for x in (start_x..frame.width as isize).step_by(block_size) {
+            let mut total = 0;
+            let mut encoded_total = 0u64;
+            let (mut weighted_total, mut weight_total) = (0u64, 0u64);
+            let mut total_squares = 0u64;
+            let mut count = 0;
+            let mut color_total = [0u64; 3];
+            let mut alpha_total = 0u64;
+
+            for dy in 0..block_size as isize {
+                for dx in 0..block_size as isize {
+                    let px = x + dx;
+                    let py = y + dy;
+                    if px < 0 || py < 0 || px >= frame.width as isize || py >= frame.height as isize
+                    {
+                        continue;
+                    }
+
+                    let index = py as usize * width + px as usize;
+                    if index >= frame.buffer.len() {
+                        continue;
+                    }
+
+                    let pixel = frame.buffer[index];
+                    let key = key_func(&pixel);
+                    total += key;
+                    encoded_total += encoding[key.min(255)];
+                    total_squares += (key * key) as u64;
+                    count += 1;
+                    if reduction == Reduction::Median {
+                        keys.push(key);
+                    }
+
+                    let (r, g, b, a) = pixel;
+                    if reduction == Reduction::AlphaWeightedMean {
+                        let opaque_key = key_func(&(r, g, b, 255));
+                        weighted_total += encoding[opaque_key.min(255)] * a as u64;
+                        weight_total += a as u64;
+                    }
+                    let encoded = [r, g, b].map(|c| encoding[c as usize] * a as u64);
+                    for (total, value) in color_total.iter_mut().zip(encoded) {
+                        *total += value;
+                    }
+                    alpha_total += a as u64;
+
+                    if color_mode == ColorMode::Dominant {
+                        let bucket = buckets.entry((r >> 4, g >> 4, b >> 4)).or_default();
+                        bucket.0 += a as u64;
+                        for (total, value) in bucket.1.iter_mut().zip(encoded) {
+                            *total += value;
+                        }
+                    }
+                }
+            }
+
+            alphas.push(alpha_total.checked_div(count as u64).unwrap_or(0) as u8);
+
+            // The circle gets the average color of the pixels in the most frequent bucket
+            if color_mode == ColorMode::Dominant
+                && let Some((_, &(weight, sums))) = buckets
+                    .iter()
+                    .max_by_key(|&(bucket, &(weight, _))| (weight, *bucket))
+            {
+                (color_total, alpha_total) = (sums, weight);
+            }
+            buckets.clear();
+
+            let value = match reduction {
+                Reduction::Mean if color_space == ColorSpace::Srgb => {
+                    total.checked_div(count).unwrap_or(0)
+                }
+                Reduction::Mean => encoded_total
+                    .checked_div(count as u64)
+                    .map_or(0, |mean| color_space.decode(mean) as usize),
+                Reduction::Deviation if count == 0 => 0,
+                Reduction::Deviation => {
+                    let mean = total as f64 / count as f64;
+                    let variance = (total_squares as f64 / count as f64 - mean * mean).max(0.0);
+                    // The deviation of values in 0..=255 stays below 128
+                    (variance.sqrt() * 2.0).round() as usize
+                }
+                Reduction::AlphaWeightedMean => weighted_total
+                    .checked_div(weight_total)
+                    .map_or(0, |mean| color_space.decode(mean) as usize),
+                Reduction::Median if count == 0 => 0,
+                Reduction::Median => *keys.select_nth_unstable(count / 2).1,
+            };
+            keys.clear();
+            blocks.push(value);
+
+            let [r, g, b] =
+                color_total.map(|c| color_space.decode(c.checked_div(alpha_total).unwrap_or(0)));
+            colors.push((r, g, b));
+        }
+    }
+
+    // Now we can create a new DotFrame with the blocks
+    // and the width and height of the frame
+    let (blocks_w, blocks_h) = grid_size(frame.width, frame.height, block_size, grid_offset);
+    let expected_len = blocks_w * blocks_h;
+
+    debug_assert!(
+        blocks.len() == expected_len,
+        "Expected: {}, but got: {}",
+        expected_len,
+        blocks.len()
+    );
+
+    DotFrame {
+        width: blocks_w as u16,
+        height: blocks_h as u16,
+        buffer: blocks,
+        colors,
+        alpha: alphas,
+        negative: Vec::new(),
+        delay: None,
+    }
+}
+
+/// Derives the values of the metrics that look at the neighbors of every pixel from the
+/// keys, see [`edge_directions`], [`sharpness`] and [`local_contrast`]. The dots of the other
+/// metrics stay as [`convert_frame`] left them.
+pub fn neighborhood_values(
+    frame: &GifFrame,
+    dots: &mut DotFrame,
+    metric: Metric,
+    block_size: usize,
+    grid_offset: (i32, i32),
+    key_func: &impl Fn(&Rgba) -> usize,
+) {
+    match metric {
+        Metric::SobelDirection => edge_directions(frame, dots, block_size, grid_offset, key_func),
+        Metric::Laplacian => sharpness(frame, dots, block_size, grid_offset, key_func),
+        Metric::Contrast => local_contrast(frame, dots, block_size, grid_offset, key_func),
+        _ => {}
+    }
+}
+
+/// The keys of all pixels of a frame, for the metrics that look at the neighbors of a pixel.
+pub(crate) struct PixelKeys {
+    keys: Vec<f32>,
+    width: isize,
+    height: isize,
+}
+
+impl PixelKeys {
+    pub(crate) fn new(frame: &GifFrame, key_func: &impl Fn(&Rgba) -> usize) -> PixelKeys {
+        PixelKeys {
+            keys: frame.buffer.iter().map(|p| key_func(p) as f32).collect(),
+            width: frame.width as isize,
+            height: frame.height as isize,
+        }
+    }
+
+    /// The depths of a depth map stretched over a frame of `width` x `height` pixels, with the
+    /// far parts deepest for `invert`.
+    pub(crate) fn from_depth_map(
+        map: &DepthMap,
+        width: u16,
+        height: u16,
+        invert: bool,
+    ) -> PixelKeys {
+        let (w, h) = (width as usize, height as usize);
+        let keys = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let (mx, my) = (x * map.width as usize / w, y * map.height as usize / h);
+                let depth = map.depths[my * map.width as usize + mx];
+                (if invert { u16::MAX - depth } else { depth }) as f32
+            })
+            .collect();
+        PixelKeys {
+            keys,
+            width: w as isize,
+            height: h as isize,
+        }
+    }
+
+    /// The key of a pixel, with the pixels on the border repeated outwards.
+    pub(crate) fn at(&self, x: isize, y: isize) -> f32 {
+        let (x, y) = (x.clamp(0, self.width - 1), y.clamp(0, self.height - 1));
+        self.keys[(y * self.width + x) as usize]
+    }
+
+    /// The columns and rows of the pixels of every block in the order of the dots, like
+    /// [`convert_frame`] clusters them, cut off at the border of the frame.
+    pub(crate) fn blocks(
+        &self,
+        block_size: usize,
+        grid_offset: (i32, i32),
+    ) -> impl Iterator<Item = (Range<isize>, Range<isize>)> {
+        let (w, h) = (self.width, self.height);
+        let (start_x, start_y) = (
+            grid_start(grid_offset.0, block_size),
+            grid_start(grid_offset.1, block_size),
+        );
+        let size = block_size as isize;
+        (start_y..h).step_by(block_size).flat_map(move |y| {
+            (start_x..w)
+                .step_by(block_size)
+                .map(move |x| (x.max(0)..(x + size).min(w), y.max(0)..(y + size).min(h)))
+        })
+    }
+}
+
+/// Replaces the values and colors of the dots by the strength and direction of the edges in
+/// their blocks, from the Sobel gradients of the pixel keys.
+///
+/// The direction is the average gradient orientation of the block, weighted by its strength,
+/// and is mapped to the hue so that a half turn goes once around the color wheel, as edges
+/// from dark to bright and bright to dark look the same.
+pub fn edge_directions(
+    frame: &GifFrame,
+    dots: &mut DotFrame,
+    block_size: usize,
+    grid_offset: (i32, i32),
+    key_func: &impl Fn(&Rgba) -> usize,
+) {
+    let keys = PixelKeys::new(frame, key_func);
+    let key = |x, y| keys.at(x, y);
+
+    for (i, (xs, ys)) in keys.blocks(block_size, grid_offset).enumerate() {
+        let (mut magnitude, mut count) = (0.0, 0);
+        // The structure tensor, which averages orientations instead of signed directions
+        let (mut xx, mut yy, mut xy) = (0.0, 0.0, 0.0);
+
+        for py in ys {
+            for px in xs.clone() {
+                let gx = key(px + 1, py - 1) + 2.0 * key(px + 1, py) + key(px + 1, py + 1)
+                    - key(px - 1, py - 1)
+                    - 2.0 * key(px - 1, py)
+                    - key(px - 1, py + 1);
+                let gy = key(px - 1, py + 1) + 2.0 * key(px, py + 1) + key(px + 1, py + 1)
+                    - key(px - 1, py - 1)
+                    - 2.0 * key(px, py - 1)
+                    - key(px + 1, py - 1);
+                magnitude += (gx * gx + gy * gy).sqrt();
+                count += 1;
+                (xx, yy, xy) = (xx + gx * gx, yy + gy * gy, xy + gx * gy);
+            }
+        }
+
+        // A step between 0 and 255 has a gradient of 4 * 255
+        dots.buffer[i] = (magnitude / count.max(1) as f32 / 4.0).round().min(255.0) as usize;
+        let orientation = 0.5 * (2.0 * xy).atan2(xx - yy);
+        let hue = orientation.to_degrees().rem_euclid(180.0) * 2.0;
+        dots.colors[i] = hsv_to_rgb((hue, 1.0, 1.0));
+    }
+}
+
+/// Replaces the values of the dots by the mean magnitude of the Laplacian of the pixel keys in
+/// their blocks, which responds to points and thin lines rather than to gradual changes.
+pub fn sharpness(
+    frame: &GifFrame,
+    dots: &mut DotFrame,
+    block_size: usize,
+    grid_offset: (i32, i32),
+    key_func: &impl Fn(&Rgba) -> usize,
+) {
+    let keys = PixelKeys::new(frame, key_func);
+    let key = |x, y| keys.at(x, y);
+
+    for (i, (xs, ys)) in keys.blocks(block_size, grid_offset).enumerate() {
+        let (mut magnitude, mut count) = (0.0, 0);
+        for py in ys {
+            for px in xs.clone() {
+                let laplacian = 4.0 * key(px, py)
+                    - key(px - 1, py)
+                    - key(px + 1, py)
+                    - key(px, py - 1)
+                    - key(px, py + 1);
+                magnitude += laplacian.abs();
+                count += 1;
+            }
+        }
+
+        // A single bright pixel on black has a Laplacian of 4 * 255
+        dots.buffer[i] = (magnitude / count.max(1) as f32 / 4.0).round().min(255.0) as usize;
+    }
+}
+
+/// Replaces the values of the dots by the mean difference between the keys of neighboring
+/// pixels in their blocks, only comparing pixels of the same block.
+pub fn local_contrast(
+    frame: &GifFrame,
+    dots: &mut DotFrame,
+    block_size: usize,
+    grid_offset: (i32, i32),
+    key_func: &impl Fn(&Rgba) -> usize,
+) {
+    let keys = PixelKeys::new(frame, key_func);
+    let key = |x, y| keys.at(x, y);
+
+    for (i, (xs, ys)) in keys.blocks(block_size, grid_offset).enumerate() {
+        let (mut difference, mut count) = (0.0, 0);
+        for py in ys.clone() {
+            for px in xs.clone() {
+                if px + 1 < xs.end {
+                    difference += (key(px, py) - key(px + 1, py)).abs();
+                    count += 1;
+                }
+                if py + 1 < ys.end {
+                    difference += (key(px, py) - key(px, py + 1)).abs();
+                    count += 1;
+                }
+            }
+        }
+
+        // A checkerboard of black and white has the largest contrast of 255
+        dots.buffer[i] = (difference / count.max(1) as f32).round() as usize;
+    }
+}
+
+/// Replaces the values of the dots by the mean depth of their blocks in a depth map stretched
+/// over the frame, keeping all 16 bits of it, so the values go up to 65535.
+pub fn depth_values(
+    frame: &GifFrame,
+    dots: &mut DotFrame,
+    map: &DepthMap,
+    block_size: usize,
+    grid_offset: (i32, i32),
+    invert: bool,
+) {
+    let keys = PixelKeys::from_depth_map(map, frame.width, frame.height, invert);
+
+    for (i, (xs, ys)) in keys.blocks(block_size, grid_offset).enumerate() {
+        let (mut sum, mut count) = (0.0, 0);
+        for py in ys {
+            for px in xs.clone() {
+                sum += keys.at(px, py) as f64;
+                count += 1;
+            }
+        }
+        dots.buffer[i] = (sum / count.max(1) as f64).round() as usize;
+    }
+}
+
+/// The depth of a stereo pair, from how far every pixel of the left eye has moved in the
+/// right one, searching up to `max_disparity` pixels to the left for the `window` x `window`
+/// pixels around it that differ the least.
+///
+/// The near parts move the most, so they end up deepest like in a depth map, and areas without
+/// any detail to match keep a disparity of zero.
+pub fn disparity_map(
+    left: &GifFrame,
+    right: &GifFrame,
+    window: usize,
+    max_disparity: usize,
+    key_func: &impl Fn(&Rgba) -> usize,
+) -> DepthMap {
+    let (left_keys, right_keys) = (
+        PixelKeys::new(left, key_func),
+        PixelKeys::new(right, key_func),
+    );
+    let (w, h) = (left.width as usize, left.height as usize);
+    let half = window / 2;
+    let mut best = vec![(f64::INFINITY, 0); w * h];
+
+    for disparity in 0..=max_disparity {
+        // A summed area table of the differences, so every window only needs four of them
+        let mut sums = vec![0.0; (w + 1) * (h + 1)];
+        for y in 0..h {
+            for x in 0..w {
+                let (px, py) = (x as isize, y as isize);
+                let difference =
+                    (left_keys.at(px, py) - right_keys.at(px - disparity as isize, py)).abs();
+                sums[(y + 1) * (w + 1) + x + 1] =
+                    difference as f64 + sums[y * (w + 1) + x + 1] + sums[(y + 1) * (w + 1) + x]
+                        - sums[y * (w + 1) + x];
+            }
+        }
+
+        for y in 0..h {
+            let (y0, y1) = (y.saturating_sub(half), (y + half + 1).min(h));
+            for x in 0..w {
+                let (x0, x1) = (x.saturating_sub(half), (x + half + 1).min(w));
+                let cost =
+                    sums[y1 * (w + 1) + x1] - sums[y0 * (w + 1) + x1] - sums[y1 * (w + 1) + x0]
+                        + sums[y0 * (w + 1) + x0];
+                // Only a strictly better match moves a pixel, so ties stay flat
+                if cost < best[y * w + x].0 {
+                    best[y * w + x] = (cost, disparity);
+                }
+            }
+        }
+    }
+
+    DepthMap {
+        width: w as u32,
+        height: h as u32,
+        depths: best
+            .into_iter()
+            .map(|(_, disparity)| (disparity * u16::MAX as usize / max_disparity.max(1)) as u16)
+            .collect(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ColorMode {
+    /// The mean of the pixels, weighted by their opacity
+    Average,
+    /// The most frequent color, counted with 4 bits per channel
+    Dominant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ColorSpace {
+    /// Average the gamma encoded values
+    Srgb,
+    /// Average in linear light and encode the result again
+    Linear,
+}
+
+impl ColorSpace {
+    /// Linear light is stored with 16 bits, so it can be summed up with integers
+    const LINEAR_SCALE: f32 = 65535.0;
+
+    /// The values that channel values in 0..=255 are summed up as.
+    pub(crate) fn encoding(self) -> [u64; 256] {
+        std::array::from_fn(|c| match self {
+            ColorSpace::Srgb => c as u64,
+            ColorSpace::Linear => (srgb_to_linear(c as u8) * Self::LINEAR_SCALE).round() as u64,
+        })
+    }
+
+    /// Turns an average of encoded values back into a channel value.
+    pub(crate) fn decode(self, value: u64) -> u8 {
+        match self {
+            ColorSpace::Srgb => value.min(255) as u8,
+            ColorSpace::Linear => linear_to_srgb(value as f32 / Self::LINEAR_SCALE),
+        }
+    }
+}
+
+/// A lookup table that remaps the keys of a metric, see `--metric-file`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lut {
+    values: Vec<u8>,
+}
+
+impl Lut {
+    /// Parses a table of `KEY VALUE` lines, both in 0..=255, where `#` starts a comment.
+    /// Keys between two lines get linearly interpolated values, the keys before the first
+    /// and after the last line take the value of that line.
+    pub fn parse(text: &str) -> Result<Lut, String> {
+        let mut points = Vec::new();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let parse = |v: &str| {
+                v.parse::<u8>()
+                    .map_err(|_| format!("Invalid lookup table entry: {}", line))
+            };
+            let mut fields = line.split(|c: char| c.is_whitespace() || c == ',');
+            let mut next = || fields.by_ref().find(|f| !f.is_empty());
+            match (next(), next(), next()) {
+                (Some(key), Some(value), None) => points.push((parse(key)?, parse(value)?)),
+                _ => return Err(format!("Expected KEY VALUE, got: {}", line)),
+            }
+        }
+        if points.is_empty() {
+            return Err("A lookup table needs at least one entry".to_string());
+        }
+        points.sort_by_key(|&(key, _)| key);
+
+        let values = (0..=255u8)
+            .map(|key| {
+                let after = points.partition_point(|&(k, _)| k < key);
+                if after == 0 {
+                    return points[0].1;
+                }
+                if after == points.len() {
+                    return points[after - 1].1;
+                }
+
+                let ((k0, v0), (k1, v1)) = (points[after - 1], points[after]);
+                let f = (key - k0) as f32 / (k1 - k0) as f32;
+                (v0 as f32 + (v1 as f32 - v0 as f32) * f).round() as u8
+            })
+            .collect();
+        Ok(Lut { values })
+    }
+
+    pub fn from_file(path: &str) -> Result<Lut, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read lookup table: {}", e))?;
+        Lut::parse(&text)
+    }
+
+    pub fn map(&self, key: u8) -> u8 {
+        self.values[key as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brightness(pixel: &Rgba) -> usize {
+        Metric::Brightness.key(pixel) as usize
+    }
+
+    fn convert(frame: &GifFrame, block_size: usize, grid_offset: (i32, i32)) -> DotFrame {
+        let (mode, space) = (ColorMode::Average, ColorSpace::Srgb);
+        let reduction = Reduction::Mean;
+        convert_frame(
+            frame,
+            block_size,
+            grid_offset,
+            reduction,
+            mode,
+            space,
+            &brightness,
+        )
+    }
+
+    fn gray(value: u8) -> Rgba {
+        (value, value, value, 255)
+    }
+
+    #[test]
+    fn blocks_are_cut_off_at_the_border() {
+        let frame = GifFrame::from_fn(10, 5, |_, _| gray(0));
+        let keys = PixelKeys::new(&frame, &brightness);
+        let blocks: Vec<_> = keys.blocks(4, (-2, 0)).collect();
+        // The grid starts 2 pixels left of the frame, so the first column is 2 wide
+        assert_eq!(blocks.len(), 3 * 2);
+        assert_eq!(blocks[0], (0..2, 0..4));
+        assert_eq!(blocks[2], (6..10, 0..4));
+        assert_eq!(blocks[5], (6..10, 4..5));
+        assert_eq!(convert(&frame, 4, (-2, 0)).buffer.len(), blocks.len());
+    }
+
+    #[test]
+    fn edge_hue_follows_the_orientation() {
+        let hue_of = |frame: &GifFrame| {
+            let mut dots = convert(frame, 8, (0, 0));
+            edge_directions(frame, &mut dots, 8, (0, 0), &brightness);
+            assert!(dots.buffer[0] > 0);
+            crate::color::rgb_to_hsv(dots.colors[0]).0
+        };
+
+        // Orientations of 0 and 90 degrees become hues of 0 and 180 degrees
+        let vertical = GifFrame::from_fn(8, 8, |x, _| gray(if x < 4 { 0 } else { 255 }));
+        assert_eq!(hue_of(&vertical), 0.0);
+        let horizontal = GifFrame::from_fn(8, 8, |_, y| gray(if y < 4 { 0 } else { 255 }));
+        assert_eq!(hue_of(&horizontal), 180.0);
+        // Diagonals are in between
+        let diagonal = GifFrame::from_fn(8, 8, |x, y| gray(if x + y < 8 { 0 } else { 255 }));
+        assert!(
+            (hue_of(&diagonal) - 90.0).abs() < 1.0,
+            "{}",
+            hue_of(&diagonal)
+        );
+    }
+
+    #[test]
+    fn flat_blocks_have_no_edges() {
+        let frame = GifFrame::from_fn(8, 8, |_, _| gray(128));
+        let mut dots = convert(&frame, 4, (0, 0));
+        edge_directions(&frame, &mut dots, 4, (0, 0), &brightness);
+        assert_eq!(dots.buffer, [0; 4]);
+    }
+
+    #[test]
+    fn depth_gradients_give_growing_dots() {
+        let frame = GifFrame::from_fn(64, 4, |x, _| gray(x as u8 * 4));
+        let increasing = |values: &[usize]| values.windows(2).all(|w| w[0] < w[1]);
+
+        let depth = |pixel: &Rgba| Metric::Depth.key(pixel) as usize;
+        let dots = convert_frame(
+            &frame,
+            4,
+            (0, 0),
+            Reduction::Mean,
+            ColorMode::Average,
+            ColorSpace::Srgb,
+            &depth,
+        );
+        assert!(increasing(&dots.buffer));
+
+        // Steps of 1000 in 16 bits would be lost in 8 bits
+        let map = DepthMap {
+            width: 16,
+            height: 1,
+            depths: (0..16).map(|x| 30000 + x * 1000).collect(),
+        };
+        let mut near = convert(&frame, 4, (0, 0));
+        depth_values(&frame, &mut near, &map, 4, (0, 0), false);
+        assert!(increasing(&near.buffer));
+        assert_eq!(near.buffer[0], 30000);
+
+        let mut far = convert(&frame, 4, (0, 0));
+        depth_values(&frame, &mut far, &map, 4, (0, 0), true);
+        far.buffer.reverse();
+        assert!(increasing(&far.buffer));
+        assert_eq!(far.buffer[15], 65535 - 30000);
+    }
+
+    #[test]
+    fn disparity_is_how_far_the_right_eye_moved() {
+        // Stripes that repeat every 8 pixels, moved 3 pixels to the left on the right
+        let stripes = |shift: u16| move |x: u16, _| gray(((x + shift) % 8 * 32) as u8);
+        let left = GifFrame::from_fn(32, 8, stripes(0));
+        let right = GifFrame::from_fn(32, 8, stripes(3));
+
+        let map = disparity_map(&left, &right, 4, 3, &brightness);
+        // Away from the border, which the moved pixels come in from
+        let inner: Vec<u16> = (0..8)
+            .flat_map(|y| (4..32).map(move |x| y * 32 + x))
+            .map(|i| map.depths[i])
+            .collect();
+        assert!(inner.iter().all(|&d| d == u16::MAX), "{inner:?}");
+
+        let same = disparity_map(&left, &left, 4, 3, &brightness);
+        assert!(same.depths.iter().all(|&d| d == 0));
+    }
+
+    #[test]
+    fn grid_offset_moves_pixels_into_the_next_block() {
+        // The left half white, the right half black
+        let frame = GifFrame::from_fn(8, 4, |x, _| gray(if x < 4 { 255 } else { 0 }));
+        assert_eq!(convert(&frame, 4, (0, 0)).buffer, [255, 0]);
+
+        // Moving the grid a pixel to the right starts it with a block of a single column, and
+        // the other three white columns share their block with the first black one
+        let shifted = convert(&frame, 4, (1, 0));
+        assert_eq!(grid_size(8, 4, 4, (1, 0)), (3, 1));
+        assert_eq!(shifted.buffer, [255, 191, 0]);
+        assert_eq!(convert(&frame, 4, (5, 0)).buffer, shifted.buffer);
+    }
+
+    #[test]
+    fn alpha_values_follow_the_opacity() {
+        // White everywhere, getting more opaque to the right
+        let frame = GifFrame::from_fn(32, 4, |x, _| (255, 255, 255, (x * 8) as u8));
+        let alpha = |pixel: &Rgba| Metric::Alpha.key(pixel) as usize;
+        let (mode, space) = (ColorMode::Average, ColorSpace::Srgb);
+        let dots = convert_frame(&frame, 4, (0, 0), Reduction::Mean, mode, space, &alpha);
+
+        // The mean of 4 columns 8 apart is 12 above the first of them
+        let expected: Vec<usize> = (0..8).map(|i| i * 32 + 12).collect();
+        assert_eq!(dots.buffer, expected);
+    }
+
+    #[test]
+    fn chroma_prefers_vivid_colors() {
+        let red = (255, 0, 0, 255);
+        let level = human_perceived_brightness(255, 0, 0);
+        let gray = (level, level, level, 255);
+        assert_eq!(Metric::Brightness.key(&red), Metric::Brightness.key(&gray));
+        assert_eq!(Metric::Chroma.key(&red), 255);
+        assert_eq!(Metric::Chroma.key(&gray), 0);
+    }
+
+    #[test]
+    fn warm_colors_have_larger_keys() {
+        let blue = (0, 0, 255, 255);
+        let level = Metric::Brightness.key(&blue);
+        let red = (0..=255)
+            .map(|r| (r, 0, 0, 255))
+            .find(|red| Metric::Brightness.key(red) == level)
+            .unwrap();
+        assert!(Metric::Temperature.key(&red) > Metric::Temperature.key(&blue));
+        // Neutral colors are in the middle
+        assert_eq!(Metric::Temperature.key(&gray(level)), 128);
+    }
+
+    #[test]
+    fn noisy_blocks_vary_more_than_flat_ones() {
+        // A checkerboard of 64 and 192 next to a flat gray of the same mean
+        let frame = GifFrame::from_fn(8, 4, |x, y| match x < 4 {
+            true => gray(if (x + y) % 2 == 0 { 64 } else { 192 }),
+            false => gray(128),
+        });
+        let (mode, space) = (ColorMode::Average, ColorSpace::Srgb);
+        let mean = convert_frame(&frame, 4, (0, 0), Reduction::Mean, mode, space, &brightness);
+        assert_eq!(mean.buffer[0], mean.buffer[1]);
+
+        let reduction = Metric::Variance.reduction();
+        let dots = convert_frame(&frame, 4, (0, 0), reduction, mode, space, &brightness);
+        assert!(dots.buffer[0] > 0);
+        assert_eq!(dots.buffer[1], 0);
+    }
+
+    #[test]
+    fn dominant_color_is_the_most_common_one() {
+        // 15 red and 10 blue pixels
+        let frame = GifFrame::from_fn(5, 5, |_, y| match y < 3 {
+            true => (255, 0, 0, 255),
+            false => (0, 0, 255, 255),
+        });
+        let color = |mode| {
+            let reduction = Reduction::Mean;
+            convert_frame(
+                &frame,
+                5,
+                (0, 0),
+                reduction,
+                mode,
+                ColorSpace::Srgb,
+                &brightness,
+            )
+            .colors[0]
+        };
+        assert_eq!(color(ColorMode::Dominant), (255, 0, 0));
+        assert_eq!(color(ColorMode::Average), (153, 0, 102));
+    }
+
+    #[test]
+    fn median_ignores_a_single_bright_pixel() {
+        let frame = GifFrame::from_fn(4, 4, |x, y| gray(if (x, y) == (1, 2) { 255 } else { 16 }));
+        let value = |reduction| {
+            let (mode, space) = (ColorMode::Average, ColorSpace::Srgb);
+            convert_frame(&frame, 4, (0, 0), reduction, mode, space, &brightness).buffer[0]
+        };
+        assert_eq!(value(Metric::MedianBrightness.reduction()), 16);
+        assert!(value(Reduction::Mean) > 16 + 10);
+    }
+
+    #[test]
+    fn blend_of_one_metric_is_that_metric() {
+        let blend = Blend::new(vec![(Metric::Brightness, 1.0), (Metric::Chroma, 0.0)]).unwrap();
+        let frame = GifFrame::from_fn(16, 16, |x, y| ((x * 16) as u8, (y * 16) as u8, 90, 255));
+        let blended = |pixel: &Rgba| blend.key(pixel) as usize;
+        let (mode, space) = (ColorMode::Average, ColorSpace::Srgb);
+        let dots = convert_frame(&frame, 4, (0, 0), Reduction::Mean, mode, space, &blended);
+        assert_eq!(dots.buffer, convert(&frame, 4, (0, 0)).buffer);
+
+        assert!(Blend::new(vec![(Metric::Brightness, -1.0)]).is_err());
+        assert!(Blend::new(vec![(Metric::Brightness, 0.0)]).is_err());
+        assert!(Blend::new(vec![(Metric::Motion, 1.0)]).is_err());
+    }
+
+    #[test]
+    fn linear_average_of_black_and_white_is_bright() {
+        let frame = GifFrame::from_fn(2, 2, |x, _| gray(if x == 0 { 0 } else { 255 }));
+        let color = |space| {
+            let (reduction, mode) = (Reduction::Mean, ColorMode::Average);
+            convert_frame(&frame, 2, (0, 0), reduction, mode, space, &brightness).colors[0]
+        };
+        assert_eq!(color(ColorSpace::Srgb), (127, 127, 127));
+        let (r, g, b) = color(ColorSpace::Linear);
+        assert!((187..=189).contains(&r), "{r}");
+        assert_eq!((g, b), (r, r));
+    }
+
+    #[test]
+    fn alpha_weighting_ignores_the_transparent_half() {
+        // Opaque white on the left, transparent on the right
+        let frame = GifFrame::from_fn(4, 4, |x, _| match x < 2 {
+            true => (255, 255, 255, 255),
+            false => (0, 0, 0, 0),
+        });
+        let value = |reduction| {
+            let (mode, space) = (ColorMode::Average, ColorSpace::Srgb);
+            convert_frame(&frame, 4, (0, 0), reduction, mode, space, &brightness).buffer[0]
+        };
+        assert_eq!(value(Reduction::Mean), 127);
+        assert_eq!(value(Reduction::AlphaWeightedMean), 255);
+    }
+
+    #[test]
+    fn laplacian_finds_points_not_gradients() {
+        // A smooth gradient with a single bright point in the right block
+        let frame = GifFrame::from_fn(16, 8, |x, y| match (x, y) {
+            (12, 4) => gray(255),
+            _ => gray(x as u8 * 8),
+        });
+        let mut dots = convert(&frame, 8, (0, 0));
+        sharpness(&frame, &mut dots, 8, (0, 0), &brightness);
+        let (gradient, point) = (dots.buffer[0], dots.buffer[1]);
+        assert!(point > 0);
+        assert!(gradient * 4 < point, "{gradient} {point}");
+    }
+
+    #[test]
+    fn checkerboards_have_more_contrast_than_gray() {
+        // Alternating black and white pixels next to a gray of the same mean
+        let frame = GifFrame::from_fn(8, 4, |x, y| match x < 4 {
+            true => gray(if (x + y) % 2 == 0 { 0 } else { 254 }),
+            false => gray(127),
+        });
+        let mut dots = convert(&frame, 4, (0, 0));
+        assert_eq!(dots.buffer[0], dots.buffer[1]);
+
+        local_contrast(&frame, &mut dots, 4, (0, 0), &brightness);
+        assert_eq!(dots.buffer, [254, 0]);
+    }
+
+    #[test]
+    fn bit_plane_seven_is_the_top_bit_of_the_channel() {
+        for red in [0, 1, 127, 128, 200, 255] {
+            let pixel = (red, 255 - red, 7, 255);
+            let expected = if red >= 128 { 255 } else { 0 };
+            assert_eq!(Channel::R.key(&pixel, Some(7)), expected, "red {red}");
+            assert_eq!(Channel::R.key(&pixel, None), red);
+        }
+        assert_eq!(Channel::B.key(&(0, 0, 7, 255), Some(2)), 255);
+        assert_eq!(Channel::B.key(&(0, 0, 7, 255), Some(3)), 0);
+    }
+}
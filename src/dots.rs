@@ -0,0 +1,437 @@
+//! The blocks of a converted frame, before they are drawn as circles.
+
+#[derive(Debug)]
+pub struct DotFrame {
+    pub width: u16,
+    pub height: u16,
+
+    // Some "key" value, can be arbitrary for now e.g. brightness, hue
+    pub buffer: Vec<usize>,
+
+    /// The average color of every block, weighted by the alpha of its pixels
+    pub colors: Vec<(u8, u8, u8)>,
+
+    /// The average alpha of every block
+    pub alpha: Vec<u8>,
+
+    /// Whether the value of every block stands for a decrease, empty if the values have no sign
+    pub negative: Vec<bool>,
+
+    /// How long the frame is shown, instead of the delay that all frames get by default
+    pub delay: Option<u16>,
+}
+
+/// Hands every converted frame with its index to `callback` before the frames are rendered,
+/// e.g. to clear cells or apply an effect of your own to the values.
+pub fn process_frames(frames: &mut [DotFrame], mut callback: impl FnMut(usize, &mut DotFrame)) {
+    for (index, frame) in frames.iter_mut().enumerate() {
+        callback(index, frame);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Neighborhood {
+    /// Only the cells sharing an edge
+    #[cfg_attr(feature = "cli", value(name = "4"))]
+    Four,
+    /// The cells sharing an edge or a corner
+    #[cfg_attr(feature = "cli", value(name = "8"))]
+    Eight,
+}
+
+impl Neighborhood {
+    fn offsets(self) -> &'static [(isize, isize)] {
+        match self {
+            Neighborhood::Four => &[(0, -1), (-1, 0), (1, 0), (0, 1)],
+            Neighborhood::Eight => &[
+                (-1, -1),
+                (0, -1),
+                (1, -1),
+                (-1, 0),
+                (1, 0),
+                (-1, 1),
+                (0, 1),
+                (1, 1),
+            ],
+        }
+    }
+}
+
+/// Replaces the values of every frame by how much they changed since the previous frame,
+/// the first frame has nothing to compare with and gets no dots at all.
+///
+/// The cells that decreased are marked as [`DotFrame::negative`].
+pub fn motion_frames(frames: &mut [DotFrame]) {
+    for i in (1..frames.len()).rev() {
+        let (before, after) = frames.split_at_mut(i);
+        let previous = &before[i - 1].buffer;
+        let frame = &mut after[0];
+        frame.negative = frame
+            .buffer
+            .iter()
+            .zip(previous)
+            .map(|(v, old)| v < old)
+            .collect();
+        for (value, &old) in frame.buffer.iter_mut().zip(previous) {
+            *value = value.abs_diff(old);
+        }
+    }
+    if let Some(first) = frames.first_mut() {
+        first.buffer.fill(0);
+    }
+}
+
+/// Scales the values of every frame that differs from the previous one by more than
+/// `threshold` on average by `boost`, up to `max_value`.
+pub fn boost_cuts(frames: &mut [DotFrame], threshold: f32, boost: f32, max_value: usize) {
+    let cuts: Vec<bool> = (0..frames.len())
+        .map(|i| {
+            i > 0 && {
+                let (previous, frame) = (&frames[i - 1].buffer, &frames[i].buffer);
+                let change: usize = frame
+                    .iter()
+                    .zip(previous)
+                    .map(|(&a, &b)| a.abs_diff(b))
+                    .sum();
+                change as f32 / frame.len().max(1) as f32 > threshold
+            }
+        })
+        .collect();
+
+    for (frame, _) in frames.iter_mut().zip(cuts).filter(|(_, cut)| *cut) {
+        for value in frame.buffer.iter_mut() {
+            *value = ((*value as f32 * boost).round() as usize).min(max_value);
+        }
+    }
+}
+
+/// Blends the last `count` frames into the first ones and drops them, so the animation runs
+/// from where the last frame left off straight into the start.
+///
+/// The first frame gets mostly the old end and the frame after the blended ones nothing of it.
+pub fn crossfade_loop(frames: &mut Vec<DotFrame>, count: usize) -> Result<(), String> {
+    if count == 0 {
+        return Ok(());
+    }
+    if frames.len() < 2 * count {
+        return Err(format!(
+            "--loop-crossfade {} needs at least {} frames, there are {}",
+            count,
+            2 * count,
+            frames.len()
+        ));
+    }
+
+    let tail = frames.split_off(frames.len() - count);
+    for (k, (frame, end)) in frames.iter_mut().zip(&tail).enumerate() {
+        let weight = (k + 1) as f32 / (count + 1) as f32;
+        let mix = |start: f32, end: f32| start * weight + end * (1.0 - weight);
+        for (value, &old) in frame.buffer.iter_mut().zip(&end.buffer) {
+            *value = mix(*value as f32, old as f32).round() as usize;
+        }
+        for (alpha, &old) in frame.alpha.iter_mut().zip(&end.alpha) {
+            *alpha = mix(*alpha as f32, old as f32).round() as u8;
+        }
+        for (color, &old) in frame.colors.iter_mut().zip(&end.colors) {
+            let channel = |a: u8, b: u8| mix(a as f32, b as f32).round() as u8;
+            *color = (
+                channel(color.0, old.0),
+                channel(color.1, old.1),
+                channel(color.2, old.2),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Box blurs the values of a frame by spreading every value evenly over the cells within
+/// `radius`, so cells on the edges share with fewer neighbors and the total stays the same.
+pub fn smooth_values(frame: &mut DotFrame, radius: usize) {
+    let (w, h) = (frame.width as usize, frame.height as usize);
+    let mut smoothed = vec![0.0f32; frame.buffer.len()];
+
+    for y in 0..h {
+        for x in 0..w {
+            let value = frame.buffer[y * w + x];
+            if value == 0 {
+                continue;
+            }
+            let (x0, x1) = (x.saturating_sub(radius), (x + radius).min(w - 1));
+            let (y0, y1) = (y.saturating_sub(radius), (y + radius).min(h - 1));
+            let share = value as f32 / ((x1 - x0 + 1) * (y1 - y0 + 1)) as f32;
+            for ny in y0..=y1 {
+                for cell in &mut smoothed[ny * w + x0..=ny * w + x1] {
+                    *cell += share;
+                }
+            }
+        }
+    }
+    for (value, smoothed) in frame.buffer.iter_mut().zip(smoothed) {
+        *value = smoothed.round() as usize;
+    }
+}
+
+/// How many times the average count a bin of the histogram of a tile may have in
+/// [`equalize_tiles`], which keeps noise in flat tiles from getting stretched to all sizes.
+const EQUALIZE_CLIP_LIMIT: f32 = 3.0;
+
+/// Equalizes the histogram of the values like CLAHE, within `tiles` x `tiles` tiles of the
+/// grid, interpolating bilinearly between the mappings of the four closest tiles.
+///
+/// The values keep the range from zero to the largest value of the frame, cells without a dot
+/// don't count and stay empty.
+pub fn equalize_tiles(frame: &mut DotFrame, tiles: usize) {
+    const BINS: usize = 256;
+    let (w, h) = (frame.width as usize, frame.height as usize);
+    let max = frame.buffer.iter().copied().max().unwrap_or(0);
+    if max == 0 {
+        return;
+    }
+    let bin = |value: usize| value * (BINS - 1) / max;
+
+    let (tiles_x, tiles_y) = (tiles.clamp(1, w), tiles.clamp(1, h));
+    let (tile_w, tile_h) = (w.div_ceil(tiles_x), h.div_ceil(tiles_y));
+    // The cumulative distribution of every tile, with the bins clipped and the excess
+    // spread over all of them
+    let mut mappings = Vec::with_capacity(tiles_x * tiles_y);
+    for ty in 0..tiles_y {
+        for tx in 0..tiles_x {
+            let mut histogram = [0.0f32; BINS];
+            let (x0, y0) = (tx * tile_w, ty * tile_h);
+            for y in y0..(y0 + tile_h).min(h) {
+                for &value in &frame.buffer[y * w + x0..y * w + (x0 + tile_w).min(w)] {
+                    if value > 0 {
+                        histogram[bin(value)] += 1.0;
+                    }
+                }
+            }
+            let count: f32 = histogram.iter().sum();
+            let limit = (EQUALIZE_CLIP_LIMIT * count / BINS as f32).max(1.0);
+            let excess: f32 = histogram.iter().map(|&n| (n - limit).max(0.0)).sum();
+
+            let mut total = 0.0;
+            let mapping: [f32; BINS] = std::array::from_fn(|b| {
+                total += histogram[b].min(limit) + excess / BINS as f32;
+                total / count.max(1.0)
+            });
+            mappings.push(mapping);
+        }
+    }
+
+    // Where a cell lies between the tile centers, clamped to the outer tiles
+    let between = |i: usize, size: usize, tiles: usize| {
+        let t = ((i as f32 + 0.5) / size as f32 - 0.5).clamp(0.0, (tiles - 1) as f32);
+        let first = t.floor() as usize;
+        (first, (first + 1).min(tiles - 1), t - first as f32)
+    };
+    for y in 0..h {
+        let (ty0, ty1, fy) = between(y, tile_h, tiles_y);
+        for x in 0..w {
+            let (tx0, tx1, fx) = between(x, tile_w, tiles_x);
+            let value = &mut frame.buffer[y * w + x];
+            if *value == 0 {
+                continue; // Empty cells stay empty
+            }
+            let b = bin(*value);
+            let map = |tx: usize, ty: usize| mappings[ty * tiles_x + tx][b];
+            let top = map(tx0, ty0) * (1.0 - fx) + map(tx1, ty0) * fx;
+            let bottom = map(tx0, ty1) * (1.0 - fx) + map(tx1, ty1) * fx;
+            *value = ((top * (1.0 - fy) + bottom * fy) * max as f32).round() as usize;
+        }
+    }
+}
+
+/// Clears the dots below `threshold` that have no other dot in their neighborhood, which
+/// removes the speckles in sparse regions while keeping clusters of small dots intact.
+pub fn remove_isolated_dots(frame: &mut DotFrame, threshold: usize, neighborhood: Neighborhood) {
+    let (w, h) = (frame.width as isize, frame.height as isize);
+    let original = frame.buffer.clone();
+
+    for y in 0..h {
+        for x in 0..w {
+            let idx = (y * w + x) as usize;
+            if original[idx] == 0 || original[idx] >= threshold {
+                continue;
+            }
+
+            let has_neighbor = neighborhood.offsets().iter().any(|&(dx, dy)| {
+                let (nx, ny) = (x + dx, y + dy);
+                nx >= 0 && ny >= 0 && nx < w && ny < h && original[(ny * w + nx) as usize] > 0
+            });
+            if !has_neighbor {
+                frame.buffer[idx] = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+impl DotFrame {
+    /// A frame of white dots with the given values, row by row.
+    pub(crate) fn from_values(width: u16, height: u16, values: &[usize]) -> DotFrame {
+        assert_eq!(values.len(), width as usize * height as usize);
+        DotFrame {
+            width,
+            height,
+            buffer: values.to_vec(),
+            colors: vec![(255, 255, 255); values.len()],
+            alpha: vec![255; values.len()],
+            negative: Vec::new(),
+            delay: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isolated_small_dots_are_removed() {
+        #[rustfmt::skip]
+        let values = [
+            0, 0, 0, 0, 0,
+            0, 3, 0, 0, 0,
+            0, 0, 0, 2, 3,
+            0, 0, 0, 3, 9,
+        ];
+        let mut frame = DotFrame::from_values(5, 4, &values);
+        remove_isolated_dots(&mut frame, 5, Neighborhood::Four);
+        #[rustfmt::skip]
+        assert_eq!(frame.buffer, [
+            0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0,
+            0, 0, 0, 2, 3,
+            0, 0, 0, 3, 9,
+        ]);
+
+        // Diagonal neighbors only count for the larger neighborhood
+        let diagonal = [3, 0, 0, 3];
+        let mut frame = DotFrame::from_values(2, 2, &diagonal);
+        remove_isolated_dots(&mut frame, 5, Neighborhood::Eight);
+        assert_eq!(frame.buffer, diagonal);
+        remove_isolated_dots(&mut frame, 5, Neighborhood::Four);
+        assert_eq!(frame.buffer, [0; 4]);
+    }
+
+    #[test]
+    fn only_the_changed_region_gets_motion_dots() {
+        // The top left block of a gray frame turns white in the second frame
+        let mut frames = [
+            DotFrame::from_values(2, 2, &[100; 4]),
+            DotFrame::from_values(2, 2, &[255, 100, 100, 100]),
+        ];
+        motion_frames(&mut frames);
+
+        assert_eq!(frames[0].buffer, [0; 4]);
+        assert_eq!(frames[1].buffer, [155, 0, 0, 0]);
+        assert_eq!(frames[1].negative, [false; 4]);
+    }
+
+    #[test]
+    fn crossfading_smooths_the_loop_point() {
+        let ramp = || -> Vec<DotFrame> {
+            (0..12)
+                .map(|i| DotFrame::from_values(1, 1, &[i * 10]))
+                .collect()
+        };
+        // The largest change between consecutive frames, from the last back to the first too
+        let largest_step = |frames: &[DotFrame]| {
+            (0..frames.len())
+                .map(|i| {
+                    let next = &frames[(i + 1) % frames.len()];
+                    frames[i].buffer[0].abs_diff(next.buffer[0])
+                })
+                .max()
+                .unwrap()
+        };
+        assert_eq!(largest_step(&ramp()), 110);
+
+        let mut frames = ramp();
+        crossfade_loop(&mut frames, 4).unwrap();
+        assert_eq!(frames.len(), 8);
+        assert_eq!(frames[0].buffer, [64]);
+        assert_eq!(largest_step(&frames), 10);
+
+        assert!(crossfade_loop(&mut ramp(), 7).is_err());
+    }
+
+    #[test]
+    fn smoothing_spreads_a_value_and_keeps_the_sum() {
+        let mut values = [0; 25];
+        values[12] = 90;
+        let mut frame = DotFrame::from_values(5, 5, &values);
+        smooth_values(&mut frame, 1);
+        #[rustfmt::skip]
+        assert_eq!(frame.buffer, [
+            0, 0, 0, 0, 0,
+            0, 10, 10, 10, 0,
+            0, 10, 10, 10, 0,
+            0, 10, 10, 10, 0,
+            0, 0, 0, 0, 0,
+        ]);
+
+        // In a corner the value is shared by fewer cells
+        let mut corner = DotFrame::from_values(3, 3, &[40, 0, 0, 0, 0, 0, 0, 0, 0]);
+        smooth_values(&mut corner, 1);
+        assert_eq!(corner.buffer, [10, 10, 0, 10, 10, 0, 0, 0, 0]);
+        assert_eq!(corner.buffer.iter().sum::<usize>(), 40);
+    }
+
+    #[test]
+    fn only_the_cut_gets_boosted() {
+        // Slowly changing frames with a cut at the fourth
+        let values = [
+            [10, 20, 30, 40],
+            [11, 20, 31, 40],
+            [12, 21, 30, 41],
+            [60, 50, 80, 70],
+            [61, 50, 80, 71],
+        ];
+        let mut frames: Vec<DotFrame> = values
+            .iter()
+            .map(|v| DotFrame::from_values(2, 2, v))
+            .collect();
+        boost_cuts(&mut frames, 5.0, 2.0, 150);
+
+        let boosted: Vec<&[usize]> = frames.iter().map(|f| f.buffer.as_slice()).collect();
+        assert_eq!(
+            boosted,
+            [
+                &values[0][..],
+                &values[1],
+                &values[2],
+                &[120, 100, 150, 140],
+                &values[4]
+            ]
+        );
+    }
+
+    #[test]
+    fn equalizing_expands_a_low_contrast_gradient() {
+        // Values from 100 to 117, next to a bright half that sets the largest value
+        let values: Vec<usize> = (0..64)
+            .map(|i| {
+                if i % 8 < 4 {
+                    100 + i % 8 + i / 8 * 2
+                } else {
+                    255
+                }
+            })
+            .collect();
+        let mut frame = DotFrame::from_values(8, 8, &values);
+        equalize_tiles(&mut frame, 2);
+
+        let gradient: Vec<usize> = (0..64)
+            .filter(|i| i % 8 < 4)
+            .map(|i| frame.buffer[i])
+            .collect();
+        let (low, high) = (
+            gradient.iter().min().unwrap(),
+            gradient.iter().max().unwrap(),
+        );
+        assert!(high - low > 4 * 17, "{gradient:?}");
+        assert_eq!(frame.buffer.iter().max(), Some(&255));
+    }
+}
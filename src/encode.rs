@@ -0,0 +1,689 @@
+//! Writing the rendered frames into GIFs.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::Instant;
+
+use gif::{Encoder, Frame};
+
+use crate::{
+    color::Rgba,
+    dots::DotFrame,
+    input::extract_gif_frames,
+    palette::{Palette, count_colors},
+    render::{Layout, RenderOptions, Trails, check_gif_size, render_frame},
+    sequence::{is_complete_png, is_png_sequence},
+    timing::frame_delays,
+};
+
+/// Measures the render and frame times, which are zero on the web where `Instant` has no clock.
+struct Stopwatch(#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))] Instant);
+
+impl Stopwatch {
+    fn start() -> Stopwatch {
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        return Stopwatch(Instant::now());
+        #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+        return Stopwatch();
+    }
+
+    fn elapsed(&self) -> Duration {
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        return self.0.elapsed();
+        #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+        return Duration::ZERO;
+    }
+}
+
+/// Describes the GIF that [`write_circles_gif`] produced.
+pub struct RenderSummary {
+    pub layout: Layout,
+    /// The RGB triples of the global palette, without the transparent entry
+    pub palette: Vec<u8>,
+    /// How long rendering and encoding took for every frame
+    pub frame_times: Vec<Duration>,
+    /// How much of the frame times went into rendering, empty when streaming
+    pub render_times: Vec<Duration>,
+    /// The delay of every frame of a GIF, empty for PNG sequences
+    pub delays: Vec<u16>,
+    /// The files that make up the output
+    pub paths: Vec<PathBuf>,
+}
+
+/// Renders the dot frames into a GIF at `path`.
+pub fn write_circles_gif(
+    path: &str,
+    frames: &[DotFrame],
+    options: &RenderOptions,
+) -> Result<RenderSummary, Box<dyn std::error::Error>> {
+    let image = BufWriter::new(File::create(path)?);
+    let mut summary = encode_circles_gif(image, frames, options)?;
+    summary.paths.push(PathBuf::from(path));
+    Ok(summary)
+}
+
+/// The path of part `index` of a GIF split with `--chunk`, `out.gif` becomes `out.part0.gif`.
+pub fn chunk_path<P: AsRef<Path>>(path: P, index: usize) -> PathBuf {
+    let path = path.as_ref();
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or("gif".as_ref()).to_string_lossy();
+    path.with_file_name(format!("{}.part{}.{}", stem, index, extension))
+}
+
+/// Renders the dot frames into GIFs of at most `chunk` frames each, see [`chunk_path`].
+///
+/// Every part gets a palette of its own, so the summary has no palette. The radius range, the
+/// timing curve and the trails run over the whole animation, as if it was one GIF.
+pub fn write_gif_chunks(
+    path: &str,
+    frames: &[DotFrame],
+    options: &RenderOptions,
+    chunk: usize,
+) -> Result<RenderSummary, Box<dyn std::error::Error>> {
+    let mut summary: Option<RenderSummary> = None;
+    let mut trails = Trails::new(options.accumulate);
+    for (index, start) in (0..frames.len()).step_by(chunk).enumerate() {
+        let part_path = chunk_path(path, index);
+        let image = BufWriter::new(File::create(&part_path)?);
+        let part = start..(start + chunk).min(frames.len());
+        let mut written = encode_gif_part(image, frames, part, &mut trails, options)?;
+        written.paths.push(part_path);
+        match &mut summary {
+            None => {
+                summary = Some(RenderSummary {
+                    palette: Vec::new(),
+                    ..written
+                })
+            }
+            Some(summary) => {
+                summary.frame_times.extend(written.frame_times);
+                summary.render_times.extend(written.render_times);
+                summary.delays.extend(written.delays);
+                summary.paths.extend(written.paths);
+            }
+        }
+    }
+    Ok(summary.expect("Need at least one frame"))
+}
+
+/// Renders the dot frames into a GIF written to `writer`.
+pub fn encode_circles_gif<W: Write>(
+    writer: W,
+    frames: &[DotFrame],
+    options: &RenderOptions,
+) -> Result<RenderSummary, Box<dyn std::error::Error>> {
+    let mut trails = Trails::new(options.accumulate);
+    encode_gif_part(writer, frames, 0..frames.len(), &mut trails, options)
+}
+
+/// Renders the frames in `part` of the animation into a GIF written to `writer`, continuing
+/// the `trails` of the frames before it.
+pub fn encode_gif_part<W: Write>(
+    writer: W,
+    all_frames: &[DotFrame],
+    part: Range<usize>,
+    trails: &mut Trails,
+    options: &RenderOptions,
+) -> Result<RenderSummary, Box<dyn std::error::Error>> {
+    let frames = &all_frames[part.clone()];
+    assert!(!frames.is_empty(), "Need at least one frame");
+
+    let layout = Layout::new(&frames[0], options);
+    check_gif_size(&layout)?;
+    let (img_w, img_h) = (layout.width, layout.height);
+    let count = all_frames.len();
+    let delays: Vec<u16> = frame_delays(count, options.delay, options.timing_curve)[part.clone()]
+        .iter()
+        .zip(frames)
+        .map(|(&delay, df)| df.delay.unwrap_or(delay))
+        .collect();
+    let mut frame_times = Vec::with_capacity(frames.len());
+    let mut render_times = Vec::with_capacity(frames.len());
+
+    if options.local_palettes {
+        let mut encoder = Encoder::new(writer, img_w as u16, img_h as u16, &[])?;
+        if frames.len() > 1 {
+            encoder.set_repeat(options.repeat)?;
+        }
+        write_comment(&mut encoder, options)?;
+
+        for (i, df) in part.clone().zip(frames) {
+            let start = Stopwatch::start();
+            let canvas = trails.composite(render_frame(df, i, count, options));
+            render_times.push(start.elapsed());
+            let mut frame = local_palette_frame(&canvas, &layout, options);
+            frame.delay = delays[i - part.start];
+            write_gif_frame(&mut encoder, &frame, options)?;
+            frame_times.push(start.elapsed());
+        }
+
+        return Ok(RenderSummary {
+            layout,
+            palette: Vec::new(),
+            frame_times,
+            render_times,
+            delays,
+            paths: Vec::new(),
+        });
+    }
+
+    // Render everything once to find out which colors make up the global palette
+    let mut histogram = HashMap::new();
+    let mut histogram_trails = trails.clone();
+    for (i, df) in part.clone().zip(frames) {
+        let canvas = histogram_trails.composite(render_frame(df, i, count, options));
+        count_colors(&mut histogram, &canvas);
+    }
+    let palette = match &options.source_palette {
+        Some(colors) => Palette::from_colors(&histogram, colors),
+        None => Palette::from_histogram(&histogram),
+    };
+    let transparent = palette.transparent_index();
+
+    // The transparent index needs an entry in the palette as well
+    let mut global_palette = palette.colors.clone();
+    global_palette.extend_from_slice(&[0, 0, 0]);
+
+    let mut encoder = Encoder::new(writer, img_w as u16, img_h as u16, &global_palette)?;
+    // A still image doesn't need the looping extension
+    if frames.len() > 1 {
+        encoder.set_repeat(options.repeat)?;
+    }
+    write_comment(&mut encoder, options)?;
+
+    let frame_buf_size = (img_w * img_h) as usize;
+    let mut pixels = vec![0u8; frame_buf_size];
+    let mut diff = FrameDiff::new(&layout, transparency(transparent, options).transparent);
+
+    for (i, df) in part.clone().zip(frames) {
+        let start = Stopwatch::start();
+
+        let canvas = trails.composite(render_frame(df, i, count, options));
+        render_times.push(start.elapsed());
+        palette.write_indices(&canvas, img_w, options.palette_dither, &mut pixels);
+
+        if options.optimize {
+            // A frame is written once the next one shows what has to be cleared after it
+            if let Some(frame) = diff.push(&pixels, delays[i - part.start]) {
+                write_gif_frame(&mut encoder, &frame, options)?;
+            }
+        } else {
+            let frame = Frame {
+                width: img_w as u16,
+                height: img_h as u16,
+                buffer: Cow::Borrowed(&pixels),
+                delay: delays[i - part.start],
+                ..transparency(transparent, options)
+            };
+            write_gif_frame(&mut encoder, &frame, options)?;
+        }
+        frame_times.push(start.elapsed());
+    }
+    if let Some(frame) = diff.finish() {
+        write_gif_frame(&mut encoder, &frame, options)?;
+    }
+
+    Ok(RenderSummary {
+        layout,
+        palette: palette.colors,
+        frame_times,
+        render_times,
+        delays,
+        paths: Vec::new(),
+    })
+}
+
+/// Decodes the written output again and checks that it has the frames and size that were
+/// rendered, to catch files that some decoders would reject.
+pub fn verify_output(summary: &RenderSummary) -> Result<(), String> {
+    let (width, height) = (summary.layout.width, summary.layout.height);
+    let frames = summary.frame_times.len();
+
+    if summary.paths.iter().all(is_png_sequence) {
+        if summary.paths.len() != frames {
+            return Err(format!(
+                "Expected {} frames, found {}",
+                frames,
+                summary.paths.len()
+            ));
+        }
+        return match summary
+            .paths
+            .iter()
+            .find(|p| !is_complete_png(p, &summary.layout))
+        {
+            Some(path) => Err(format!("{} is not a complete frame", path.display())),
+            None => Ok(()),
+        };
+    }
+
+    // The frames can be split over several GIFs, see `--chunk`
+    let mut decoded_frames = 0;
+    for path in &summary.paths {
+        let decoded = extract_gif_frames(path)?;
+        decoded_frames += decoded.len();
+        if let Some(frame) = decoded
+            .iter()
+            .find(|f| f.width as u32 != width || f.height as u32 != height)
+        {
+            return Err(format!(
+                "Expected {}x{} frames, decoded one of {}x{}",
+                width, height, frame.width, frame.height
+            ));
+        }
+    }
+    if decoded_frames != frames {
+        return Err(format!(
+            "Expected {} frames, decoded {}",
+            frames, decoded_frames
+        ));
+    }
+    Ok(())
+}
+
+/// Copies a frame with its rows in the order of the four passes of an interlaced GIF, every
+/// 8th row from the first, every 8th from the fifth, every 4th from the third and the rest.
+pub fn interlace(frame: &Frame) -> Frame<'static> {
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let rows = (0..height)
+        .step_by(8)
+        .chain((4..height).step_by(8))
+        .chain((2..height).step_by(4))
+        .chain((1..height).step_by(2));
+    let buffer = rows
+        .flat_map(|row| &frame.buffer[row * width..(row + 1) * width])
+        .copied()
+        .collect();
+    Frame {
+        buffer: Cow::Owned(buffer),
+        interlaced: true,
+        palette: frame.palette.clone(),
+        ..*frame
+    }
+}
+
+/// Writes a frame, interlaced with [`RenderOptions::interlaced`].
+fn write_gif_frame<W: Write>(
+    encoder: &mut Encoder<W>,
+    frame: &Frame,
+    options: &RenderOptions,
+) -> Result<(), gif::EncodingError> {
+    if options.interlaced {
+        encoder.write_frame(&interlace(frame))
+    } else {
+        encoder.write_frame(frame)
+    }
+}
+
+/// Writes [`RenderOptions::comment`] as a comment extension, if there is one.
+pub(crate) fn write_comment<W: Write>(
+    encoder: &mut Encoder<W>,
+    options: &RenderOptions,
+) -> io::Result<()> {
+    match &options.comment {
+        Some(comment) => {
+            encoder.write_raw_extension(gif::Extension::Comment.into(), &[comment.as_bytes()])
+        }
+        None => Ok(()),
+    }
+}
+
+/// Quantizes a rendered frame into a frame with a local palette of its own.
+pub(crate) fn local_palette_frame(
+    canvas: &[Rgba],
+    layout: &Layout,
+    options: &RenderOptions,
+) -> Frame<'static> {
+    let mut histogram = HashMap::new();
+    count_colors(&mut histogram, canvas);
+    let palette = Palette::from_histogram(&histogram);
+
+    let mut pixels = vec![0; canvas.len()];
+    palette.write_indices(canvas, layout.width, options.palette_dither, &mut pixels);
+    let mut colors = palette.colors.clone();
+    colors.extend_from_slice(&[0, 0, 0]);
+
+    Frame {
+        width: layout.width as u16,
+        height: layout.height as u16,
+        buffer: Cow::Owned(pixels),
+        palette: Some(colors),
+        ..transparency(palette.transparent_index(), options)
+    }
+}
+
+/// Turns full frames of palette indices into frames of just the rectangle that changed, see
+/// `--optimize`.
+///
+/// Viewers keep what is outside of the rectangle, and inside of it the pixels that stay the same
+/// become transparent so they compress better. A pixel can only become transparent again by
+/// clearing the rectangle of the frame before, so a frame is only finished once the next one
+/// is known.
+pub struct FrameDiff {
+    width: usize,
+    transparent: Option<u8>,
+    /// What the viewer shows before the pending frame is drawn
+    shown: Vec<u8>,
+    /// The indices and delay of the frame that waits for its successor
+    pending: Option<(Vec<u8>, u16)>,
+}
+
+impl FrameDiff {
+    pub fn new(layout: &Layout, transparent: Option<u8>) -> FrameDiff {
+        FrameDiff {
+            width: layout.width as usize,
+            transparent,
+            shown: Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// Adds the next frame, returning the one before it.
+    pub fn push(&mut self, pixels: &[u8], delay: u16) -> Option<Frame<'static>> {
+        let finished = self
+            .pending
+            .take()
+            .map(|(previous, previous_delay)| self.frame(&previous, previous_delay, Some(pixels)));
+        self.pending = Some((pixels.to_vec(), delay));
+        finished
+    }
+
+    /// Returns the last frame.
+    pub fn finish(&mut self) -> Option<Frame<'static>> {
+        let (pixels, delay) = self.pending.take()?;
+        Some(self.frame(&pixels, delay, None))
+    }
+
+    fn frame(&mut self, pixels: &[u8], delay: u16, next: Option<&[u8]>) -> Frame<'static> {
+        let width = self.width;
+        let height = pixels.len() / width;
+        let first = self.shown.is_empty();
+        let (mut x0, mut y0, mut x1, mut y1) = if first {
+            (0, 0, width - 1, height - 1)
+        } else {
+            (usize::MAX, usize::MAX, 0, 0)
+        };
+        let mut include = |i: usize| {
+            let (x, y) = (i % width, i / width);
+            (x0, y0, x1, y1) = (x0.min(x), y0.min(y), x1.max(x), y1.max(y));
+        };
+
+        if !first {
+            for (i, (&p, &s)) in pixels.iter().zip(&self.shown).enumerate() {
+                if p != s {
+                    include(i);
+                }
+            }
+        }
+        // The pixels that the next frame makes transparent are cleared after this one
+        let mut clear = false;
+        if let (Some(t), Some(next)) = (self.transparent, next) {
+            for (i, (&p, &n)) in pixels.iter().zip(next).enumerate() {
+                if n == t && p != t {
+                    include(i);
+                    clear = true;
+                }
+            }
+        }
+        if x0 > x1 {
+            // Nothing changed, a single pixel that stays the same still carries the delay
+            (x0, y0, x1, y1) = (0, 0, 0, 0);
+        }
+
+        let mut buffer = Vec::with_capacity((x1 - x0 + 1) * (y1 - y0 + 1));
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let i = y * width + x;
+                buffer.push(match self.transparent {
+                    Some(t) if !first && pixels[i] == self.shown[i] => t,
+                    _ => pixels[i],
+                });
+            }
+        }
+
+        self.shown = pixels.to_vec();
+        if let (true, Some(t)) = (clear, self.transparent) {
+            for y in y0..=y1 {
+                self.shown[y * width + x0..=y * width + x1].fill(t);
+            }
+        }
+
+        Frame {
+            left: x0 as u16,
+            top: y0 as u16,
+            width: (x1 - x0 + 1) as u16,
+            height: (y1 - y0 + 1) as u16,
+            buffer: Cow::Owned(buffer),
+            delay,
+            transparent: self.transparent,
+            dispose: if clear {
+                gif::DisposalMethod::Background
+            } else {
+                gif::DisposalMethod::Keep
+            },
+            ..Frame::default()
+        }
+    }
+}
+
+/// A frame with the transparent index and the disposal that clears the frame for the next one.
+///
+/// With [`RenderOptions::no_transparent`] the index is an ordinary color instead, the black
+/// entry that every palette ends with, and every frame covers the previous one anyway.
+fn transparency(index: u8, options: &RenderOptions) -> Frame<'static> {
+    if options.no_transparent {
+        Frame {
+            dispose: gif::DisposalMethod::Keep,
+            ..Frame::default()
+        }
+    } else {
+        Frame {
+            transparent: Some(index),
+            dispose: gif::DisposalMethod::Background,
+            ..Frame::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{input::GifFrameReader, render::ColorSource, testing::TempDir};
+
+    fn frames(count: usize) -> Vec<DotFrame> {
+        (0..count)
+            .map(|i| DotFrame::from_values(3, 2, &[i % 4, 3, 0, (i + 2) % 4, 1, 2]))
+            .collect()
+    }
+
+    fn options() -> RenderOptions {
+        RenderOptions {
+            start_radius: 2,
+            end_radius: 6,
+            spacing: 14,
+            max_value: 3,
+            accumulate: 0.5,
+            ..RenderOptions::default()
+        }
+    }
+
+    fn decode(path: &Path) -> Vec<Vec<Rgba>> {
+        GifFrameReader::open(path)
+            .unwrap()
+            .map(|frame| frame.unwrap().buffer)
+            .collect()
+    }
+
+    #[test]
+    fn chunks_split_the_frames() {
+        let dir = TempDir::new("chunks");
+        let path = dir.join("out.gif");
+        let summary = write_gif_chunks(&path, &frames(25), &options(), 10).unwrap();
+
+        let counts: Vec<usize> = summary.paths.iter().map(|p| decode(p).len()).collect();
+        assert_eq!(counts, [10, 10, 5]);
+        assert_eq!(summary.paths[2], Path::new(&dir.join("out.part2.gif")));
+    }
+
+    #[test]
+    fn chunks_continue_the_radius_range_and_trails() {
+        let dir = TempDir::new("chunks-whole");
+        let whole = dir.join("whole.gif");
+        write_circles_gif(&whole, &frames(25), &options()).unwrap();
+        let parts = dir.join("parts.gif");
+        let summary = write_gif_chunks(&parts, &frames(25), &options(), 10).unwrap();
+
+        let parts: Vec<Vec<Rgba>> = summary.paths.iter().flat_map(|p| decode(p)).collect();
+        assert_eq!(parts, decode(Path::new(&whole)));
+    }
+
+    #[test]
+    fn local_palettes_keep_more_colors() {
+        // 100 colors on every frame, 400 altogether
+        let frames: Vec<DotFrame> = (0..4)
+            .map(|i| {
+                let mut frame = DotFrame::from_values(10, 10, &[1; 100]);
+                frame.colors = (0..100).map(|j| (j * 2, i * 60, 255 - j)).collect();
+                frame
+            })
+            .collect();
+        let encode = |local_palettes| {
+            let options = RenderOptions {
+                dot_color_from: ColorSource::Original,
+                local_palettes,
+                ..RenderOptions::default()
+            };
+            let mut output = Vec::new();
+            encode_circles_gif(&mut output, &frames, &options).unwrap();
+            output
+        };
+        let (local, global) = (encode(true), encode(false));
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(local.as_slice())
+            .unwrap();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert!(frame.palette.is_some());
+        }
+
+        // How far the centers of the dots are off from their colors
+        let layout = Layout::new(&frames[0], &RenderOptions::default());
+        let error = |gif: &[u8]| {
+            let decoded = GifFrameReader::new(gif).unwrap();
+            let mut error = 0;
+            for (frame, dots) in decoded.zip(&frames) {
+                let frame = frame.unwrap();
+                for (i, &(r, g, b)) in dots.colors.iter().enumerate() {
+                    let (cx, cy) = layout.center(i as u32 % 10, i as u32 / 10);
+                    let (dr, dg, db, _) =
+                        frame.buffer[cy as usize * layout.width as usize + cx as usize];
+                    error += r.abs_diff(dr) as u32 + g.abs_diff(dg) as u32 + b.abs_diff(db) as u32;
+                }
+            }
+            error
+        };
+        assert_eq!(error(&local), 0);
+        assert!(error(&global) > 0);
+    }
+
+    #[test]
+    fn opaque_gifs_have_no_transparent_index() {
+        let options = RenderOptions {
+            no_transparent: true,
+            ..options()
+        };
+        let mut output = Vec::new();
+        encode_circles_gif(&mut output, &frames(4), &options).unwrap();
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(output.as_slice())
+            .unwrap();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!(frame.transparent, None);
+            assert_ne!(frame.dispose, gif::DisposalMethod::Background);
+        }
+        for frame in GifFrameReader::new(output.as_slice()).unwrap() {
+            assert!(frame.unwrap().buffer.iter().all(|p| p.3 == 255));
+        }
+    }
+
+    #[test]
+    fn optimized_gifs_are_smaller_and_look_the_same() {
+        // Only one cell of the grid changes from frame to frame
+        let frames: Vec<DotFrame> = (0..6)
+            .map(|i| {
+                let mut values = [3; 64];
+                values[9] = i % 4;
+                DotFrame::from_values(8, 8, &values)
+            })
+            .collect();
+        let encode = |optimize| {
+            let options = RenderOptions {
+                max_value: 3,
+                optimize,
+                ..RenderOptions::default()
+            };
+            let mut output = Vec::new();
+            encode_circles_gif(&mut output, &frames, &options).unwrap();
+            output
+        };
+        let (optimized, plain) = (encode(true), encode(false));
+        let decoded = |gif: &[u8]| -> Vec<Vec<Rgba>> {
+            GifFrameReader::new(gif)
+                .unwrap()
+                .map(|frame| frame.unwrap().buffer)
+                .collect()
+        };
+
+        assert!(
+            optimized.len() < plain.len() / 2,
+            "{} {}",
+            optimized.len(),
+            plain.len()
+        );
+        assert_eq!(decoded(&optimized), decoded(&plain));
+    }
+
+    #[test]
+    fn interlaced_gifs_decode_to_the_same_frames() {
+        let encode = |interlaced| {
+            let options = RenderOptions {
+                interlaced,
+                ..options()
+            };
+            let mut output = Vec::new();
+            encode_circles_gif(&mut output, &frames(4), &options).unwrap();
+            output
+        };
+        let (interlaced, plain) = (encode(true), encode(false));
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(interlaced.as_slice())
+            .unwrap();
+        // Decoding the pixels clears the flag when it puts the rows back in order
+        let mut count = 0;
+        while let Some(frame) = decoder.next_frame_info().unwrap() {
+            assert!(frame.interlaced);
+            let mut pixels = vec![0; decoder.buffer_size()];
+            decoder.read_into_buffer(&mut pixels).unwrap();
+            count += 1;
+        }
+        assert_eq!(count, 4);
+        let decoded = |gif: &[u8]| -> Vec<Vec<Rgba>> {
+            GifFrameReader::new(gif)
+                .unwrap()
+                .map(|frame| frame.unwrap().buffer)
+                .collect()
+        };
+        assert_eq!(decoded(&interlaced), decoded(&plain));
+    }
+}
@@ -0,0 +1,732 @@
+//! Decoding the frames of the input and what can be done to them before they are clustered
+//! into dots.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+use gif::{ColorOutput, DecodeOptions, Repeat};
+use image::{
+    RgbaImage,
+    imageops::{self, FilterType},
+};
+
+use crate::color::{Rgba, TRANSPARENT, is_opaque};
+
+#[derive(Clone)]
+pub struct GifFrame {
+    pub width: u16,
+    pub height: u16,
+    /// The pixel data of the GIF frame in RGBA format.
+    pub buffer: Vec<(u8, u8, u8, u8)>,
+    /// How long the frame is shown, in hundredths of a second
+    pub delay: u16,
+}
+
+#[cfg(test)]
+impl GifFrame {
+    /// A frame with the color of every pixel from `pixel(x, y)`.
+    pub(crate) fn from_fn(width: u16, height: u16, pixel: impl Fn(u16, u16) -> Rgba) -> GifFrame {
+        GifFrame {
+            width,
+            height,
+            buffer: (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .map(|(x, y)| pixel(x, y))
+                .collect(),
+            delay: 10,
+        }
+    }
+}
+
+/// Decodes the frames of a GIF one at a time, so they don't all have to be kept in memory.
+///
+/// The frames are drawn onto the logical screen like a viewer would, so frames that only
+/// update a part of it or have transparent holes come out as the whole picture.
+///
+/// The GIF can come from anything that implements [`Read`], like a file or bytes in memory.
+pub struct GifFrameReader<R: Read = File> {
+    decoder: gif::Decoder<R>,
+    /// The logical screen with everything drawn so far, empty before the first frame
+    canvas: Vec<Rgba>,
+    /// The color of the logical screen declared in the GIF
+    declared_background: Option<(u8, u8, u8)>,
+    /// Overrides the background, see [`GifFrameReader::with_canvas_color`]
+    canvas_color: Option<(u8, u8, u8)>,
+    /// How to clean up after the previous frame before drawing the next one
+    disposal: Disposal,
+}
+
+/// What the previous frame leaves behind.
+enum Disposal {
+    Keep,
+    /// Clear the rectangle `(x, y, width, height)` of the frame
+    Clear(usize, usize, usize, usize),
+    /// Put back the canvas from before the frame
+    Restore(Vec<Rgba>),
+}
+
+impl GifFrameReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<GifFrameReader, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open file: {}", e))?;
+        GifFrameReader::new(file)
+    }
+}
+
+impl<R: Read> GifFrameReader<R> {
+    /// Reads the header of a GIF, the frames follow as they are iterated over.
+    pub fn new(reader: R) -> Result<GifFrameReader<R>, String> {
+        let mut decoder = DecodeOptions::new();
+        decoder.set_color_output(ColorOutput::RGBA);
+        let decoder = decoder
+            .read_info(reader)
+            .map_err(|e| format!("Failed to read GIF info: {}", e))?;
+
+        let declared_background = decoder.bg_color().and_then(|index| {
+            let palette = decoder.global_palette()?;
+            let rgb = palette.get(index * 3..index * 3 + 3)?;
+            Some((rgb[0], rgb[1], rgb[2]))
+        });
+
+        Ok(GifFrameReader {
+            decoder,
+            canvas: Vec::new(),
+            declared_background,
+            canvas_color: None,
+            disposal: Disposal::Keep,
+        })
+    }
+
+    /// Starts from a canvas of this color instead, which also shows through the transparent
+    /// pixels and wherever a frame is disposed to the background.
+    ///
+    /// Without it, only the parts of the screen that the first frame doesn't cover get the
+    /// declared background color, like browsers show them, and the rest is transparent.
+    pub fn with_canvas_color(mut self, color: Option<(u8, u8, u8)>) -> GifFrameReader<R> {
+        self.canvas_color = color;
+        self
+    }
+
+    /// How often the GIF wants to be played, as far as the decoder has read it.
+    ///
+    /// The loop count is usually stored right after the header, but a few encoders only
+    /// write it after the first frame.
+    pub fn repeat(&self) -> Repeat {
+        self.decoder.repeat()
+    }
+
+    /// The RGB triples of the global palette of the GIF, if it has one.
+    pub fn global_palette(&self) -> Option<&[u8]> {
+        self.decoder.global_palette()
+    }
+
+    /// The size of the logical screen of the GIF.
+    pub fn screen_size(&self) -> (u16, u16) {
+        (self.decoder.width(), self.decoder.height())
+    }
+
+    fn read_frame(&mut self) -> Result<Option<GifFrame>, String> {
+        let (screen_w, screen_h) = self.screen_size();
+        let (screen_w, screen_h) = (screen_w as usize, screen_h as usize);
+        let background = self
+            .canvas_color
+            .map_or(TRANSPARENT, |(r, g, b)| (r, g, b, 255));
+
+        let Some(frame) = self
+            .decoder
+            .read_next_frame()
+            .map_err(|e| format!("Failed to read frame: {}", e))?
+        else {
+            return Ok(None);
+        };
+
+        let (left, top) = (frame.left as usize, frame.top as usize);
+        let (width, height) = (frame.width as usize, frame.height as usize);
+        debug_assert!(
+            frame.buffer.len() == width * height * 4,
+            "Buffer length mismatch"
+        );
+
+        if self.canvas.is_empty() {
+            self.canvas = vec![background; screen_w * screen_h];
+            if let (None, Some((r, g, b))) = (self.canvas_color, self.declared_background) {
+                for (i, pixel) in self.canvas.iter_mut().enumerate() {
+                    let (x, y) = (i % screen_w, i / screen_w);
+                    if x < left || y < top || x >= left + width || y >= top + height {
+                        *pixel = (r, g, b, 255);
+                    }
+                }
+            }
+        }
+
+        match std::mem::replace(&mut self.disposal, Disposal::Keep) {
+            Disposal::Keep => {}
+            Disposal::Clear(x, y, w, h) => {
+                for row in y..(y + h).min(screen_h) {
+                    for col in x..(x + w).min(screen_w) {
+                        self.canvas[row * screen_w + col] = background;
+                    }
+                }
+            }
+            Disposal::Restore(previous) => self.canvas = previous,
+        }
+        self.disposal = match frame.dispose {
+            gif::DisposalMethod::Background => Disposal::Clear(left, top, width, height),
+            gif::DisposalMethod::Previous => Disposal::Restore(self.canvas.clone()),
+            _ => Disposal::Keep,
+        };
+
+        // Transparent pixels let the canvas below show through
+        for (i, px) in frame.buffer.chunks_exact(4).enumerate() {
+            let (x, y) = (left + i % width, top + i / width);
+            if px[3] > 0 && x < screen_w && y < screen_h {
+                self.canvas[y * screen_w + x] = (px[0], px[1], px[2], px[3]);
+            }
+        }
+
+        Ok(Some(GifFrame {
+            width: screen_w as u16,
+            height: screen_h as u16,
+            buffer: self.canvas.clone(),
+            delay: frame.delay,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for GifFrameReader<R> {
+    type Item = Result<GifFrame, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_frame().transpose()
+    }
+}
+
+pub fn extract_gif_frames<P: AsRef<Path>>(path: P) -> Result<Vec<GifFrame>, String> {
+    GifFrameReader::open(path)?.collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ScaleFilter {
+    /// Nearest neighbor, no new colors
+    Nearest,
+    /// Linear interpolation
+    Triangle,
+    /// Cubic interpolation, sharper than linear
+    CatmullRom,
+    /// Lanczos with a window of 3, the sharpest but slowest
+    Lanczos3,
+}
+
+impl ScaleFilter {
+    fn filter_type(self) -> FilterType {
+        match self {
+            ScaleFilter::Nearest => FilterType::Nearest,
+            ScaleFilter::Triangle => FilterType::Triangle,
+            ScaleFilter::CatmullRom => FilterType::CatmullRom,
+            ScaleFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// The size of a frame after [`scale_frame`].
+pub fn scaled_size(width: u16, height: u16, factor: f32) -> (u16, u16) {
+    let scale = |v: u16| (v as f32 * factor).round().clamp(1.0, u16::MAX as f32) as u16;
+    (scale(width), scale(height))
+}
+
+/// The block size that covers the same part of a frame after shrinking it to fit into
+/// `resolution`x`resolution`, or `None` if the frame already fits or the blocks would stay the same.
+pub fn work_block_size(
+    width: u16,
+    height: u16,
+    block_size: usize,
+    resolution: u16,
+) -> Option<usize> {
+    let factor = resolution as f32 / width.max(height) as f32;
+    let work_block_size = ((block_size as f32 * factor).round() as usize).max(1);
+    (work_block_size < block_size).then_some(work_block_size)
+}
+
+/// Resizes a frame by `factor`, it stays at least one pixel wide and high.
+pub fn scale_frame(frame: &GifFrame, factor: f32, filter: ScaleFilter) -> GifFrame {
+    let (width, height) = (frame.width as u32, frame.height as u32);
+    let bytes = frame
+        .buffer
+        .iter()
+        .flat_map(|&(r, g, b, a)| [r, g, b, a])
+        .collect();
+    let image = RgbaImage::from_raw(width, height, bytes).expect("The buffer matches the size");
+
+    let (scaled_width, scaled_height) = scaled_size(frame.width, frame.height, factor);
+    let scaled = imageops::resize(
+        &image,
+        scaled_width as u32,
+        scaled_height as u32,
+        filter.filter_type(),
+    );
+
+    GifFrame {
+        width: scaled_width,
+        height: scaled_height,
+        buffer: scaled.pixels().map(|p| (p[0], p[1], p[2], p[3])).collect(),
+        delay: frame.delay,
+    }
+}
+
+/// The smallest rectangle `(x, y, width, height)` that contains every pixel of every frame
+/// that differs from the border color, which is the top left pixel of the first frame.
+/// Returns `None` if there is nothing but border.
+pub fn content_bounds(frames: &[GifFrame], tolerance: u8) -> Option<(u16, u16, u16, u16)> {
+    let border = *frames.first()?.buffer.first()?;
+    let is_border = |&(r, g, b, a): &Rgba| {
+        if !is_opaque(border) || !is_opaque((r, g, b, a)) {
+            return is_opaque(border) == is_opaque((r, g, b, a));
+        }
+        r.abs_diff(border.0) <= tolerance
+            && g.abs_diff(border.1) <= tolerance
+            && b.abs_diff(border.2) <= tolerance
+    };
+
+    let (mut x0, mut y0, mut x1, mut y1) = (u16::MAX, u16::MAX, 0, 0);
+    for frame in frames {
+        for (i, pixel) in frame.buffer.iter().enumerate() {
+            if !is_border(pixel) {
+                let (x, y) = (
+                    (i % frame.width as usize) as u16,
+                    (i / frame.width as usize) as u16,
+                );
+                (x0, y0, x1, y1) = (x0.min(x), y0.min(y), x1.max(x), y1.max(y));
+            }
+        }
+    }
+    (x0 <= x1).then(|| (x0, y0, x1 - x0 + 1, y1 - y0 + 1))
+}
+
+/// Cuts the rectangle `(x, y, width, height)` out of a frame, clipped to the frame.
+pub fn crop_frame(frame: &GifFrame, (x, y, width, height): (u16, u16, u16, u16)) -> GifFrame {
+    let width = width.min(frame.width.saturating_sub(x));
+    let height = height.min(frame.height.saturating_sub(y));
+    let buffer = (y..y + height)
+        .flat_map(|row| {
+            let start = row as usize * frame.width as usize + x as usize;
+            frame.buffer[start..start + width as usize].iter().copied()
+        })
+        .collect();
+    GifFrame {
+        width,
+        height,
+        buffer,
+        delay: frame.delay,
+    }
+}
+
+/// Averages the pixels of frames of the same size channel by channel, alpha included.
+/// The blended frame is shown as long as all of them together.
+pub fn blend_frames(frames: &[GifFrame]) -> GifFrame {
+    let mut totals = vec![[0u32; 4]; frames[0].buffer.len()];
+    for frame in frames {
+        for (total, &(r, g, b, a)) in totals.iter_mut().zip(&frame.buffer) {
+            for (t, c) in total.iter_mut().zip([r, g, b, a]) {
+                *t += c as u32;
+            }
+        }
+    }
+    let count = frames.len() as u32;
+    let average = |t: u32| ((t + count / 2) / count) as u8;
+    GifFrame {
+        width: frames[0].width,
+        height: frames[0].height,
+        buffer: totals
+            .iter()
+            .map(|&[r, g, b, a]| (average(r), average(g), average(b), average(a)))
+            .collect(),
+        delay: frames
+            .iter()
+            .fold(0, |total, f| total.saturating_add(f.delay)),
+    }
+}
+
+/// Gaussian blurs every channel of the frame with the standard deviation `sigma` in pixels.
+pub fn blur_frame(frame: &mut GifFrame, sigma: f32) {
+    let bytes = frame
+        .buffer
+        .iter()
+        .flat_map(|&(r, g, b, a)| [r, g, b, a])
+        .collect();
+    let image = RgbaImage::from_raw(frame.width as u32, frame.height as u32, bytes)
+        .expect("The buffer matches the size");
+    let blurred = imageops::blur(&image, sigma);
+    frame.buffer = blurred.pixels().map(|p| (p[0], p[1], p[2], p[3])).collect();
+}
+
+/// Raises every color channel of the frame in 0.0..=1.0 to its power in `gammas`, exponents
+/// below 1 brighten the channel and above 1 darken it.
+pub fn apply_gamma(frame: &mut GifFrame, gammas: (f32, f32, f32)) {
+    let table = |gamma: f32| -> [u8; 256] {
+        std::array::from_fn(|c| ((c as f32 / 255.0).powf(gamma) * 255.0).round() as u8)
+    };
+    let (r_table, g_table, b_table) = (table(gammas.0), table(gammas.1), table(gammas.2));
+    for (r, g, b, _) in frame.buffer.iter_mut() {
+        (*r, *g, *b) = (
+            r_table[*r as usize],
+            g_table[*g as usize],
+            b_table[*b as usize],
+        );
+    }
+}
+
+/// Quantizes every color channel of the frame to `levels` evenly spaced values, alpha is kept.
+pub fn posterize(frame: &mut GifFrame, levels: u16) {
+    let step = 255.0 / (levels.max(2) - 1) as f32;
+    let quantize = |c: u8| ((c as f32 / step).round() * step).round() as u8;
+    for (r, g, b, _) in frame.buffer.iter_mut() {
+        (*r, *g, *b) = (quantize(*r), quantize(*g), quantize(*b));
+    }
+}
+
+/// Floyd-Steinberg dithers every color channel of the frame to `levels` evenly spaced values.
+///
+/// The error is only spread to opaque pixels, so the average color of a block stays the same.
+pub fn dither(frame: &mut GifFrame, levels: u16) {
+    let (width, height) = (frame.width as usize, frame.height as usize);
+    let step = 255.0 / (levels.max(2) - 1) as f32;
+    let mut error = vec![[0.0f32; 3]; width * height];
+
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let (r, g, b, a) = frame.buffer[i];
+            if a < 128 {
+                continue;
+            }
+
+            let mut quantized = [0; 3];
+            let mut residual = [0.0; 3];
+            for (c, value) in [r, g, b].into_iter().enumerate() {
+                let wanted = (value as f32 + error[i][c]).clamp(0.0, 255.0);
+                let level = ((wanted / step).round() * step).round();
+                quantized[c] = level as u8;
+                residual[c] = wanted - level;
+            }
+            frame.buffer[i] = (quantized[0], quantized[1], quantized[2], a);
+
+            let neighbors = [(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)];
+            for (dx, dy, weight) in neighbors {
+                let (nx, ny) = (x as isize + dx, y + dy);
+                if nx < 0 || nx as usize >= width || ny >= height {
+                    continue;
+                }
+                let n = ny * width + nx as usize;
+                if frame.buffer[n].3 >= 128 {
+                    for c in 0..3 {
+                        error[n][c] += residual[c] * weight / 16.0;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reads a PNG image of any color type as RGBA.
+pub fn read_png<P: AsRef<Path>>(path: P) -> Result<RgbaImage, Box<dyn std::error::Error>> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size().unwrap_or_default()];
+    let info = reader.next_frame(&mut buffer)?;
+
+    let rgba = buffer[..info.buffer_size()]
+        .chunks_exact(info.color_type.samples())
+        .flat_map(|px| match *px {
+            [r, g, b, a] => [r, g, b, a],
+            [r, g, b] => [r, g, b, 255],
+            [l, a] => [l, l, l, a],
+            [l] => [l, l, l, 255],
+            _ => unreachable!("PNG pixels have 1 to 4 samples"),
+        })
+        .collect();
+    RgbaImage::from_raw(info.width, info.height, rgba).ok_or_else(|| "Truncated PNG".into())
+}
+
+/// A grayscale depth map, with the 16 bits of a 16-bit PNG kept.
+pub struct DepthMap {
+    pub width: u32,
+    pub height: u32,
+    /// The depth of every pixel, 8-bit images scaled to the same 0..=65535
+    pub depths: Vec<u16>,
+}
+
+impl DepthMap {
+    /// Reads a PNG as a depth map, averaging the channels of color images.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<DepthMap, Box<dyn std::error::Error>> {
+        let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+        decoder.set_transformations(png::Transformations::EXPAND);
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size().unwrap_or_default()];
+        let info = reader.next_frame(&mut buffer)?;
+
+        let bytes = &buffer[..info.buffer_size()];
+        let samples: Vec<u16> = match info.bit_depth {
+            png::BitDepth::Sixteen => bytes
+                .chunks_exact(2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+                .collect(),
+            _ => bytes.iter().map(|&v| v as u16 * 257).collect(),
+        };
+        let depths = samples
+            .chunks_exact(info.color_type.samples())
+            .map(|px| match *px {
+                [r, g, b, ..] => ((r as u32 + g as u32 + b as u32) / 3) as u16,
+                [l, ..] => l,
+                [] => unreachable!("PNG pixels have 1 to 4 samples"),
+            })
+            .collect::<Vec<_>>();
+        if depths.len() != (info.width * info.height) as usize {
+            return Err("Truncated PNG".into());
+        }
+        Ok(DepthMap {
+            width: info.width,
+            height: info.height,
+            depths,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        convert::{ColorMode, ColorSpace, Metric, Reduction, convert_frame},
+        testing::TempDir,
+    };
+
+    #[test]
+    fn depth_maps_keep_16_bits() {
+        let dir = TempDir::new("depth-16");
+        let path = dir.join("depth.png");
+        let mut encoder = png::Encoder::new(File::create(&path).unwrap(), 3, 1);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        let depths: [u16; 3] = [1000, 1001, 65535];
+        let bytes: Vec<u8> = depths.iter().flat_map(|d| d.to_be_bytes()).collect();
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&bytes)
+            .unwrap();
+
+        let map = DepthMap::read(&path).unwrap();
+        assert_eq!((map.width, map.height), (3, 1));
+        assert_eq!(map.depths, depths);
+    }
+
+    #[test]
+    fn depth_maps_scale_8_bits_to_16() {
+        let dir = TempDir::new("depth-8");
+        let path = dir.join("depth.png");
+        let mut encoder = png::Encoder::new(File::create(&path).unwrap(), 2, 1);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&[0, 0, 0, 255, 255, 255])
+            .unwrap();
+
+        assert_eq!(DepthMap::read(&path).unwrap().depths, [0, 65535]);
+    }
+
+    #[test]
+    fn posterizing_collapses_the_levels() {
+        let gradient = |x: u16, _| (x as u8 * 8, 255 - x as u8 * 8, x as u8 * 4, 255);
+        let mut frame = GifFrame::from_fn(32, 2, gradient);
+        posterize(&mut frame, 2);
+        for &(r, g, b, a) in &frame.buffer {
+            assert!([r, g, b].iter().all(|c| *c == 0 || *c == 255));
+            assert_eq!(a, 255);
+        }
+
+        // A dot per pixel, and the gray ramp only has the two sizes left
+        let mut gray =
+            GifFrame::from_fn(32, 2, |x, _| (x as u8 * 8, x as u8 * 8, x as u8 * 8, 255));
+        posterize(&mut gray, 2);
+        let key = |pixel: &Rgba| Metric::Brightness.key(pixel) as usize;
+        let (mode, space) = (ColorMode::Average, ColorSpace::Srgb);
+        let dots = convert_frame(&gray, 1, (0, 0), Reduction::Mean, mode, space, &key);
+        let mut sizes = dots.buffer.clone();
+        sizes.sort();
+        sizes.dedup();
+        assert_eq!(sizes, [0, 255]);
+    }
+
+    #[test]
+    fn dithering_keeps_the_mean() {
+        let mut frame = GifFrame::from_fn(16, 16, |_, _| (100, 100, 100, 255));
+        dither(&mut frame, 2);
+
+        let reds: Vec<u32> = frame.buffer.iter().map(|p| p.0 as u32).collect();
+        assert!(reds.iter().all(|&r| r == 0 || r == 255));
+        assert!(reds.contains(&0) && reds.contains(&255));
+        let mean = reds.iter().sum::<u32>() as f32 / reds.len() as f32;
+        assert!((mean - 100.0).abs() < 4.0, "{mean}");
+    }
+
+    #[test]
+    fn nearest_neighbor_adds_no_colors() {
+        let checkerboard = GifFrame::from_fn(16, 16, |x, y| {
+            let c = if (x + y) % 2 == 0 { 0 } else { 255 };
+            (c, c, c, 255)
+        });
+        let colors = |filter| {
+            let mut colors: Vec<Rgba> = scale_frame(&checkerboard, 0.3, filter).buffer;
+            colors.sort();
+            colors.dedup();
+            colors
+        };
+        assert_eq!(
+            colors(ScaleFilter::Nearest),
+            [(0, 0, 0, 255), (255, 255, 255, 255)]
+        );
+        // Averaging the squares gives grays in between
+        let triangle = colors(ScaleFilter::Triangle);
+        assert!(triangle.iter().any(|&(c, _, _, _)| c > 0 && c < 255));
+    }
+
+    #[test]
+    fn trimming_bounds_the_shape_tightly() {
+        // A 4x3 square on a noisy black, moving one pixel right in the second frame
+        let square = |left: u16| {
+            GifFrame::from_fn(20, 10, move |x, y| match (left..left + 4).contains(&x) {
+                true if (3..6).contains(&y) => (200, 100, 50, 255),
+                _ => (0, 0, ((x + y) % 3) as u8, 255),
+            })
+        };
+        let frames = [square(8), square(9)];
+        assert_eq!(content_bounds(&frames, 0), Some((0, 0, 20, 10)));
+        let bounds = content_bounds(&frames, 2).unwrap();
+        assert_eq!(bounds, (8, 3, 5, 3));
+
+        let trimmed = crop_frame(&frames[1], bounds);
+        assert_eq!((trimmed.width, trimmed.height), (5, 3));
+        assert_eq!(trimmed.buffer[0], (0, 0, 2, 255));
+        assert_eq!(trimmed.buffer[1..5], [(200, 100, 50, 255); 4]);
+
+        let border = GifFrame::from_fn(4, 4, |_, _| (0, 0, 0, 255));
+        assert_eq!(content_bounds(&[border], 0), None);
+    }
+
+    #[test]
+    fn uncovered_screen_gets_the_background_color() {
+        // A white 2x2 frame inside a 4x4 screen, whose background is the red at index 0
+        let mut bytes = Vec::new();
+        let mut encoder = gif::Encoder::new(&mut bytes, 4, 4, &[255, 0, 0, 255, 255, 255]).unwrap();
+        let frame = gif::Frame {
+            left: 1,
+            top: 1,
+            width: 2,
+            height: 2,
+            buffer: vec![1; 4].into(),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&frame).unwrap();
+        drop(encoder);
+
+        let first = |canvas_color| {
+            let reader = GifFrameReader::new(bytes.as_slice()).unwrap();
+            reader
+                .with_canvas_color(canvas_color)
+                .next()
+                .unwrap()
+                .unwrap()
+        };
+        let covered = |i: usize| (1..3).contains(&(i % 4)) && (1..3).contains(&(i / 4));
+        for (canvas_color, behind) in [(None, (255, 0, 0, 255)), (Some((0, 0, 9)), (0, 0, 9, 255))]
+        {
+            let frame = first(canvas_color);
+            for (i, &pixel) in frame.buffer.iter().enumerate() {
+                let expected = if covered(i) {
+                    (255, 255, 255, 255)
+                } else {
+                    behind
+                };
+                assert_eq!(pixel, expected, "pixel {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn blended_frames_are_the_average_of_their_group() {
+        let frames: Vec<GifFrame> = (0..8u16)
+            .map(|i| GifFrame {
+                delay: 10,
+                ..GifFrame::from_fn(3, 2, |x, y| {
+                    let v = (i * 20 + x * 5 + y) as u8;
+                    (v, 255 - v, i as u8, 255)
+                })
+            })
+            .collect();
+        let blended: Vec<GifFrame> = frames.chunks(4).map(blend_frames).collect();
+
+        assert_eq!(blended.len(), 2);
+        for (group, frame) in frames.chunks(4).zip(&blended) {
+            assert_eq!(frame.delay, 40);
+            for (i, &pixel) in frame.buffer.iter().enumerate() {
+                let total =
+                    |c: fn(&Rgba) -> u8| group.iter().map(|f| c(&f.buffer[i]) as u32).sum::<u32>();
+                let average = |c| ((total(c) + 2) / 4) as u8;
+                let expected = (average(|p| p.0), average(|p| p.1), average(|p| p.2), 255);
+                assert_eq!(pixel, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn blurring_evens_out_noisy_blocks() {
+        // Gray with salt and pepper noise scattered unevenly over the blocks
+        let noisy = GifFrame::from_fn(32, 32, |x, y| {
+            let mut hash = ((x as u32) << 16 | y as u32).wrapping_mul(0x9e37_79b1);
+            hash ^= hash >> 15;
+            let v = match hash % 23 {
+                0 | 1 => 255,
+                2 => 0,
+                _ => 128,
+            };
+            (v, v, v, 255)
+        });
+        let spread = |frame: &GifFrame| {
+            let key = |pixel: &Rgba| Metric::Brightness.key(pixel) as usize;
+            let (mode, space) = (ColorMode::Average, ColorSpace::Srgb);
+            let dots = convert_frame(frame, 4, (0, 0), Reduction::Mean, mode, space, &key);
+            // The mean distance of the values from their mean
+            let mean = dots.buffer.iter().sum::<usize>() as f32 / dots.buffer.len() as f32;
+            let distance: f32 = dots.buffer.iter().map(|&v| (v as f32 - mean).abs()).sum();
+            distance / dots.buffer.len() as f32
+        };
+        let mut blurred = noisy.clone();
+        blur_frame(&mut blurred, 4.0);
+
+        assert!(
+            spread(&blurred) * 2.0 < spread(&noisy),
+            "{} {}",
+            spread(&blurred),
+            spread(&noisy)
+        );
+    }
+
+    #[test]
+    fn red_gamma_below_one_brightens_the_reds() {
+        let original = GifFrame::from_fn(2, 1, |x, _| match x {
+            0 => (128, 0, 0, 255),
+            _ => (0, 128, 0, 255),
+        });
+        let mut graded = original.clone();
+        apply_gamma(&mut graded, (0.5, 1.0, 1.0));
+
+        let key = |frame: &GifFrame, x: usize| Metric::Brightness.key(&frame.buffer[x]);
+        assert!(key(&graded, 0) > key(&original, 0));
+        assert_eq!(key(&graded, 1), key(&original, 1));
+        assert_eq!(graded.buffer[0], (181, 0, 0, 255));
+    }
+}
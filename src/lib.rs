@@ -0,0 +1,29 @@
+//! The building blocks of the pointillist converter that are useful on their own.
+
+pub mod color;
+pub mod dots;
+
+#[cfg(feature = "pipeline")]
+pub mod convert;
+#[cfg(feature = "pipeline")]
+pub mod encode;
+#[cfg(feature = "pipeline")]
+pub mod input;
+#[cfg(feature = "pipeline")]
+pub mod palette;
+#[cfg(feature = "pipeline")]
+pub mod render;
+#[cfg(feature = "pipeline")]
+pub mod sequence;
+#[cfg(feature = "pipeline")]
+pub mod stream;
+#[cfg(feature = "pipeline")]
+pub mod timing;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+#[cfg(feature = "wasm")]
+pub use wasm::stipple_gif;
+
+#[cfg(all(test, feature = "pipeline"))]
+mod testing;
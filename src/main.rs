@@ -1,9 +1,44 @@
-use std::{borrow::Cow, fs::File, path::Path};
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use clap::Parser;
-use gif::{ColorOutput, DecodeOptions, Encoder, Frame, Repeat};
+use clap::{Parser, ValueEnum};
+use gif::Repeat;
+use pointillist::{
+    color::{Rgb, Rgba, flatten, parse_color, weighted_brightness},
+    convert::{
+        Blend, Channel, ColorMode, ColorSpace, Lut, Metric, Reduction, convert_frame, depth_values,
+        disparity_map, grid_size, grid_start, neighborhood_values,
+    },
+    dots::{
+        DotFrame, Neighborhood, boost_cuts, crossfade_loop, equalize_tiles, motion_frames,
+        process_frames, remove_isolated_dots, smooth_values,
+    },
+    encode::{encode_circles_gif, verify_output, write_circles_gif, write_gif_chunks},
+    input::{
+        DepthMap, GifFrame, GifFrameReader, ScaleFilter, apply_gamma, blend_frames, blur_frame,
+        content_bounds, crop_frame, dither, posterize, read_png, scale_frame, scaled_size,
+        work_block_size,
+    },
+    palette::write_palette_file,
+    render::{
+        Background, Border, CellShape, ColorSource, Gradient, Layout, RenderOptions, Response,
+        SquareMode, Style, Tonemap, fits_gif, output_dimensions, render_frame,
+    },
+    sequence::{ExistingFrames, NameTemplate, is_png_sequence, write_png_sequence},
+    stream::stream_circles_gif,
+    timing::{Retimer, TimingCurve, speed_up},
+};
+use serde::Serialize;
 
-#[derive(Parser)]
+#[cfg(feature = "tui")]
+mod tui;
+
+#[derive(Clone, Parser)]
 #[command(name = "Pointillist")]
 #[command(version = "0.1")]
 #[command(about = "Turns any gif into a pointillist style gif.", long_about = None)]
@@ -12,268 +47,2259 @@ pub struct Args {
     #[arg(short, long)]
     pub in_path: String,
 
-    /// Path to the output GIF file
-    #[arg(short, long)]
-    pub out_path: String,
+    /// Color behind the frames of the input GIF [default: the declared background color
+    /// where the first frame doesn't reach, transparent elsewhere]
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_color)]
+    pub canvas_color: Option<(u8, u8, u8)>,
+
+    /// Path to the output GIF file, or a .png path to write a numbered PNG sequence
+    #[arg(short, long, required_unless_present = "benchmark_mode")]
+    pub out_path: Option<String>,
 
     /// Size of the blocks to cluster pixels into
     #[arg(short, long, default_value_t = 8)]
     pub block_size: usize,
 
+    /// Choose the block size so there are N columns of dots across the input instead
+    #[arg(long, value_name = "N", conflicts_with = "block_size",
+          value_parser = clap::value_parser!(u16).range(1..))]
+    pub grid_density: Option<u16>,
+
     /// How much padding to add between the circles
     #[arg(short, long, default_value_t = 2)]
     pub padding: u32,
 
+    /// Repeat the frames of the input this many times before processing them
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub input_loop: u32,
+
+    /// Only convert the first frame, to get a still image out of an animation
+    #[arg(long, conflicts_with_all = ["input_loop", "stream"])]
+    pub first_frame_only: bool,
+
+    /// Stop decoding the input after this many frames, e.g. for a quick preview
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_frames: Option<u32>,
+
+    /// Crop away the borders that have the color of the top left pixel in every frame
+    #[arg(long, conflicts_with = "stream")]
+    pub trim: bool,
+
+    /// How far the channels of a border pixel may be off the border color for --trim
+    #[arg(long, default_value_t = 0, requires = "trim")]
+    pub trim_tolerance: u8,
+
+    /// Increase the block size until the output fits the 65535x65535 limit of GIFs
+    #[arg(long)]
+    pub clamp_output_size: bool,
+
+    /// Scale the input frames by this factor before clustering them into blocks
+    #[arg(long, value_name = "FACTOR", value_parser = parse_positive)]
+    pub scale: Option<f32>,
+
+    /// Shrink the input frames to at most N pixels wide and high before clustering them, with a
+    /// block size to match, so large inputs convert faster into the same grid of dots
+    #[arg(long, value_name = "N", conflicts_with = "scale",
+          value_parser = clap::value_parser!(u16).range(1..))]
+    pub work_resolution: Option<u16>,
+
+    /// Interpolation used by --scale and --work-resolution, nearest keeps the hard edges of pixel art
+    #[arg(long, value_enum, default_value_t = ScaleFilter::Triangle)]
+    pub scale_filter: ScaleFilter,
+
+    /// Shift the grid of blocks by X,Y pixels
+    #[arg(long, value_name = "X,Y", value_parser = parse_pair::<i32>, default_value = "0,0")]
+    pub grid_offset: (i32, i32),
+
+    /// Move the dots by the fraction of a pixel that the grid offset amounts to in the output,
+    /// instead of putting every center on a whole pixel. Best with --antialias-samples
+    #[arg(long)]
+    pub subpixel_centers: bool,
+
     /// Maximum radius of the circles
     #[arg(short, long, default_value_t = 8)]
     pub radius: u32,
 
-    /// Delay of the frames in the output GIF
+    /// Maximum radius of the circles on the first frame [default: radius]
+    #[arg(long)]
+    pub start_radius: Option<u32>,
+
+    /// Maximum radius of the circles on the last frame [default: start-radius]
+    #[arg(long)]
+    pub end_radius: Option<u32>,
+
+    /// Distance between the centers of neighboring circles [default: 2 * radius + padding]
+    #[arg(long)]
+    pub spacing: Option<u32>,
+
+    /// Allow circles to grow past their cell and overlap their neighbors
+    #[arg(long)]
+    pub no_clip: bool,
+
+    /// Cut every dot off at the edge of its cell, for a tiled mosaic look with --no-clip or
+    /// a large --spacing
+    #[arg(long, value_enum, default_value_t = CellShape::None)]
+    pub cell_shape: CellShape,
+
+    /// Draw a red/cyan anaglyph, moving the dots of the two eyes up to SEPARATION pixels apart
+    /// so the big dots come out of the screen and the small ones sink into it. Made for
+    /// --metric depth
+    #[arg(long, value_name = "SEPARATION", value_parser = parse_positive)]
+    pub stereo: Option<f32>,
+
+    /// GIF of the right eye to the input of the left eye, giving the dots of --metric depth
+    /// the sizes of how far their blocks move between the first frames of the two, up to an
+    /// eighth of the width
+    #[arg(
+        long,
+        value_name = "RIGHT",
+        requires = "stereo",
+        conflicts_with = "depth_map"
+    )]
+    pub stereo_pair: Option<String>,
+
+    /// Merge squares of up to 2^DEPTH x 2^DEPTH cells with similar values into one big dot,
+    /// so flat areas get a few large dots and detailed ones many small dots
+    #[arg(long, visible_alias = "metric-adaptive-block", value_name = "DEPTH",
+          value_parser = clap::value_parser!(u32).range(1..=8))]
+    pub adaptive_blocks: Option<u32>,
+
+    /// How far the values of merged cells may differ with --adaptive-blocks, as a fraction
+    /// of the maximum value (0.0 to 1.0)
+    #[arg(long, value_name = "T", default_value_t = 0.1, value_parser = parse_fraction)]
+    pub adaptive_tolerance: f32,
+
+    /// Delay of the frames in the output GIF, in hundredths of a second
     #[arg(short, long, default_value_t = 5)]
     pub delay: u16,
+
+    /// Frame rate of the output GIF instead of a delay, rounded to the nearest whole delay
+    #[arg(long, conflicts_with = "delay", value_parser = parse_positive)]
+    pub fps: Option<f32>,
+
+    /// Play the output this many times as fast by changing the delay. Viewers slow down delays
+    /// below 2 hundredths of a second, so beyond that frames are dropped instead
+    #[arg(long, default_value_t = 1.0, value_parser = parse_positive)]
+    pub speed: f32,
+
+    /// Keep the delay of every input frame instead of giving all frames the same one, scaled
+    /// by --speed. Frames that would get too short are dropped and their time goes to the next
+    #[arg(long, conflicts_with_all = ["delay", "fps", "timing_curve"])]
+    pub retime_from_input: bool,
+
+    /// Average every N consecutive input frames into one, for motion blur and fewer frames
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    pub frame_blend: u16,
+
+    /// Fade the last N frames into the first N, so an animation that doesn't loop by itself
+    /// loops without a jump. The output gets N frames shorter
+    #[arg(long, value_name = "N", conflicts_with = "stream")]
+    pub loop_crossfade: Option<usize>,
+
+    /// Treat frames whose dot values changed by more than T on average since the previous
+    /// frame as scene cuts and flash their dots, see --cut-boost
+    #[arg(
+        long,
+        value_name = "T",
+        conflicts_with = "stream",
+        visible_alias = "metric-temporal-derivative"
+    )]
+    pub cut_threshold: Option<f32>,
+
+    /// How much the dot values of a scene cut are scaled up by, capped at the largest value
+    #[arg(long, value_name = "FACTOR", default_value_t = 1.5, value_parser = parse_positive)]
+    pub cut_boost: f32,
+
+    /// Loop the output as often as the input instead of forever
+    #[arg(long)]
+    pub preserve_loop_metadata: bool,
+
+    /// Which property of the pixels decides the size of the circles
+    #[arg(short, long, value_enum, default_value_t = Metric::Brightness)]
+    pub metric: Metric,
+
+    /// Convert once per metric and write them next to each other, e.g. out.brightness.gif
+    #[arg(long, value_enum, value_delimiter = ',', conflicts_with = "metric")]
+    pub metrics: Vec<Metric>,
+
+    /// Composite the pixels onto this color by their opacity before computing the metric,
+    /// instead of ignoring the ones below half opacity
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_color)]
+    pub flatten_color: Option<(u8, u8, u8)>,
+
+    /// Give the cool colors the big dots with --metric temperature
+    #[arg(long)]
+    pub temperature_invert: bool,
+
+    /// Give the dark, far parts of a depth map the big dots with --metric depth
+    #[arg(long)]
+    pub depth_invert: bool,
+
+    /// Grayscale PNG to take the sizes of --metric depth from instead of the input, stretched
+    /// over it. 16-bit depth maps keep their full precision
+    #[arg(long, value_name = "FILE")]
+    pub depth_map: Option<String>,
+
+    /// The channel that --metric channel reads [default: r]
+    #[arg(long, value_enum)]
+    pub channel: Option<Channel>,
+
+    /// Only look at bit N of the channel with --metric channel, 7 being the highest, so the
+    /// dots are either empty or full
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(0..=7))]
+    pub bit_plane: Option<u8>,
+
+    /// Remap the metric through a lookup table, a file of `KEY VALUE` lines in 0..=255
+    #[arg(long, value_name = "PATH", value_parser = Lut::from_file)]
+    pub metric_file: Option<Lut>,
+
+    /// Custom R,G,B weights for the brightness metric, normalized to sum up to 1
+    #[arg(long, value_name = "R,G,B", value_parser = parse_weights, conflicts_with = "metrics")]
+    pub metric_weights: Option<(f32, f32, f32)>,
+
+    /// Weighted metrics for --metric blend, normalized to sum up to 1
+    #[arg(long, value_name = "METRIC:WEIGHT,...", value_parser = Blend::parse)]
+    pub blend: Option<Blend>,
+
+    /// How the values are mapped to the radius of the circles
+    #[arg(long, value_enum, default_value_t = Response::Linear)]
+    pub response: Response,
+
+    /// Exponent of --response pow, above 1 shrinks the dark areas and below 1 grows them
+    #[arg(long, default_value_t = 2.0, value_parser = parse_positive)]
+    pub response_exp: f32,
+
+    /// How the frame delays are distributed over the animation
+    #[arg(long, value_enum, default_value_t = TimingCurve::Linear)]
+    pub timing_curve: TimingCurve,
+
+    /// Where the colors of the circles come from [default: fixed, or size with --gradient-map]
+    #[arg(long, value_enum)]
+    pub dot_color_from: Option<ColorSource>,
+
+    /// How the pixels of a block are combined into the color of its circle
+    #[arg(long, value_enum, default_value_t = ColorMode::Average)]
+    pub color_mode: ColorMode,
+
+    /// Whether colors and brightness are averaged as they are stored or in linear light,
+    /// which keeps mixes of bright colors from getting too dark
+    #[arg(long, value_enum, default_value_t = ColorSpace::Srgb)]
+    pub color_space: ColorSpace,
+
+    /// Gradient that colors the circles by size: grayscale, viridis, magma or a file of stops
+    #[arg(long, value_name = "GRADIENT", value_parser = Gradient::from_arg)]
+    pub gradient_map: Option<Gradient>,
+
+    /// Color of the circles when they have a fixed color
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_color, default_value = "ffffff")]
+    pub dot_color: (u8, u8, u8),
+
+    /// With --metric motion, draw the blocks that got brighter in the first color and the
+    /// ones that got darker in the second, sized by how much they changed
+    #[arg(long, visible_alias = "metric-signed", value_name = "RRGGBB,RRGGBB",
+          value_parser = parse_color_pair)]
+    pub signed: Option<(Rgb, Rgb)>,
+
+    /// Tone mapping applied to the colors of the circles
+    #[arg(long, value_enum, default_value_t = Tonemap::None)]
+    pub tonemap: Tonemap,
+
+    /// Invert every channel of the circle colors for a false color negative
+    #[arg(long)]
+    pub metric_invert_per_channel: bool,
+
+    /// Rotate the hue of the circle colors by this many degrees
+    #[arg(
+        long,
+        value_name = "DEGREES",
+        default_value_t = 0.0,
+        allow_negative_numbers = true
+    )]
+    pub hue_rotate: f32,
+
+    /// How the dots are drawn
+    #[arg(short, long, value_enum, default_value_t = Style::Dots)]
+    pub style: Style,
+
+    /// Draw every frame over the previous one faded by DECAY (0.0..1.0), leaving trails
+    #[arg(long, value_name = "DECAY", default_value_t = 0.0, value_parser = parse_decay)]
+    pub accumulate: f32,
+
+    /// Render the image N times larger and shrink it back down, which smooths all edges
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=16))]
+    pub render_scale: u32,
+
+    /// Smooth the edges of the circles with NxN samples per pixel, 1 turns it off
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..=16))]
+    pub antialias_samples: u32,
+
+    /// Draw ellipses with this ratio of width to height instead of circles, e.g. 2:1
+    #[arg(long, value_name = "W:H", default_value = "1:1", value_parser = parse_aspect)]
+    pub dot_aspect: f32,
+
+    /// Draw the dots with this opacity from 0.0 to 1.0, so the background shows through them.
+    /// GIFs only keep it over an opaque background
+    #[arg(long, value_name = "OPACITY", default_value_t = 1.0, value_parser = parse_fraction)]
+    pub dot_opacity: f32,
+
+    /// Morph the dots from circles at the smallest values towards squares at the largest, as the
+    /// exponent of a superellipse going from FROM to TO
+    #[arg(long, value_name = "FROM:TO", value_parser = parse_shape_range, num_args = 0..=1,
+          require_equals = true, default_missing_value = "2:8")]
+    pub dot_shape_from_value: Option<(f32, f32)>,
+
+    /// With the field style, only draw where the field reaches this value (0.0 to 1.0)
+    #[arg(long)]
+    pub field_threshold: Option<f32>,
+
+    /// With the binary style, the fraction of the maximum value (0.0 to 1.0) that a block
+    /// needs to get a dot
+    #[arg(long, value_name = "T", default_value_t = 0.5, value_parser = parse_fraction)]
+    pub binary_threshold: f32,
+
+    /// Make the output square by padding or cropping it, keeping the dots centered
+    #[arg(long, value_enum)]
+    pub square_output: Option<SquareMode>,
+
+    /// Color of the padding added by --square-output pad [default: transparent]
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_color)]
+    pub square_color: Option<(u8, u8, u8)>,
+
+    /// Fill the cells of the dots with this color behind the circles
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_color)]
+    pub cell_background: Option<(u8, u8, u8)>,
+
+    /// Keep the cells of fully transparent blocks of the input transparent, without dots,
+    /// --cell-background or --background-image there
+    #[arg(long)]
+    pub output_transparency_from_source: bool,
+
+    /// PNG image to draw the circles on, stretched to the size of the output
+    #[arg(long, value_name = "FILE", conflicts_with = "accumulate")]
+    pub background_image: Option<String>,
+
+    /// Draw lines between the cells of the grid, to show which block became which dot
+    #[arg(long, value_name = "RRGGBB", value_parser = parse_color, num_args = 0..=1,
+          require_equals = true, default_missing_value = "808080")]
+    pub grid_lines: Option<(u8, u8, u8)>,
+
+    /// Draw a solid border of WIDTH pixels around the image [default color: 000000]
+    #[arg(long, value_name = "WIDTH[:RRGGBB]", value_parser = parse_border)]
+    pub border: Option<Border>,
+
+    /// Reduce every color channel of the input to this many levels, for a banded poster look
+    #[arg(long, value_name = "LEVELS", value_parser = clap::value_parser!(u16).range(2..=256))]
+    pub posterize: Option<u16>,
+
+    /// Error diffuse the input to BITS per channel before averaging the blocks, for some grain
+    #[arg(long, value_name = "BITS", value_parser = clap::value_parser!(u8).range(1..=7))]
+    pub dither_source: Option<u8>,
+
+    /// Gaussian blur the input with a standard deviation of RADIUS pixels before the metric,
+    /// so noise doesn't make the dots jitter
+    #[arg(long, value_name = "RADIUS", value_parser = parse_positive)]
+    pub preprocess_blur: Option<f32>,
+
+    /// Raise every color channel of the input to its own power, e.g. 0.8,1,1.2 to warm it up
+    /// before the metric and the colors
+    #[arg(long, visible_alias = "metric-gamma-per-channel", value_name = "R,G,B",
+          value_parser = parse_gammas)]
+    pub gamma_rgb: Option<(f32, f32, f32)>,
+
+    /// How much of the error of mapping the colors to the GIF palette is diffused to the
+    /// neighboring pixels, from 0.0 for none to 1.0 for full Floyd-Steinberg dithering
+    #[arg(long, value_name = "STRENGTH", default_value_t = 0.0, value_parser = parse_fraction)]
+    pub palette_dither_strength: f32,
+
+    /// Spread the value of every dot over the cells up to RADIUS cells away, so the sizes change
+    /// smoothly between neighbors
+    #[arg(long, value_name = "RADIUS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub metric_smooth: Option<u32>,
+
+    /// Equalize the histogram of the dot values in TILES x TILES tiles, blended between the
+    /// tiles, so regions of low contrast use all the dot sizes as well
+    #[arg(long, visible_alias = "metric-contrast-adaptive", value_name = "TILES",
+          value_parser = clap::value_parser!(u32).range(1..=64))]
+    pub adaptive_normalization: Option<u32>,
+
+    /// Clear every dot below this value, so dark noise becomes clean background
+    #[arg(long, value_name = "T")]
+    pub min_value: Option<usize>,
+
+    /// Remove dots smaller than this value that have no other dot next to them
+    #[arg(long)]
+    pub merge_threshold: Option<usize>,
+
+    /// Which cells count as next to each other for --merge-threshold
+    #[arg(long, value_enum, default_value_t = Neighborhood::Eight)]
+    pub merge_neighborhood: Neighborhood,
+
+    /// The dot value that gets the maximum radius [default: the largest value of all frames]
+    #[arg(long)]
+    pub max_value: Option<usize>,
+
+    /// Map the values between LOW and HIGH to the radius, everything below gets no circle and
+    /// everything above the maximum radius
+    #[arg(long, value_name = "LOW,HIGH", value_parser = parse_pair::<usize>, conflicts_with = "max_value")]
+    pub metric_clamp: Option<(usize, usize)>,
+
+    /// Map the values between LOW and HIGH to the radius like --metric-clamp, but give the
+    /// values outside of the band no circle at all, to pick out the midtones for example
+    #[arg(long, visible_alias = "metric-bandpass", value_name = "LOW,HIGH",
+          value_parser = parse_pair::<usize>, conflicts_with_all = ["max_value", "metric_clamp"])]
+    pub band: Option<(usize, usize)>,
+
+    /// Stream the frames through a pipeline of threads instead of keeping them all in memory.
+    /// Every frame gets its own palette, and --max-value or --metric-clamp is needed
+    #[arg(long)]
+    pub stream: bool,
+
+    /// Split the output into GIFs of at most N frames each, `out.gif` becomes `out.part0.gif`,
+    /// `out.part1.gif`, ...
+    #[arg(long, value_name = "N", conflicts_with_all = ["stream", "output_palette_file"],
+          value_parser = clap::value_parser!(u32).range(1..))]
+    pub chunk: Option<u32>,
+
+    /// Number of worker threads when streaming or writing a PNG sequence, which doesn't change
+    /// the output [default: number of CPUs]
+    #[arg(long)]
+    pub threads: Option<usize>,
+
+    /// Keep the frames of a PNG sequence that were already written, e.g. by an interrupted run
+    #[arg(long, conflicts_with = "force")]
+    pub resume: bool,
+
+    /// Overwrite the frames of a PNG sequence that already exist
+    #[arg(long)]
+    pub force: bool,
+
+    /// Write indexed PNGs with 1, 2, 4 or 8 bits per pixel, transparency becomes all or nothing
+    #[arg(long, value_name = "BITS", value_parser = parse_bit_depth)]
+    pub output_bitdepth: Option<u8>,
+
+    /// Name the files of a PNG sequence after this template next to the output path, where
+    /// n in braces is the frame index and `{n:05}` pads it with zeros to 5 digits
+    #[arg(long, visible_alias = "output-name-template", value_name = "TEMPLATE",
+          value_parser = NameTemplate::parse)]
+    pub name_template: Option<NameTemplate>,
+
+    /// Give every frame its own palette, quantized to just the colors of that frame
+    #[arg(long)]
+    pub local_palettes: bool,
+
+    /// Only encode the part of every GIF frame that changed since the previous one, which
+    /// makes mostly still animations a lot smaller
+    #[arg(long, alias = "output-gif-optimization", conflicts_with_all = ["local_palettes", "stream"])]
+    pub optimize: bool,
+
+    /// Use the global palette of the input GIF for the output instead of picking new colors,
+    /// every color is drawn with its closest entry
+    #[arg(long, conflicts_with_all = ["local_palettes", "stream"])]
+    pub output_colors_from_gif_palette: bool,
+
+    /// Average the metric over the pixels of a block weighted by their opacity, so blocks on
+    /// the edge of transparent areas aren't pulled down by them
+    #[arg(long)]
+    pub alpha_weighting: bool,
+
+    /// Don't write the parameters of the conversion into a comment of the output GIF
+    #[arg(long)]
+    pub no_metadata: bool,
+
+    /// Write a GIF without transparency, with black where it would be transparent
+    #[arg(long)]
+    pub no_transparent: bool,
+
+    /// Write interlaced GIF frames, which viewers can show roughly before they are loaded
+    #[arg(long, visible_alias = "output-interlaced")]
+    pub interlaced: bool,
+
+    /// Write the palette of the output GIF to a .gpl or .act file
+    #[arg(long, conflicts_with = "local_palettes")]
+    pub output_palette_file: Option<String>,
+
+    /// Decode the output again after writing it and check its frames
+    #[arg(long, conflicts_with = "benchmark_mode")]
+    pub verify: bool,
+
+    /// Fail instead of converting if the frames and buffers would take up more than this many
+    /// bytes, roughly estimated from the frame count and the sizes
+    #[arg(long, value_name = "BYTES")]
+    pub limit_memory: Option<u64>,
+
+    /// Write processing statistics as JSON to a file, or to stderr if the path is "-"
+    #[arg(long, value_name = "PATH")]
+    pub stats_json: Option<String>,
+
+    /// Write the decode, convert, render and encode time of every frame as JSON to a file
+    #[arg(long, value_name = "PATH", conflicts_with = "stream")]
+    pub profile: Option<String>,
+
+    /// Write a JSON description of the output to a file: its format, size, palette, delays,
+    /// the parameters and a hash of the input
+    #[arg(long, value_name = "PATH", conflicts_with = "benchmark_mode")]
+    pub emit_manifest: Option<String>,
+
+    /// Run the whole pipeline without writing any output and print the throughput
+    #[arg(long, hide = true)]
+    pub benchmark_mode: bool,
+
+    /// Preview the first frame in the terminal and tune the parameters with the keyboard
+    #[cfg(feature = "tui")]
+    #[arg(long, conflicts_with_all = ["stream", "metrics", "benchmark_mode"])]
+    pub tui: bool,
 }
 
-#[derive(Debug)]
-pub struct DotFrame {
-    pub width: u16,
-    pub height: u16,
+/// Parses two comma separated colors like `00ff00,ff0000`.
+fn parse_color_pair(s: &str) -> Result<(Rgb, Rgb), String> {
+    let (a, b) = s
+        .split_once(',')
+        .ok_or_else(|| format!("Expected two comma separated colors, got: {}", s))?;
+    Ok((parse_color(a.trim())?, parse_color(b.trim())?))
+}
 
-    // Some "key" value, can be arbitrary for now e.g. brightness, hue
-    pub buffer: Vec<usize>,
+/// Parses two comma separated values like `3,-2`.
+fn parse_pair<T: std::str::FromStr>(s: &str) -> Result<(T, T), String> {
+    let (a, b) = s
+        .split_once(',')
+        .ok_or_else(|| format!("Expected two comma separated values, got: {}", s))?;
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<T>()
+            .map_err(|_| format!("Invalid value: {}", v))
+    };
+    Ok((parse(a)?, parse(b)?))
 }
 
-pub struct GifFrame {
-    pub width: u16,
-    pub height: u16,
-    /// The pixel data of the GIF frame in RGBA format.
-    pub buffer: Vec<(u8, u8, u8, u8)>,
+/// Parses three non-negative comma separated weights like `0.3,0.6,0.1` and normalizes them.
+fn parse_weights(s: &str) -> Result<(f32, f32, f32), String> {
+    let weights = s
+        .split(',')
+        .map(|v| {
+            v.trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|w| *w >= 0.0 && w.is_finite())
+                .ok_or_else(|| format!("Invalid weight: {}", v))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let [r, g, b] = weights[..] else {
+        return Err(format!(
+            "Expected three comma separated weights, got: {}",
+            s
+        ));
+    };
+
+    let sum = r + g + b;
+    if sum == 0.0 {
+        return Err("At least one weight has to be positive".to_string());
+    }
+    Ok((r / sum, g / sum, b / sum))
 }
 
-fn extract_gif_frames<P: AsRef<Path>>(path: P) -> Result<Vec<GifFrame>, String> {
-    let file = File::open(path).map_err(|e| format!("Failed to open file: {}", e))?;
-    let mut decoder = DecodeOptions::new();
-    decoder.set_color_output(ColorOutput::RGBA);
-    let mut decoder = decoder
-        .read_info(file)
-        .map_err(|e| format!("Failed to read GIF info: {}", e))?;
+/// Parses three positive comma separated exponents like `0.8,1,1.2`.
+fn parse_gammas(s: &str) -> Result<(f32, f32, f32), String> {
+    let gammas = s
+        .split(',')
+        .map(|v| parse_positive(v.trim()).map_err(|e| format!("Invalid gamma {}: {}", v, e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    let [r, g, b] = gammas[..] else {
+        return Err(format!("Expected three comma separated gammas, got: {}", s));
+    };
+    Ok((r, g, b))
+}
 
-    let mut frames = Vec::new();
+/// Parses a border like `10` or `10:ff0000`.
+fn parse_border(s: &str) -> Result<Border, String> {
+    let (width, color) = match s.split_once(':') {
+        Some((width, color)) => (width, parse_color(color)?),
+        None => (s, (0, 0, 0)),
+    };
+    let width = width.trim().parse().map_err(|e| format!("{}", e))?;
+    Ok(Border { width, color })
+}
 
-    while let Some(frame) = decoder
-        .read_next_frame()
-        .map_err(|e| format!("Failed to read frame: {}", e))?
-    {
-        let width = frame.width;
-        let height = frame.height;
+/// The line `--benchmark-mode` prints, with the frames converted per second.
+fn throughput(frames: usize, elapsed: Duration) -> String {
+    let elapsed = elapsed.as_secs_f64();
+    format!(
+        "{} frames in {:.3}s, {:.2} frames/s",
+        frames,
+        elapsed,
+        frames as f64 / elapsed
+    )
+}
 
-        debug_assert!(
-            frame.buffer.len() == width as usize * height as usize * 4,
-            "Buffer length mismatch"
-        );
+/// Parses the bit depth of an indexed PNG.
+fn parse_bit_depth(s: &str) -> Result<u8, String> {
+    match s.parse::<u8>() {
+        Ok(bits @ (1 | 2 | 4 | 8)) => Ok(bits),
+        _ => Err("The bit depth has to be 1, 2, 4 or 8".to_string()),
+    }
+}
 
-        let mut buffer = Vec::with_capacity(width as usize * height as usize * 4);
+/// Parses a decay factor in the range 0.0..1.0.
+fn parse_decay(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(decay) if (0.0..1.0).contains(&decay) => Ok(decay),
+        Ok(_) => Err("The decay has to be at least 0 and below 1".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
 
-        let mut i = 0;
-        while i + 3 < frame.buffer.len() {
-            let rgba = (
-                frame.buffer[i],
-                frame.buffer[i + 1],
-                frame.buffer[i + 2],
-                frame.buffer[i + 3],
-            );
-            buffer.push(rgba);
-            i += 4;
-        }
+/// Parses a fraction in the range 0.0..=1.0.
+fn parse_fraction(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        Ok(_) => Err("The value has to be between 0 and 1".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
 
-        frames.push(GifFrame {
-            width,
-            height,
-            buffer,
-        });
+/// Parses a positive number like a frame rate or a scale factor.
+fn parse_positive(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+        Ok(_) => Err("The value has to be positive".to_string()),
+        Err(e) => Err(e.to_string()),
     }
+}
+
+/// Parses a ratio like `2:1` into the quotient of the two positive numbers.
+fn parse_aspect(s: &str) -> Result<f32, String> {
+    let (w, h) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Expected a ratio like W:H, got: {}", s))?;
+    Ok(parse_positive(w.trim())? / parse_positive(h.trim())?)
+}
+
+/// Parses the exponents `FROM:TO` of a superellipse, 2 is an ellipse and larger ones get squarer.
+fn parse_shape_range(s: &str) -> Result<(f32, f32), String> {
+    let (from, to) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Expected two exponents like FROM:TO, got: {}", s))?;
+    Ok((parse_positive(from.trim())?, parse_positive(to.trim())?))
+}
+
+/// Machine readable statistics about a single conversion, see `--stats-json`.
+#[derive(Serialize)]
+struct Stats {
+    frames: usize,
+    input_width: u16,
+    input_height: u16,
+    output_width: u32,
+    output_height: u32,
+    grid_width: u32,
+    grid_height: u32,
+    max_value: usize,
+    /// Not measured when streaming, the stages overlap then
+    decode_ms: Option<f64>,
+    convert_ms: Option<f64>,
+    /// Render and encode time of every frame
+    frame_ms: Vec<f64>,
+    output_bytes: u64,
+}
 
-    Ok(frames)
+/// How long every frame took in every stage, see `--profile`.
+#[derive(Serialize)]
+struct Profile {
+    /// For every frame of the input, before looping, blending or skipping them
+    decode_ms: Vec<f64>,
+    convert_ms: Vec<f64>,
+    render_ms: Vec<f64>,
+    encode_ms: Vec<f64>,
 }
 
-fn convert_to_dots(
-    frames: Vec<GifFrame>,
+/// Describes a finished conversion for other tools, see `--emit-manifest`.
+#[derive(Serialize)]
+struct Manifest {
+    /// "gif" or "png-sequence"
+    format: &'static str,
+    paths: Vec<PathBuf>,
+    width: u32,
+    height: u32,
+    frames: usize,
+    /// The global palette as RRGGBB colors, empty if every frame has a palette of its own
+    palette: Vec<String>,
+    /// In hundredths of a second, empty for PNG sequences
+    delays: Vec<u16>,
+    parameters: ManifestParameters,
+    source: ManifestSource,
+}
+
+#[derive(Serialize)]
+struct ManifestParameters {
     block_size: usize,
-    key_func: impl Fn(&(u8, u8, u8, u8)) -> usize,
-) -> Vec<DotFrame> {
-    let mut dot_frames = Vec::new();
-    for frame in frames {
-        // For every frame we want to cluster the pixels into blocks of size block_size x block_size
-        // and calculate the average brightness of each block.
-        let width = frame.width as usize;
-        let mut blocks = Vec::new();
-
-        for y in (0..frame.height as usize).step_by(block_size) {
-            for x in (0..frame.width as usize).step_by(block_size) {
-                let mut total = 0;
-                let mut count = 0;
-
-                for dy in 0..block_size {
-                    for dx in 0..block_size {
-                        let px = x + dx;
-                        let py = y + dy;
-                        if px >= frame.width as usize || py >= frame.height as usize {
-                            continue;
-                        }
+    start_radius: u32,
+    end_radius: u32,
+    padding: u32,
+    metric: String,
+    max_value: usize,
+    /// The whole command line, including what the other fields don't cover
+    arguments: Vec<String>,
+}
 
-                        let index = py * width + px;
-                        if index >= frame.buffer.len() {
-                            continue;
-                        }
+#[derive(Serialize)]
+struct ManifestSource {
+    path: String,
+    bytes: u64,
+    /// The 64 bit FNV-1a hash of the file as hex
+    fnv1a: String,
+}
 
-                        let pixel = frame.buffer[index];
-                        total += key_func(&pixel);
-                        count += 1;
-                    }
+/// The 64 bit FNV-1a hash of the bytes, which is simple and stable across versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Inserts the name of `metric` before the extension of `path`, `out.gif` becomes `out.lab.gif`.
+pub fn metric_path(path: &str, metric: Metric) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = metric.to_possible_value().expect("Metrics aren't skipped");
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}.{}.{}", stem, name.get_name(), ext.to_string_lossy()),
+        None => format!("{}.{}", stem, name.get_name()),
+    };
+    path.with_file_name(file_name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    #[cfg(feature = "tui")]
+    if args.tui {
+        return tui::run_tui(args);
+    }
+    run_metrics(&args)
+}
+
+/// Converts the input once for every metric of `--metrics`, or once if there are none.
+fn run_metrics(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    if args.metrics.is_empty() {
+        return run(args);
+    }
+
+    for &metric in &args.metrics {
+        let out_path = |path: &String| metric_path(path, metric);
+        let args = Args {
+            metric,
+            out_path: args.out_path.as_ref().map(out_path),
+            output_palette_file: args.output_palette_file.as_ref().map(out_path),
+            stats_json: args.stats_json.as_ref().map(|path| {
+                if path == "-" {
+                    path.clone()
+                } else {
+                    out_path(path)
                 }
+            }),
+            emit_manifest: args.emit_manifest.as_ref().map(out_path),
+            profile: args.profile.as_ref().map(out_path),
+            ..args.clone()
+        };
+        run(&args)?;
+    }
+    Ok(())
+}
+
+/// The most bytes a single rendered frame may take up without --limit-memory, larger ones
+/// would rather abort the conversion on the allocation than fail cleanly.
+const MAX_CANVAS_BYTES: u64 = 4 << 30;
+
+/// The first frame of a conversion with the width and height of the output, see --tui.
+type Preview = (u32, u32, Vec<Rgba>);
+
+/// Runs the whole conversion for one metric.
+fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    convert_gif(args, None)
+}
+
+/// Like [`run`], but with a `preview` only renders the first frame into it and writes nothing.
+fn convert_gif(
+    args: &Args,
+    preview: Option<&mut Preview>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let metric = args.metric;
+    let weights = args.metric_weights;
+    if weights.is_some() && !matches!(metric, Metric::Brightness | Metric::MedianBrightness) {
+        return Err("--metric-weights only applies to the brightness metrics".into());
+    }
+    if args.temperature_invert && metric != Metric::Temperature {
+        return Err("--temperature-invert only applies to the temperature metric".into());
+    }
+    if args.flatten_color.is_some() && metric == Metric::Alpha {
+        return Err(
+            "--flatten-color makes every pixel opaque, the alpha metric would be flat".into(),
+        );
+    }
+    if args.adaptive_blocks.is_some() && args.style == Style::Field {
+        return Err("--adaptive-blocks merges dots, it doesn't apply to the field style".into());
+    }
+    if args.stereo.is_some() && args.style == Style::Field {
+        return Err("--stereo moves the dots, it doesn't apply to the field style".into());
+    }
+    if args.signed.is_some() && metric != Metric::Motion {
+        return Err("--signed only applies to the motion metric".into());
+    }
+    if args.depth_invert && metric != Metric::Depth {
+        return Err("--depth-invert only applies to the depth metric".into());
+    }
+    if args.depth_map.is_some() && metric != Metric::Depth {
+        return Err("--depth-map only applies to the depth metric".into());
+    }
+    if args.stereo_pair.is_some() && metric != Metric::Depth {
+        return Err("--stereo-pair only applies to the depth metric".into());
+    }
+    if (args.channel.is_some() || args.bit_plane.is_some()) && metric != Metric::Channel {
+        return Err("--channel and --bit-plane only apply to the channel metric".into());
+    }
+    let channel = args.channel.unwrap_or(Channel::R);
+    let reduction = match metric.reduction() {
+        Reduction::Mean if args.alpha_weighting => Reduction::AlphaWeightedMean,
+        _ if args.alpha_weighting => {
+            return Err("--alpha-weighting only applies to metrics that average the blocks".into());
+        }
+        reduction => reduction,
+    };
+    if let Some((low, high)) = args.metric_clamp
+        && low >= high
+    {
+        return Err("--metric-clamp needs LOW below HIGH".into());
+    }
+    if let Some((low, high)) = args.band
+        && low >= high
+    {
+        return Err("--band needs LOW below HIGH".into());
+    }
+    let invert_temperature = args.temperature_invert;
+    let blend = match (&args.blend, metric) {
+        (Some(blend), Metric::Blend) => Some(blend),
+        (None, Metric::Blend) => return Err("--metric blend needs --blend".into()),
+        (Some(_), _) if args.metrics.is_empty() => {
+            return Err("--blend only applies to --metric blend".into());
+        }
+        _ => None,
+    };
+
+    let lut = &args.metric_file;
+    let key_func = move |pixel: &(u8, u8, u8, u8)| {
+        let flattened;
+        let pixel = match args.flatten_color {
+            Some(background) => {
+                let &(r, g, b, a) = pixel;
+                let (r, g, b) = flatten((r, g, b), a, background);
+                flattened = (r, g, b, 255);
+                &flattened
+            }
+            None => pixel,
+        };
+        let &(r, g, b, a) = pixel;
+        // The alpha metric is the opacity itself, so half transparent pixels keep their dots
+        if a < 128 && metric != Metric::Alpha {
+            return 0; // Make fully transparent pixels have zero brightness
+        }
+
+        let key = match weights {
+            // Scale brightness by alpha
+            Some(weights) => {
+                (weighted_brightness(r, g, b, weights) as f32 * (a as f32 / 255.0)) as u8
+            }
+            // Swapping red and blue turns warm into cool and the other way around
+            None if invert_temperature => metric.key(&(b, g, r, a)),
+            None if args.depth_invert => metric.key(&(255 - r, 255 - g, 255 - b, a)),
+            None if metric == Metric::Channel => channel.key(pixel, args.bit_plane),
+            None => match blend {
+                Some(blend) => blend.key(pixel),
+                None => metric.key(pixel),
+            },
+        };
+        match lut {
+            Some(lut) => lut.map(key) as usize,
+            None => key as usize,
+        }
+    };
+
+    let mut start_radius = args.start_radius.unwrap_or(args.radius);
+    let mut end_radius = args.end_radius.unwrap_or(start_radius);
+    let spacing = args
+        .spacing
+        .unwrap_or(2 * start_radius.max(end_radius) + args.padding);
+
+    if !args.no_clip {
+        // Keep every circle inside of its own cell
+        start_radius = start_radius.min(spacing / 2);
+        end_radius = end_radius.min(spacing / 2);
+    }
+
+    // GIF delays are whole hundredths of a second, so most frame rates can only be approximated
+    let delay = match args.fps {
+        Some(fps) => {
+            let delay = (100.0 / fps).round().clamp(1.0, u16::MAX as f32) as u16;
+            if 100.0 / delay as f32 != fps {
+                eprintln!(
+                    "Warning: {} fps can't be represented exactly, using {:.2} fps instead",
+                    fps,
+                    100.0 / delay as f32
+                );
+            }
+            delay
+        }
+        None => args.delay,
+    };
+    // Frames with delays of their own are retimed one by one instead
+    let (delay, frame_step) = match speed_up(delay, args.speed) {
+        _ if args.retime_from_input => (delay, 1),
+        (fast, step) if fast as f32 != delay as f32 * step as f32 / args.speed => {
+            eprintln!(
+                "Warning: speed {} can't be represented exactly, using {:.2} instead",
+                args.speed,
+                delay as f32 * step as f32 / fast as f32
+            );
+            (fast, step)
+        }
+        sped_up => sped_up,
+    };
+
+    let mut options = RenderOptions {
+        padding: args.padding,
+        start_radius,
+        end_radius,
+        spacing,
+        max_value: args.max_value.unwrap_or(1),
+        delay,
+        repeat: Repeat::Infinite,
+        value_window: args.metric_clamp.or(args.band),
+        band_pass: args.band.is_some(),
+        response: args.response,
+        response_exp: args.response_exp,
+        timing_curve: args.timing_curve,
+        dot_color_from: args
+            .dot_color_from
+            .unwrap_or(if args.gradient_map.is_some() {
+                ColorSource::Size
+            } else if metric == Metric::SobelDirection {
+                ColorSource::Original
+            } else {
+                ColorSource::Fixed
+            }),
+        dot_color: args.dot_color,
+        signed_colors: args.signed,
+        gradient: args
+            .gradient_map
+            .clone()
+            .unwrap_or_else(Gradient::grayscale),
+        tonemap: args.tonemap,
+        invert: args.metric_invert_per_channel,
+        hue_rotate: args.hue_rotate,
+        style: args.style,
+        cell_shape: args.cell_shape,
+        stereo: args.stereo,
+        adaptive_blocks: args.adaptive_blocks,
+        adaptive_tolerance: args.adaptive_tolerance,
+        antialias_samples: args.antialias_samples,
+        dot_aspect: args.dot_aspect,
+        dot_opacity: args.dot_opacity,
+        center_shift: (0.0, 0.0),
+        shape_exponents: args.dot_shape_from_value,
+        color_space: args.color_space,
+        render_scale: args.render_scale,
+        accumulate: args.accumulate,
+        field_threshold: args.field_threshold,
+        binary_threshold: args.binary_threshold,
+        square: args.square_output,
+        square_color: args.square_color,
+        grid_lines: args.grid_lines,
+        border: args.border,
+        local_palettes: args.local_palettes,
+        optimize: args.optimize,
+        no_transparent: args.no_transparent,
+        interlaced: args.interlaced,
+        palette_dither: args.palette_dither_strength,
+        source_palette: None,
+        png_bit_depth: args.output_bitdepth,
+        comment: None,
+        cell_background: args.cell_background,
+        transparency_from_source: args.output_transparency_from_source,
+        background: match &args.background_image {
+            Some(path) => Some(Arc::new(Background::new(
+                read_png(path).map_err(|err| format!("{path}: {err}"))?,
+            ))),
+            None => None,
+        },
+    };
+
+    let mut block_size = match args.grid_density {
+        Some(columns) => {
+            let (width, height) = GifFrameReader::open(&args.in_path)?.screen_size();
+            let (width, _) = match args.scale {
+                Some(factor) => scaled_size(width, height, factor),
+                None => (width, height),
+            };
+            if columns > width {
+                return Err(format!(
+                    "--grid-density {} is more than the {} pixels the input is wide",
+                    columns, width
+                )
+                .into());
+            }
+            (width as usize).div_ceil(columns as usize)
+        }
+        None => args.block_size,
+    };
+    if args.clamp_output_size {
+        let (width, height) = GifFrameReader::open(&args.in_path)?.screen_size();
+        let (width, height) = match args.scale {
+            Some(factor) => scaled_size(width, height, factor),
+            None => (width, height),
+        };
+        let dimensions =
+            |block_size| output_dimensions(width, height, block_size, args.grid_offset, &options);
+
+        let requested = block_size;
+        while !fits_gif(dimensions(block_size)) && block_size < width.max(height) as usize {
+            block_size += 1;
+        }
+        if block_size != requested {
+            let (w, h) = dimensions(block_size);
+            eprintln!(
+                "Increased the block size to {} so the output fits into a GIF ({}x{})",
+                block_size, w, h
+            );
+        }
+    }
+
+    if args.subpixel_centers {
+        // Where the first block starts in the input, in pixels of the output
+        let shift = |offset: i32| {
+            let start = grid_start(offset, block_size) as f32 * spacing as f32 / block_size as f32;
+            start - start.round()
+        };
+        options.center_shift = (shift(args.grid_offset.0), shift(args.grid_offset.1));
+    }
+
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
 
-                let avg = if count > 0 { total / count } else { 0 };
-                blocks.push(avg);
+    if !args.no_metadata {
+        let radius = match (options.start_radius, options.end_radius) {
+            (start, end) if start == end => start.to_string(),
+            (start, end) => format!("{start}-{end}"),
+        };
+        options.comment = Some(format!(
+            "pointillist {}: block size {}, radius {}, padding {}, metric {}",
+            env!("CARGO_PKG_VERSION"),
+            block_size,
+            radius,
+            options.padding,
+            metric
+                .to_possible_value()
+                .map_or_else(String::new, |v| v.get_name().to_owned()),
+        ));
+    }
+
+    // What every frame and the rendering take up, for --limit-memory
+    let (frame_bytes, dots_bytes, render_bytes) = {
+        let (width, height) = GifFrameReader::open(&args.in_path)?.screen_size();
+        let (width, height) = match args.scale {
+            Some(factor) => scaled_size(width, height, factor),
+            None => (width, height),
+        };
+        let (grid_w, grid_h) = grid_size(width, height, block_size, args.grid_offset);
+        let (out_w, out_h) =
+            output_dimensions(width, height, block_size, args.grid_offset, &options);
+        let samples = (options.render_scale * options.render_scale) as u64;
+        let canvas_bytes = out_w as u64 * out_h as u64 * samples * size_of::<Rgba>() as u64;
+        let canvas_limit = args.limit_memory.unwrap_or(MAX_CANVAS_BYTES);
+        if canvas_bytes > canvas_limit {
+            return Err(format!(
+                "A frame of {}x{} pixels would need about {} bytes, more than the {} of \
+                 --limit-memory, try a larger --block-size or a smaller --radius",
+                out_w, out_h, canvas_bytes, canvas_limit
+            )
+            .into());
+        }
+        // A canvas for every worker and the previous frame, and the palette indices
+        let canvases = threads as u64 + 1;
+        (
+            width as u64 * height as u64 * size_of::<Rgba>() as u64,
+            (grid_w * grid_h * (size_of::<usize>() + size_of::<(u8, u8, u8)>())) as u64,
+            canvas_bytes * canvases + out_w as u64 * out_h as u64,
+        )
+    };
+    let check_memory = |bytes: u64| match args.limit_memory {
+        Some(limit) if bytes > limit => Err(format!(
+            "The conversion would need about {} bytes, more than --limit-memory {}",
+            bytes, limit
+        )),
+        _ => Ok(()),
+    };
+
+    let depth_map = match (&args.depth_map, &args.stereo_pair) {
+        (Some(path), _) => Some(DepthMap::read(path).map_err(|err| format!("{path}: {err}"))?),
+        (None, Some(path)) => {
+            let first_frame = |path: &str| {
+                let mut reader = GifFrameReader::open(path)?.with_canvas_color(args.canvas_color);
+                reader
+                    .next()
+                    .unwrap_or_else(|| Err(format!("{path} has no frames")))
+            };
+            let (left, right) = (first_frame(&args.in_path)?, first_frame(path)?);
+            if (left.width, left.height) != (right.width, right.height) {
+                return Err("--stereo-pair needs a GIF of the same size as the input".into());
             }
+            let max_disparity = (left.width as usize / 8).max(1);
+            Some(disparity_map(
+                &left,
+                &right,
+                block_size,
+                max_disparity,
+                &key_func,
+            ))
+        }
+        (None, None) => None,
+    };
+
+    let clean_up = |dots: &mut DotFrame| {
+        if let Some(radius) = args.metric_smooth {
+            smooth_values(dots, radius as usize);
+        }
+        if let Some(tiles) = args.adaptive_normalization {
+            equalize_tiles(dots, tiles as usize);
+        }
+        if let Some(threshold) = args.merge_threshold {
+            remove_isolated_dots(dots, threshold, args.merge_neighborhood);
+        }
+        if let Some(min) = args.min_value {
+            dots.buffer
+                .iter_mut()
+                .filter(|v| **v < min)
+                .for_each(|v| *v = 0);
         }
+    };
 
-        // Now we can create a new DotFrame with the blocks
-        // and the width and height of the frame
-        let blocks_w = (frame.width as usize + block_size - 1) / block_size;
-        let blocks_h = (frame.height as usize + block_size - 1) / block_size;
-        let expected_len = blocks_w * blocks_h;
+    let convert = |frame: &GifFrame| {
+        // Analyzing a smaller frame with smaller blocks keeps the grid the same
+        let work_block_size = args
+            .work_resolution
+            .and_then(|n| work_block_size(frame.width, frame.height, block_size, n));
+        let (block_size, grid_offset, scale) = match work_block_size {
+            Some(work_block_size) => {
+                let factor = work_block_size as f32 / block_size as f32;
+                let offset = |v: i32| (v as f32 * factor).round() as i32;
+                let grid_offset = (offset(args.grid_offset.0), offset(args.grid_offset.1));
+                (work_block_size, grid_offset, Some(factor))
+            }
+            None => (block_size, args.grid_offset, args.scale),
+        };
 
-        debug_assert!(
-            blocks.len() == expected_len,
-            "Expected: {}, but got: {}",
-            expected_len,
-            blocks.len()
+        let preprocessed;
+        let frame = if scale.is_some()
+            || args.preprocess_blur.is_some()
+            || args.gamma_rgb.is_some()
+            || args.posterize.is_some()
+            || args.dither_source.is_some()
+        {
+            let mut frame = match scale {
+                Some(factor) => scale_frame(frame, factor, args.scale_filter),
+                None => frame.clone(),
+            };
+            if let Some(sigma) = args.preprocess_blur {
+                blur_frame(&mut frame, sigma);
+            }
+            if let Some(gammas) = args.gamma_rgb {
+                apply_gamma(&mut frame, gammas);
+            }
+            if let Some(levels) = args.posterize {
+                posterize(&mut frame, levels);
+            }
+            if let Some(bits) = args.dither_source {
+                dither(&mut frame, 1 << bits);
+            }
+            preprocessed = frame;
+            &preprocessed
+        } else {
+            frame
+        };
+
+        let mut dots = convert_frame(
+            frame,
+            block_size,
+            grid_offset,
+            reduction,
+            args.color_mode,
+            args.color_space,
+            &key_func,
         );
+        neighborhood_values(frame, &mut dots, metric, block_size, grid_offset, &key_func);
+        if let Some(map) = &depth_map {
+            let invert = args.depth_invert;
+            depth_values(frame, &mut dots, map, block_size, grid_offset, invert);
+        }
+        // The values of the motion metric only exist once all frames are converted
+        if metric != Metric::Motion {
+            clean_up(&mut dots);
+        }
+        dots.delay = args.retime_from_input.then_some(frame.delay);
+        dots
+    };
 
-        dot_frames.push(DotFrame {
-            width: blocks_w as u16,
-            height: blocks_h as u16,
-            buffer: blocks,
-        });
+    let out_path = args.out_path.as_deref().unwrap_or_default();
+    if options.png_bit_depth.is_some() && !is_png_sequence(out_path) {
+        return Err("--output-bitdepth only applies to PNG output".into());
+    }
+    if args.chunk.is_some() && is_png_sequence(out_path) {
+        return Err("--chunk only applies to GIF output".into());
+    }
+    if options.interlaced && is_png_sequence(out_path) {
+        return Err("--interlaced only applies to GIF output".into());
+    }
+    if options.optimize && is_png_sequence(out_path) {
+        return Err("--optimize only applies to GIF output".into());
+    }
+    if args.name_template.is_some() && !is_png_sequence(out_path) {
+        return Err("--name-template only applies to PNG output".into());
     }
+    if args.output_colors_from_gif_palette && is_png_sequence(out_path) {
+        return Err("--output-colors-from-gif-palette only applies to GIF output".into());
+    }
+    let max_frames = if args.first_frame_only {
+        1
+    } else {
+        args.max_frames.map_or(usize::MAX, |n| n as usize)
+    };
 
-    dot_frames
-}
+    let (input_width, input_height, summary, decode_time, convert_time);
+    let (mut decode_times, mut convert_times) = (Vec::new(), Vec::new());
+    let start = Instant::now();
 
-pub fn write_circles_gif(
-    path: &str,
-    frames: &[DotFrame],
-    padding: u32,
-    max_radius: u32,
-    max_value: usize,
-    delay: u16,
-) -> Result<(), Box<dyn std::error::Error>> {
-    assert!(!frames.is_empty(), "Need at least one frame");
-
-    let grid_w = frames[0].width as u32;
-    let grid_h = frames[0].height as u32;
-    let img_w = grid_w * (2 * max_radius + padding) + padding;
-    let img_h = grid_h * (2 * max_radius + padding) + padding;
-
-    let mut image = File::create(path)?;
-
-    // 2) Two-color global palette: black, then white
-    let palette: &[u8] = &[
-        0, 0, 0, // index 0 == black
-        255, 255, 255, // index 1 == white
-    ];
-
-    let mut encoder = Encoder::new(&mut image, img_w as u16, img_h as u16, palette)?;
-    encoder.set_repeat(Repeat::Infinite)?;
-
-    let frame_buf_size = (img_w * img_h) as usize;
-    let mut pixels = vec![0u8; frame_buf_size];
-
-    for df in frames {
-        pixels.fill(2); // Fill with index 2 for transparent pixels
-
-        for row in 0..grid_h {
-            for col in 0..grid_w {
-                let idx = (row * grid_w + col) as usize;
-                let val = df.buffer[idx];
-                let r = (val as f32 / max_value.max(1) as f32) * (max_radius as f32);
-                let r2 = r * r;
-
-                let cx = padding as f32
-                    + (col as f32 * (2.0 * max_radius as f32 + padding as f32))
-                    + max_radius as f32;
-                let cy = padding as f32
-                    + (row as f32 * (2.0 * max_radius as f32 + padding as f32))
-                    + max_radius as f32;
-
-                let x0 = ((cx - r).max(0.0).floor()) as u32;
-                let x1 = ((cx + r).min((img_w - 1) as f32).ceil()) as u32;
-                let y0 = ((cy - r).max(0.0).floor()) as u32;
-                let y1 = ((cy + r).min((img_h - 1) as f32).ceil()) as u32;
-
-                for y in y0..=y1 {
-                    for x in x0..=x1 {
-                        let dx = x as f32 - cx;
-                        let dy = y as f32 - cy;
-                        if dx * dx + dy * dy <= r2 {
-                            let pix_idx = (y * img_w + x) as usize;
-                            pixels[pix_idx] = 1;
+    // The preview only needs the first frame, which is quicker to convert the regular way
+    if args.stream && preview.is_none() {
+        if !args.benchmark_mode && is_png_sequence(out_path) {
+            return Err("Streaming only supports GIF output".into());
+        }
+        if metric == Metric::Motion {
+            return Err(
+                "The motion metric compares consecutive frames, it can't be streamed".into(),
+            );
+        }
+        if options.accumulate > 0.0 {
+            return Err("Trails need the previous frame, they can't be streamed".into());
+        }
+        if args.max_value.is_none() && args.metric_clamp.is_none() && args.band.is_none() {
+            return Err("Streaming needs --max-value, --metric-clamp or --band up front".into());
+        }
+
+        let reader = GifFrameReader::open(&args.in_path)?.with_canvas_color(args.canvas_color);
+        if args.preserve_loop_metadata {
+            options.repeat = reader.repeat();
+        }
+        // The stages overlap, so there are no separate timings for them
+        (input_width, input_height) = reader.screen_size();
+        (decode_time, convert_time) = (None, None);
+
+        // Every loop decodes the input again, so the frames never have to be buffered
+        let mut readers = vec![reader];
+        for _ in 1..args.input_loop {
+            readers.push(GifFrameReader::open(&args.in_path)?.with_canvas_color(args.canvas_color));
+        }
+        let mut frames = readers.into_iter().flat_map(|r| r.take(max_frames));
+        let blend = args.frame_blend as usize;
+        let mut blended = std::iter::from_fn(move || {
+            match frames.by_ref().take(blend).collect::<Result<Vec<_>, _>>() {
+                Ok(mut group) if group.len() <= 1 => group.pop().map(Ok),
+                Ok(group) => Some(Ok(blend_frames(&group))),
+                Err(err) => Some(Err(err)),
+            }
+        });
+        let mut retimer = Retimer::new(args.speed);
+        let frames = std::iter::from_fn(move || {
+            if !args.retime_from_input {
+                return blended.next();
+            }
+            loop {
+                match blended.next() {
+                    Some(Ok(frame)) => {
+                        if let Some(frame) = retimer.retime(frame) {
+                            return Some(Ok(frame));
                         }
                     }
+                    Some(Err(err)) => return Some(Err(err)),
+                    None => return retimer.finish().map(Ok),
                 }
             }
+        })
+        .step_by(frame_step);
+        // Every channel between the stages and every worker holds a frame at most
+        check_memory(3 * threads as u64 * (frame_bytes + dots_bytes) + render_bytes)?;
+
+        summary = if args.benchmark_mode {
+            stream_circles_gif(frames, io::sink(), &convert, &options, threads)?
+        } else {
+            let image = BufWriter::new(File::create(out_path)?);
+            let mut summary = stream_circles_gif(frames, image, &convert, &options, threads)?;
+            summary.paths.push(PathBuf::from(out_path));
+            summary
+        };
+    } else {
+        let decode_start = Instant::now();
+        let mut reader = GifFrameReader::open(&args.in_path)?.with_canvas_color(args.canvas_color);
+        if args.output_colors_from_gif_palette {
+            let colors = reader
+                .global_palette()
+                .ok_or("The input GIF has no global palette to reuse")?;
+            options.source_palette = Some(colors.to_vec());
         }
+        // The decoded frames, their copies for --input-loop and the dots they turn into
+        let per_frame =
+            frame_bytes * (1 + args.input_loop as u64) + dots_bytes * args.input_loop as u64;
+        let timed = std::iter::from_fn(|| {
+            let start = Instant::now();
+            let frame = reader.next()?;
+            decode_times.push(start.elapsed());
+            Some(frame)
+        });
+        let mut decoded = timed
+            .take(max_frames)
+            .enumerate()
+            .map(|(i, frame)| {
+                check_memory(render_bytes + (i as u64 + 1) * per_frame)?;
+                frame
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if args.preserve_loop_metadata {
+            options.repeat = reader.repeat();
+        }
+        (input_width, input_height) = decoded.first().map_or((0, 0), |f| (f.width, f.height));
 
-        let frame = Frame {
-            width: img_w as u16,
-            height: img_h as u16,
-            buffer: Cow::Borrowed(&pixels),
-            delay,
-            transparent: Some(2), // index 2 == for transparent pixels
-            dispose: gif::DisposalMethod::Background,
-            ..Frame::default()
+        if args.trim
+            && let Some(bounds) = content_bounds(&decoded, args.trim_tolerance)
+        {
+            decoded = decoded.iter().map(|f| crop_frame(f, bounds)).collect();
+        }
+        let looped = (0..args.input_loop).flat_map(|_| decoded.iter().cloned());
+        let mut frames: Vec<GifFrame> = if args.frame_blend > 1 {
+            // The last group is averaged over the frames that are left
+            looped
+                .collect::<Vec<_>>()
+                .chunks(args.frame_blend as usize)
+                .map(blend_frames)
+                .step_by(frame_step)
+                .collect()
+        } else {
+            looped.step_by(frame_step).collect()
         };
+        if args.retime_from_input {
+            let mut retimer = Retimer::new(args.speed);
+            frames = frames
+                .into_iter()
+                .filter_map(|f| retimer.retime(f))
+                .collect();
+            frames.extend(retimer.finish());
+        }
+        if frames.is_empty() {
+            return Err("The GIF has no frames".into());
+        }
+        decode_time = Some(decode_start.elapsed());
 
-        encoder.write_frame(&frame)?;
+        let convert_start = Instant::now();
+        let mut dot_frames: Vec<DotFrame> = frames
+            .iter()
+            .map(|frame| {
+                let start = Instant::now();
+                let dots = convert(frame);
+                convert_times.push(start.elapsed());
+                dots
+            })
+            .collect();
+        if metric == Metric::Motion {
+            motion_frames(&mut dot_frames);
+            process_frames(&mut dot_frames, |_, dots| clean_up(dots));
+        }
+        if let Some(count) = args.loop_crossfade {
+            crossfade_loop(&mut dot_frames, count)?;
+        }
+        convert_time = Some(convert_start.elapsed());
+
+        options.max_value = args.max_value.unwrap_or_else(|| {
+            dot_frames
+                .iter()
+                .flat_map(|f| f.buffer.iter())
+                .cloned()
+                .max()
+                .unwrap_or(1)
+        });
+        // Boosted after finding the largest value, so the other frames keep their sizes
+        if let Some(threshold) = args.cut_threshold {
+            boost_cuts(
+                &mut dot_frames,
+                threshold,
+                args.cut_boost,
+                options.max_value,
+            );
+        }
+
+        if let Some(preview) = preview {
+            let layout = Layout::new(&dot_frames[0], &options);
+            let canvas = render_frame(&dot_frames[0], 0, dot_frames.len(), &options);
+            *preview = (layout.width, layout.height, canvas);
+            return Ok(());
+        }
+
+        summary = if args.benchmark_mode {
+            encode_circles_gif(io::sink(), &dot_frames, &options)?
+        } else if is_png_sequence(out_path) {
+            if options.accumulate > 0.0 {
+                return Err("Trails are only supported in GIF output".into());
+            }
+            let existing = if args.resume {
+                ExistingFrames::Skip
+            } else if args.force {
+                ExistingFrames::Overwrite
+            } else {
+                ExistingFrames::Fail
+            };
+            let template = args.name_template.as_ref();
+            write_png_sequence(out_path, &dot_frames, &options, existing, threads, template)?
+        } else if let Some(chunk) = args.chunk {
+            write_gif_chunks(out_path, &dot_frames, &options, chunk as usize)?
+        } else {
+            write_circles_gif(out_path, &dot_frames, &options)?
+        };
     }
 
-    Ok(())
-}
+    if args.benchmark_mode {
+        println!("{}", throughput(summary.frame_times.len(), start.elapsed()));
+    }
 
-#[inline(always)]
-pub fn human_perceived_brightness(r: u8, g: u8, b: u8) -> u8 {
-    (0.299 * (r as f32).powi(2) + 0.587 * (g as f32).powi(2) + 0.114 * (b as f32).powi(2))
-        .sqrt()
-        .round() as u8
-}
+    if args.verify {
+        verify_output(&summary).map_err(|e| format!("Verifying the output failed: {}", e))?;
+    }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+    if let Some(path) = &args.output_palette_file {
+        write_palette_file(path, &summary.palette)?;
+    }
 
-    let frames = extract_gif_frames(&args.in_path)?;
-    let dot_frames = convert_to_dots(frames, args.block_size, |(r, g, b, a)| {
-        if *a < 128 {
-            0 // Make fully transparent pixels have zero brightness
+    if let Some(path) = &args.stats_json {
+        let millis = |t: Duration| t.as_secs_f64() * 1000.0;
+        let stats = Stats {
+            frames: summary.frame_times.len(),
+            input_width,
+            input_height,
+            output_width: summary.layout.width,
+            output_height: summary.layout.height,
+            grid_width: summary.layout.grid_w,
+            grid_height: summary.layout.grid_h,
+            max_value: options.max_value,
+            decode_ms: decode_time.map(millis),
+            convert_ms: convert_time.map(millis),
+            frame_ms: summary.frame_times.iter().copied().map(millis).collect(),
+            output_bytes: summary
+                .paths
+                .iter()
+                .map(|p| std::fs::metadata(p).map(|m| m.len()))
+                .sum::<Result<u64, _>>()?,
+        };
+
+        let json = serde_json::to_string_pretty(&stats)?;
+        if path == "-" {
+            eprintln!("{}", json);
         } else {
-            // Scale brightness by alpha
-            (human_perceived_brightness(*r, *g, *b) as f32 * (*a as f32 / 255.0)) as usize
-        }
-    });
-
-    let max_value = dot_frames
-        .iter()
-        .flat_map(|f| f.buffer.iter())
-        .cloned()
-        .max()
-        .unwrap_or(1);
-
-    write_circles_gif(
-        &args.out_path,
-        &dot_frames,
-        args.padding,
-        args.radius,
-        max_value,
-        args.delay,
-    )?;
+            std::fs::write(path, json)?;
+        }
+    }
+
+    if let Some(path) = &args.profile {
+        let millis = |times: &[Duration]| times.iter().map(|t| t.as_secs_f64() * 1000.0).collect();
+        let encode_times: Vec<Duration> = summary
+            .frame_times
+            .iter()
+            .zip(&summary.render_times)
+            .map(|(&frame, &render)| frame.saturating_sub(render))
+            .collect();
+        let profile = Profile {
+            decode_ms: millis(&decode_times),
+            convert_ms: millis(&convert_times),
+            render_ms: millis(&summary.render_times),
+            encode_ms: millis(&encode_times),
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&profile)?)?;
+    }
+
+    if let Some(path) = &args.emit_manifest {
+        let source = std::fs::read(&args.in_path)?;
+        let manifest = Manifest {
+            format: if is_png_sequence(out_path) {
+                "png-sequence"
+            } else {
+                "gif"
+            },
+            paths: summary.paths.clone(),
+            width: summary.layout.width,
+            height: summary.layout.height,
+            frames: summary.frame_times.len(),
+            palette: summary
+                .palette
+                .chunks_exact(3)
+                .map(|c| format!("{:02x}{:02x}{:02x}", c[0], c[1], c[2]))
+                .collect(),
+            delays: summary.delays.clone(),
+            parameters: ManifestParameters {
+                block_size,
+                start_radius: options.start_radius,
+                end_radius: options.end_radius,
+                padding: options.padding,
+                metric: metric
+                    .to_possible_value()
+                    .map_or_else(String::new, |v| v.get_name().to_owned()),
+                max_value: options.max_value,
+                arguments: std::env::args().collect(),
+            },
+            source: ManifestSource {
+                path: args.in_path.clone(),
+                bytes: source.len() as u64,
+                fnv1a: format!("{:016x}", fnv1a(&source)),
+            },
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&manifest)?)?;
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod testing;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// Writes a GIF with the frames drawn by `pixel(frame, x, y)`, each shown for 10/100 s.
+    pub(crate) fn write_gif(
+        path: &str,
+        (width, height): (u16, u16),
+        frames: u16,
+        pixel: impl Fn(u16, u16, u16) -> Rgba,
+    ) {
+        let mut encoder =
+            gif::Encoder::new(File::create(path).unwrap(), width, height, &[]).unwrap();
+        encoder.set_repeat(Repeat::Infinite).unwrap();
+        for i in 0..frames {
+            let mut rgba: Vec<u8> = (0..height)
+                .flat_map(|y| (0..width).map(move |x| (x, y)))
+                .flat_map(|(x, y)| {
+                    let (r, g, b, a) = pixel(i, x, y);
+                    [r, g, b, a]
+                })
+                .collect();
+            let mut frame = gif::Frame::from_rgba_speed(width, height, &mut rgba, 30);
+            frame.delay = 10;
+            encoder.write_frame(&frame).unwrap();
+        }
+    }
+
+    /// A horizontal gradient from black to white that moves to the right on every frame.
+    pub(crate) fn gradient(i: u16, x: u16, _: u16) -> Rgba {
+        let gray = ((x + 4 * i) * 8) as u8;
+        (gray, gray, gray, 255)
+    }
+
+    fn convert(args: &[&str]) -> Result<(), Box<dyn std::error::Error>> {
+        run(&Args::try_parse_from(["pointillist"].iter().chain(args))?)
+    }
+
+    fn frame_count(path: &str) -> usize {
+        GifFrameReader::open(path).unwrap().count()
+    }
+
+    fn delays(path: &str) -> Vec<u16> {
+        let frames = GifFrameReader::open(path).unwrap();
+        frames.map(|frame| frame.unwrap().delay).collect()
+    }
+
+    /// The number of drawn pixels of every column of dots in the first frame of a GIF with the
+    /// default padding and spacing.
+    fn dot_areas(path: &str, columns: usize) -> Vec<usize> {
+        let frame = GifFrameReader::open(path).unwrap().next().unwrap().unwrap();
+        let mut areas = vec![0; columns];
+        for (i, &(_, _, _, a)) in frame.buffer.iter().enumerate() {
+            let x = i % frame.width as usize;
+            if a > 0 {
+                areas[(x.saturating_sub(2) / 18).min(columns - 1)] += 1;
+            }
+        }
+        areas
+    }
+
+    #[test]
+    fn huge_canvas_fails_cleanly() {
+        let dir = TempDir::new("huge-canvas");
+        let input = dir.join("in.gif");
+        write_gif(&input, (32, 24), 1, gradient);
+
+        let out = dir.join("out.png");
+        let err = convert(&["-i", &input, "-o", &out, "-b", "1", "-r", "2000"]).unwrap_err();
+        assert!(err.to_string().contains("--limit-memory"), "{err}");
+    }
+
+    #[test]
+    fn clamped_output_fits_into_a_gif() {
+        let dir = TempDir::new("clamp");
+        let input = dir.join("in.gif");
+        write_gif(&input, (1600, 1), 1, gradient);
+
+        // 1600 columns of circles 42 pixels apart would be too wide, but 800 columns fit
+        let out = dir.join("out.gif");
+        convert(&[
+            "-i",
+            &input,
+            "-o",
+            &out,
+            "-b",
+            "1",
+            "-r",
+            "20",
+            "--clamp-output-size",
+        ])
+        .unwrap();
+        let size = GifFrameReader::open(&out).unwrap().screen_size();
+        assert_eq!(size, (799 * 42 + 44, 44));
+    }
+
+    #[test]
+    fn depth_invert_reverses_the_dot_sizes() {
+        let dir = TempDir::new("depth-invert");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 4), 1, gradient);
+        let (near, far) = (dir.join("near.gif"), dir.join("far.gif"));
+        let args = ["-i", &input, "-b", "4", "--metric", "depth"];
+        convert(&[&args[..], &["-o", &near]].concat()).unwrap();
+        convert(&[&args[..], &["-o", &far, "--depth-invert"]].concat()).unwrap();
+
+        let near = dot_areas(&near, 4);
+        assert!(near.windows(2).all(|w| w[0] < w[1]), "{near:?}");
+        let mut far = dot_areas(&far, 4);
+        far.reverse();
+        assert!(far.windows(2).all(|w| w[0] < w[1]), "{far:?}");
+    }
+
+    #[test]
+    fn depth_maps_need_the_depth_metric() {
+        let dir = TempDir::new("depth-map-metric");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 4), 1, gradient);
+        let out = dir.join("out.gif");
+        let map = dir.join("depth.png");
+        let args = ["-i", &input, "-o", &out, "--depth-map", &map];
+        let err = convert(&args).unwrap_err();
+        assert!(err.to_string().contains("depth metric"), "{err}");
+    }
+
+    #[test]
+    fn stereo_pairs_at_one_depth_are_flat() {
+        let dir = TempDir::new("stereo-pair");
+        let (left, right) = (dir.join("left.gif"), dir.join("right.gif"));
+        write_gif(&left, (16, 4), 1, gradient);
+        // Everything moved by the same pixel is at the same depth
+        write_gif(&right, (16, 4), 1, |i, x, y| gradient(i, x + 1, y));
+        let out = dir.join("out.gif");
+        let args = ["-i", &left, "-o", &out, "-b", "4", "--metric", "depth"];
+        convert(&[&args[..], &["--stereo", "8", "--stereo-pair", &right]].concat()).unwrap();
+
+        // The red dots of the left eye sit right on the cyan ones of the right eye
+        let frame = GifFrameReader::open(&out).unwrap().next().unwrap().unwrap();
+        assert!(frame.buffer.iter().any(|&(_, _, _, a)| a > 0));
+        assert!(frame.buffer.iter().all(|&(r, g, b, _)| r == g && g == b));
+    }
+
+    #[test]
+    fn palette_file_has_the_colors_of_the_gif() {
+        let dir = TempDir::new("palette-file");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 3, gradient);
+        let (out, palette) = (dir.join("out.gif"), dir.join("out.gpl"));
+        let args = [
+            "-i",
+            &input,
+            "-o",
+            &out,
+            "-b",
+            "4",
+            "--gradient-map",
+            "viridis",
+        ];
+        convert(&[&args[..], &["--output-palette-file", &palette]].concat()).unwrap();
+
+        let mut used = std::collections::BTreeSet::new();
+        for frame in GifFrameReader::open(&out).unwrap() {
+            for (r, g, b, a) in frame.unwrap().buffer {
+                if a > 0 {
+                    used.insert((r, g, b));
+                }
+            }
+        }
+        let text = std::fs::read_to_string(&palette).unwrap();
+        let listed: std::collections::BTreeSet<_> = text
+            .lines()
+            .skip(4)
+            .map(|line| {
+                let channels: Vec<u8> = line
+                    .split_whitespace()
+                    .take(3)
+                    .map(|c| c.parse().unwrap())
+                    .collect();
+                (channels[0], channels[1], channels[2])
+            })
+            .collect();
+        assert!(used.len() > 2);
+        assert_eq!(listed, used);
+    }
+
+    #[test]
+    fn stats_describe_the_conversion() {
+        let dir = TempDir::new("stats-json");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 3, gradient);
+        let (out, stats) = (dir.join("out.gif"), dir.join("stats.json"));
+        convert(&["-i", &input, "-o", &out, "-b", "4", "--stats-json", &stats]).unwrap();
+
+        let stats: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&stats).unwrap()).unwrap();
+        let mut keys: Vec<_> = stats.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "convert_ms",
+                "decode_ms",
+                "frame_ms",
+                "frames",
+                "grid_height",
+                "grid_width",
+                "input_height",
+                "input_width",
+                "max_value",
+                "output_bytes",
+                "output_height",
+                "output_width",
+            ]
+        );
+        assert_eq!(stats["frames"], 3);
+        assert_eq!(stats["frame_ms"].as_array().unwrap().len(), 3);
+        assert_eq!([&stats["input_width"], &stats["input_height"]], [16, 8]);
+        assert_eq!([&stats["grid_width"], &stats["grid_height"]], [4, 2]);
+        assert_eq!(
+            stats["output_bytes"],
+            std::fs::metadata(&out).unwrap().len()
+        );
+        assert!(stats["decode_ms"].is_f64());
+    }
+
+    #[test]
+    fn input_loop_repeats_the_frames() {
+        let dir = TempDir::new("input-loop");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 4, gradient);
+        let out = dir.join("out.gif");
+        convert(&["-i", &input, "-o", &out, "-b", "4", "--input-loop", "3"]).unwrap();
+        assert_eq!(frame_count(&out), 12);
+
+        let streamed = dir.join("streamed.gif");
+        let args = [
+            "-i",
+            &input,
+            "-o",
+            &streamed,
+            "-b",
+            "4",
+            "--input-loop",
+            "3",
+        ];
+        convert(&[&args[..], &["--stream", "--max-value", "255"]].concat()).unwrap();
+        assert_eq!(frame_count(&streamed), 12);
+    }
+
+    #[test]
+    fn benchmark_mode_writes_nothing() {
+        let dir = TempDir::new("benchmark");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 3, gradient);
+        let out = dir.join("out.gif");
+        convert(&["-i", &input, "-o", &out, "--benchmark-mode"]).unwrap();
+        assert!(!Path::new(&out).exists());
+
+        let line = throughput(40, Duration::from_millis(500));
+        assert_eq!(line, "40 frames in 0.500s, 80.00 frames/s");
+    }
+
+    #[test]
+    fn fps_sets_the_delay() {
+        let dir = TempDir::new("fps");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 3, gradient);
+        let out = dir.join("out.gif");
+        convert(&["-i", &input, "-o", &out, "--fps", "20"]).unwrap();
+        assert_eq!(delays(&out), [5, 5, 5]);
+
+        // 30 fps would need a delay of 3.33, the closest is used
+        convert(&["-i", &input, "-o", &out, "--fps", "30"]).unwrap();
+        assert_eq!(delays(&out), [3, 3, 3]);
+    }
+
+    #[test]
+    fn every_metric_gets_a_file() {
+        let dir = TempDir::new("metrics");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 2, gradient);
+        let out = dir.join("out.gif");
+        let args = [
+            "pointillist",
+            "-i",
+            &input,
+            "-o",
+            &out,
+            "--metrics",
+            "lab,chroma",
+        ];
+        run_metrics(&Args::try_parse_from(args).unwrap()).unwrap();
+
+        assert!(!Path::new(&out).exists());
+        assert_eq!(frame_count(&dir.join("out.lab.gif")), 2);
+        assert_eq!(frame_count(&dir.join("out.chroma.gif")), 2);
+    }
+
+    #[test]
+    fn first_frame_only_makes_a_still() {
+        let dir = TempDir::new("first-frame");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 10, gradient);
+        let out = dir.join("out.gif");
+        convert(&["-i", &input, "-o", &out, "--first-frame-only"]).unwrap();
+        assert_eq!(frame_count(&out), 1);
+    }
+
+    #[test]
+    fn verify_passes_on_a_normal_render() {
+        let dir = TempDir::new("verify");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 3, gradient);
+        for out in ["out.gif", "out.png"] {
+            let out = dir.join(out);
+            convert(&["-i", &input, "-o", &out, "-b", "4", "--verify"]).unwrap();
+        }
+        let out = dir.join("chunked.gif");
+        convert(&["-i", &input, "-o", &out, "--chunk", "2", "--verify"]).unwrap();
+    }
+
+    #[test]
+    fn loop_count_is_copied_from_the_input() {
+        let dir = TempDir::new("loop-count");
+        let input = dir.join("in.gif");
+        let mut encoder = gif::Encoder::new(File::create(&input).unwrap(), 4, 4, &[]).unwrap();
+        encoder.set_repeat(Repeat::Finite(3)).unwrap();
+        for gray in [0, 255] {
+            let mut rgba = [gray, gray, gray, 255].repeat(16);
+            encoder
+                .write_frame(&gif::Frame::from_rgba(4, 4, &mut rgba))
+                .unwrap();
+        }
+        drop(encoder);
+
+        let repeat = |path: &str| {
+            let mut reader = GifFrameReader::open(path).unwrap();
+            reader.by_ref().for_each(drop);
+            reader.repeat()
+        };
+        let out = dir.join("out.gif");
+        convert(&["-i", &input, "-o", &out, "--preserve-loop-metadata"]).unwrap();
+        assert_eq!(repeat(&out), Repeat::Finite(3));
+        convert(&["-i", &input, "-o", &out]).unwrap();
+        assert_eq!(repeat(&out), Repeat::Infinite);
+    }
+
+    #[test]
+    fn alpha_metric_follows_the_opacity() {
+        let dir = TempDir::new("alpha");
+        let input = dir.join("in.gif");
+        let palette = [0, 0, 0, 255, 255, 255];
+        let mut encoder =
+            gif::Encoder::new(File::create(&input).unwrap(), 36, 4, &palette).unwrap();
+        for i in 0..8 {
+            // The column of blocks c is white in c of the 8 frames and cleared after each
+            let frame = gif::Frame {
+                width: 36,
+                height: 4,
+                buffer: (0..144)
+                    .map(|p| (p % 36 / 4 > i) as u8)
+                    .collect::<Vec<_>>()
+                    .into(),
+                transparent: Some(0),
+                dispose: gif::DisposalMethod::Background,
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+        drop(encoder);
+        let out = dir.join("out.gif");
+        let args = ["-i", &input, "-o", &out, "-b", "4", "--frame-blend", "8"];
+        convert(&[&args[..], &["--metric", "alpha", "--max-value", "255"]].concat()).unwrap();
+
+        // Blending gives alphas from 0 to 255, and the half transparent ones get dots too
+        let areas = dot_areas(&out, 9);
+        assert_eq!(areas[0], 0);
+        assert!(areas.windows(2).all(|w| w[0] < w[1]), "{areas:?}");
+    }
+
+    #[test]
+    fn min_value_clears_the_small_dots() {
+        let dir = TempDir::new("min-value");
+        let input = dir.join("in.gif");
+        // Blocks with the values 12, 44, 76 and 108
+        write_gif(&input, (16, 4), 1, gradient);
+        let (all, floor) = (dir.join("all.gif"), dir.join("floor.gif"));
+        convert(&["-i", &input, "-o", &all, "-b", "4"]).unwrap();
+        convert(&["-i", &input, "-o", &floor, "-b", "4", "--min-value", "40"]).unwrap();
+
+        let (all, floor) = (dot_areas(&all, 4), dot_areas(&floor, 4));
+        assert!(all[0] > 0);
+        assert_eq!(floor[0], 0);
+        assert_eq!(floor[1..], all[1..]);
+    }
+
+    #[test]
+    fn max_frames_stops_early() {
+        let dir = TempDir::new("max-frames");
+        let input = dir.join("in.gif");
+        write_gif(&input, (8, 4), 50, gradient);
+        let out = dir.join("out.gif");
+        convert(&["-i", &input, "-o", &out, "--max-frames", "5"]).unwrap();
+        assert_eq!(frame_count(&out), 5);
+
+        let args = ["-i", &input, "-o", &out, "--max-frames", "5", "--stream"];
+        convert(&[&args[..], &["--max-value", "255"]].concat()).unwrap();
+        assert_eq!(frame_count(&out), 5);
+    }
+
+    #[test]
+    fn inverting_lookup_table_reverses_the_sizes() {
+        let dir = TempDir::new("metric-file");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 4), 1, gradient);
+        let lut = dir.join("invert.txt");
+        std::fs::write(&lut, "# bright gets small dots\n0 255\n255 0\n").unwrap();
+        let (plain, inverted) = (dir.join("plain.gif"), dir.join("inverted.gif"));
+        convert(&["-i", &input, "-o", &plain, "-b", "4"]).unwrap();
+        convert(&[
+            "-i",
+            &input,
+            "-o",
+            &inverted,
+            "-b",
+            "4",
+            "--metric-file",
+            &lut,
+        ])
+        .unwrap();
+
+        let plain = dot_areas(&plain, 4);
+        assert!(plain.windows(2).all(|w| w[0] < w[1]), "{plain:?}");
+        let inverted = dot_areas(&inverted, 4);
+        assert!(inverted.windows(2).all(|w| w[0] > w[1]), "{inverted:?}");
+    }
+
+    #[test]
+    fn memory_limit_fails_before_writing() {
+        let dir = TempDir::new("limit-memory");
+        let input = dir.join("in.gif");
+        write_gif(&input, (32, 24), 10, gradient);
+        let out = dir.join("out.gif");
+        for stream in [false, true] {
+            let mut args = vec!["-i", &input, "-o", &out, "--limit-memory", "30000"];
+            if stream {
+                args.extend(["--stream", "--max-value", "255"]);
+            }
+            let err = convert(&args).unwrap_err();
+            assert!(err.to_string().contains("would need about"), "{err}");
+            assert!(!std::path::Path::new(&out).exists());
+        }
+        convert(&["-i", &input, "-o", &out, "--limit-memory", "100000000"]).unwrap();
+    }
+
+    #[test]
+    fn comment_has_the_parameters() {
+        let dir = TempDir::new("metadata");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 2, gradient);
+        let (out, bare) = (dir.join("out.gif"), dir.join("bare.gif"));
+        let args = [
+            "-i", &input, "-b", "4", "-r", "6", "-p", "3", "--metric", "chroma",
+        ];
+        convert(&[&args[..], &["-o", &out]].concat()).unwrap();
+        convert(&[&args[..], &["-o", &bare, "--no-metadata"]].concat()).unwrap();
+
+        // The comment fits into a single sub-block, so its text is in the file as it is
+        let comment = format!(
+            "pointillist {}: block size 4, radius 6, padding 3, metric chroma",
+            env!("CARGO_PKG_VERSION")
+        );
+        let contains = |path: &str| {
+            let bytes = std::fs::read(path).unwrap();
+            bytes
+                .windows(comment.len())
+                .any(|w| w == comment.as_bytes())
+        };
+        assert!(contains(&out));
+        assert!(!contains(&bare));
+    }
+
+    #[test]
+    fn speed_scales_the_duration() {
+        let dir = TempDir::new("speed");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 10, gradient);
+        let duration = |speed: &str| {
+            let out = dir.join(&format!("speed{speed}.gif"));
+            convert(&["-i", &input, "-o", &out, "--delay", "10", "--speed", speed]).unwrap();
+            delays(&out).iter().map(|&d| d as u32).sum::<u32>()
+        };
+        assert_eq!(duration("1"), 100);
+        assert_eq!(duration("2"), 50);
+        assert_eq!(duration("0.5"), 200);
+        // Too short delays drop every other frame instead
+        assert_eq!(speed_up(10, 8.0), (3, 2));
+    }
+
+    #[test]
+    fn work_resolution_keeps_the_grid() {
+        let dir = TempDir::new("work-resolution");
+        let input = dir.join("in.gif");
+        write_gif(&input, (32, 4), 1, gradient);
+        let render = |work: Option<&str>| {
+            let out = dir.join(&format!("work{}.gif", work.unwrap_or("full")));
+            let mut args = vec!["-i", &input, "-o", &out, "-b", "8"];
+            if let Some(n) = work {
+                args.extend(["--work-resolution", n]);
+            }
+            convert(&args).unwrap();
+            (
+                GifFrameReader::open(&out).unwrap().screen_size(),
+                dot_areas(&out, 4),
+            )
+        };
+        let (full_size, full) = render(None);
+        // A row of 4 circles of radius 8, 18 pixels apart, with a padding of 2
+        assert_eq!(full_size, (3 * 18 + 20, 20));
+        for work in ["16", "8"] {
+            let (size, areas) = render(Some(work));
+            assert_eq!(size, full_size);
+            assert!(areas.windows(2).all(|w| w[0] < w[1]), "{areas:?}");
+            // The gradient is smooth, so fewer pixels average to about the same
+            for (a, b) in areas.iter().zip(&full) {
+                assert!(a.abs_diff(*b) * 5 <= *b, "{areas:?} {full:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn retiming_halves_every_input_delay() {
+        let dir = TempDir::new("retime");
+        let input = dir.join("in.gif");
+        let mut encoder = gif::Encoder::new(
+            File::create(&input).unwrap(),
+            8,
+            8,
+            &[0, 0, 0, 255, 255, 255],
+        )
+        .unwrap();
+        for (i, delay) in [10, 20, 40, 6].into_iter().enumerate() {
+            let frame = gif::Frame {
+                width: 8,
+                height: 8,
+                buffer: (0..64)
+                    .map(|p| (p % 4 == i) as u8)
+                    .collect::<Vec<_>>()
+                    .into(),
+                delay,
+                ..gif::Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+        drop(encoder);
+
+        let out = dir.join("out.gif");
+        convert(&[
+            "-i",
+            &input,
+            "-o",
+            &out,
+            "--retime-from-input",
+            "--speed",
+            "2",
+        ])
+        .unwrap();
+        assert_eq!(delays(&out), [5, 10, 20, 3]);
+    }
+
+    #[test]
+    fn retiming_keeps_a_frame_of_short_inputs() {
+        let dir = TempDir::new("retime-short");
+        let input = dir.join("in.gif");
+        write_gif(&input, (8, 8), 1, gradient);
+        let out = dir.join("out.gif");
+        let args = [
+            "-i",
+            &input,
+            "-o",
+            &out,
+            "--retime-from-input",
+            "--speed",
+            "20",
+        ];
+        convert(&args).unwrap();
+        assert_eq!(delays(&out), [2]);
+
+        convert(&[&args[..], &["--stream", "--max-value", "255"]].concat()).unwrap();
+        assert_eq!(delays(&out), [2]);
+    }
+
+    #[test]
+    fn manifest_describes_the_output() {
+        let dir = TempDir::new("manifest");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 3, gradient);
+        let (out, manifest) = (dir.join("out.gif"), dir.join("manifest.json"));
+        convert(&[
+            "-i",
+            &input,
+            "-o",
+            &out,
+            "-b",
+            "4",
+            "-r",
+            "6",
+            "--emit-manifest",
+            &manifest,
+        ])
+        .unwrap();
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&manifest).unwrap()).unwrap();
+        assert_eq!(manifest["format"], "gif");
+        assert_eq!(manifest["paths"], serde_json::json!([out]));
+        // 4x2 circles of radius 6, 14 pixels apart, with a padding of 2
+        assert_eq!(
+            [&manifest["width"], &manifest["height"]],
+            [3 * 14 + 16, 14 + 16]
+        );
+        assert_eq!(manifest["frames"], 3);
+        assert_eq!(manifest["delays"], serde_json::json!([5, 5, 5]));
+
+        let colors = manifest["palette"].as_array().unwrap();
+        let screen = GifFrameReader::open(&out).unwrap();
+        let palette = screen.global_palette().unwrap();
+        // The GIF has the transparent index on top of the colors
+        assert_eq!(colors.len(), palette.len() / 3 - 1);
+        assert_eq!(
+            colors[0],
+            format!("{:02x}{:02x}{:02x}", palette[0], palette[1], palette[2])
+        );
+
+        let parameters = &manifest["parameters"];
+        assert_eq!(parameters["block_size"], 4);
+        assert_eq!(
+            [&parameters["start_radius"], &parameters["end_radius"]],
+            [6, 6]
+        );
+        assert_eq!(parameters["metric"], "brightness");
+        let source = std::fs::read(&input).unwrap();
+        assert_eq!(manifest["source"]["bytes"], source.len());
+        assert_eq!(
+            manifest["source"]["fnv1a"],
+            format!("{:016x}", fnv1a(&source))
+        );
+    }
+
+    #[test]
+    fn grid_density_sets_the_columns() {
+        let dir = TempDir::new("grid-density");
+        let input = dir.join("in.gif");
+        write_gif(&input, (200, 30), 1, gradient);
+        let (out, stats) = (dir.join("out.gif"), dir.join("stats.json"));
+        convert(&[
+            "-i",
+            &input,
+            "-o",
+            &out,
+            "--grid-density",
+            "20",
+            "--stats-json",
+            &stats,
+        ])
+        .unwrap();
+
+        // Blocks of 10x10 pixels
+        let stats: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&stats).unwrap()).unwrap();
+        assert_eq!([&stats["grid_width"], &stats["grid_height"]], [20, 3]);
+        let err = convert(&["-i", &input, "-o", &out, "--grid-density", "201"]).unwrap_err();
+        assert!(err.to_string().contains("--grid-density"), "{err}");
+    }
+
+    #[test]
+    fn profile_times_every_frame_of_every_stage() {
+        let dir = TempDir::new("profile");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 4, gradient);
+        let (out, profile) = (dir.join("out.gif"), dir.join("profile.json"));
+        convert(&["-i", &input, "-o", &out, "--profile", &profile]).unwrap();
+
+        let profile: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&profile).unwrap()).unwrap();
+        for stage in ["decode_ms", "convert_ms", "render_ms", "encode_ms"] {
+            let times = profile[stage].as_array().unwrap();
+            assert_eq!(times.len(), 4, "{stage}");
+            assert!(times.iter().all(|t| t.as_f64().unwrap() >= 0.0));
+        }
+    }
+
+    #[test]
+    fn output_colors_come_from_the_source_palette() {
+        let dir = TempDir::new("source-palette");
+        let input = dir.join("in.gif");
+        // Four colors in the global palette, the pixels mix them in every block
+        let palette = [250, 10, 10, 10, 200, 30, 20, 40, 220, 240, 240, 90];
+        let mut encoder =
+            gif::Encoder::new(File::create(&input).unwrap(), 16, 8, &palette).unwrap();
+        let frame = gif::Frame {
+            width: 16,
+            height: 8,
+            buffer: (0..128u32)
+                .map(|p| ((p * 7 + p / 16) % 4) as u8)
+                .collect::<Vec<_>>()
+                .into(),
+            ..gif::Frame::default()
+        };
+        encoder.write_frame(&frame).unwrap();
+        drop(encoder);
+
+        let out = dir.join("out.gif");
+        let args = [
+            "--dot-color-from",
+            "original",
+            "--output-colors-from-gif-palette",
+        ];
+        convert(&[&["-i", &input, "-o", &out, "-b", "4"], &args[..]].concat()).unwrap();
+
+        let colors: Vec<&[u8]> = palette.chunks(3).collect();
+        let frame = GifFrameReader::open(&out).unwrap().next().unwrap().unwrap();
+        let drawn: Vec<_> = frame.buffer.iter().filter(|p| p.3 > 0).collect();
+        assert!(!drawn.is_empty());
+        for &&(r, g, b, _) in &drawn {
+            assert!(colors.contains(&&[r, g, b][..]), "{r} {g} {b}");
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! The indexed colors of the output, and palette files for other programs.
+
+use std::{collections::HashMap, path::Path};
+
+use color_quant::NeuQuant;
+
+use crate::color::{Rgba, is_opaque};
+
+/// Maximum number of colors in the palette, one index is reserved for transparent pixels.
+pub const MAX_PALETTE_COLORS: usize = 255;
+
+/// A global palette together with the index of every color that gets drawn.
+pub struct Palette {
+    /// The RGB triples of the palette
+    pub colors: Vec<u8>,
+    lookup: HashMap<(u8, u8, u8), u8>,
+}
+
+impl Palette {
+    /// Builds a palette from a histogram of the drawn colors, if there are too many of them
+    /// they are quantized into [`MAX_PALETTE_COLORS`] colors weighted by how often they occur.
+    pub fn from_histogram(histogram: &HashMap<(u8, u8, u8), usize>) -> Palette {
+        Palette::with_max_colors(histogram, MAX_PALETTE_COLORS)
+    }
+
+    /// Maps every color of the histogram to its closest entry of a given palette of RGB
+    /// triples, which loses its last color if it has no room for the transparent index.
+    pub fn from_colors(histogram: &HashMap<(u8, u8, u8), usize>, colors: &[u8]) -> Palette {
+        let mut palette = Palette {
+            colors: colors[..colors.len().min(MAX_PALETTE_COLORS * 3)].to_vec(),
+            lookup: HashMap::new(),
+        };
+        palette.lookup = histogram
+            .keys()
+            .map(|&(r, g, b)| ((r, g, b), palette.closest_index([r, g, b])))
+            .collect();
+        palette
+    }
+
+    /// Like [`Palette::from_histogram`], but with at most `max_colors` colors.
+    pub fn with_max_colors(histogram: &HashMap<(u8, u8, u8), usize>, max_colors: usize) -> Palette {
+        // Sort the colors so the palette doesn't depend on the iteration order of the map
+        let mut unique: Vec<_> = histogram.iter().map(|(&c, &n)| (c, n)).collect();
+        unique.sort_unstable();
+
+        if unique.len() <= max_colors {
+            let colors = unique
+                .iter()
+                .flat_map(|&((r, g, b), _)| [r, g, b])
+                .collect();
+            let lookup = unique
+                .iter()
+                .enumerate()
+                .map(|(i, &(c, _))| (c, i as u8))
+                .collect();
+            return Palette { colors, lookup };
+        }
+
+        // Too many colors, let NeuQuant learn the palette from a sample that keeps the
+        // relative frequencies of the colors and map every color to its closest entry
+        let total: usize = unique.iter().map(|&(_, n)| n).sum();
+        let mut sample = Vec::new();
+        for &((r, g, b), n) in &unique {
+            let repeat = (n * QUANTIZER_SAMPLES / total).max(1);
+            for _ in 0..repeat {
+                sample.extend_from_slice(&[r, g, b, 255]);
+            }
+        }
+
+        let quantizer = NeuQuant::new(10, max_colors, &sample);
+        let lookup = unique
+            .iter()
+            .map(|&((r, g, b), _)| ((r, g, b), quantizer.index_of(&[r, g, b, 255]) as u8))
+            .collect();
+
+        Palette {
+            colors: quantizer.color_map_rgb(),
+            lookup,
+        }
+    }
+
+    /// Returns the palette index of an opaque pixel or the transparent index.
+    pub fn index_of(&self, pixel: Rgba) -> u8 {
+        let (r, g, b, _) = pixel;
+        if is_opaque(pixel) {
+            self.lookup[&(r, g, b)]
+        } else {
+            self.transparent_index()
+        }
+    }
+
+    /// Maps a canvas of `width` pixels per row to palette indices, diffusing `strength` of the
+    /// error of every opaque pixel to its opaque neighbors like [`dither`](crate::input::dither) does.
+    ///
+    /// Without diffusion every color is in the palette lookup, with it the colors shift and
+    /// get the closest entry instead.
+    pub fn write_indices(&self, canvas: &[Rgba], width: u32, strength: f32, indices: &mut [u8]) {
+        if strength <= 0.0 {
+            for (index, &pixel) in indices.iter_mut().zip(canvas) {
+                *index = self.index_of(pixel);
+            }
+            return;
+        }
+
+        let width = width as usize;
+        let height = canvas.len() / width;
+        let mut error = vec![[0.0f32; 3]; canvas.len()];
+        let mut closest = HashMap::new();
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                if !is_opaque(canvas[i]) {
+                    indices[i] = self.transparent_index();
+                    continue;
+                }
+
+                let (r, g, b, _) = canvas[i];
+                let wanted: [f32; 3] =
+                    std::array::from_fn(|c| ([r, g, b][c] as f32 + error[i][c]).clamp(0.0, 255.0));
+                let color = wanted.map(|c| c.round() as u8);
+                let index = *closest
+                    .entry(color)
+                    .or_insert_with(|| self.closest_index(color));
+                indices[i] = index;
+
+                let entry = &self.colors[index as usize * 3..index as usize * 3 + 3];
+                let residual: [f32; 3] =
+                    std::array::from_fn(|c| (wanted[c] - entry[c] as f32) * strength);
+                let neighbors = [(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)];
+                for (dx, dy, weight) in neighbors {
+                    let (nx, ny) = (x as isize + dx, y + dy);
+                    if nx < 0 || nx as usize >= width || ny >= height {
+                        continue;
+                    }
+                    let n = ny * width + nx as usize;
+                    if is_opaque(canvas[n]) {
+                        for c in 0..3 {
+                            error[n][c] += residual[c] * weight / 16.0;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The index of the palette color with the smallest squared distance to `color`.
+    fn closest_index(&self, [r, g, b]: [u8; 3]) -> u8 {
+        let distance = |entry: &[u8]| {
+            let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+            d(entry[0], r) + d(entry[1], g) + d(entry[2], b)
+        };
+        self.colors
+            .chunks_exact(3)
+            .enumerate()
+            .min_by_key(|(_, entry)| distance(entry))
+            .map_or(0, |(i, _)| i as u8)
+    }
+
+    /// The index right after the last color is used for transparent pixels.
+    pub fn transparent_index(&self) -> u8 {
+        (self.colors.len() / 3) as u8
+    }
+}
+
+/// Adds the opaque pixels of a rendered frame to a color histogram.
+pub fn count_colors(histogram: &mut HashMap<(u8, u8, u8), usize>, canvas: &[Rgba]) {
+    for &pixel in canvas {
+        if is_opaque(pixel) {
+            let (r, g, b, _) = pixel;
+            *histogram.entry((r, g, b)).or_insert(0) += 1;
+        }
+    }
+}
+
+/// Roughly how many pixels the quantizer learns the palette from.
+const QUANTIZER_SAMPLES: usize = 1 << 16;
+
+/// Writes an RGB palette to `path`, picking the format from the file extension.
+///
+/// Supported are GIMP palettes (`.gpl`) and Adobe Color Tables (`.act`).
+pub fn write_palette_file<P: AsRef<Path>>(
+    path: P,
+    palette: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = path.as_ref();
+    let colors = palette.chunks_exact(3);
+
+    let bytes = match path.extension().and_then(|e| e.to_str()) {
+        Some("gpl") => {
+            let mut text = String::from("GIMP Palette\nName: Pointillist\nColumns: 16\n#\n");
+            for (i, c) in colors.enumerate() {
+                text += &format!("{:3} {:3} {:3}\tIndex {}\n", c[0], c[1], c[2], i);
+            }
+            text.into_bytes()
+        }
+        Some("act") => {
+            // 256 RGB entries, followed by the number of used colors and the transparent index
+            let len = palette.len().min(256 * 3);
+            let mut bytes = vec![0u8; 256 * 3];
+            bytes[..len].copy_from_slice(&palette[..len]);
+            bytes.extend_from_slice(&(colors.len().min(256) as u16).to_be_bytes());
+            bytes.extend_from_slice(&u16::MAX.to_be_bytes());
+            bytes
+        }
+        _ => {
+            return Err(format!(
+                "Unsupported palette file format: {} (expected .gpl or .act)",
+                path.display()
+            )
+            .into());
+        }
+    };
+
+    std::fs::write(path, bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dither_strength_spreads_more_of_the_error() {
+        // A horizontal gradient mapped to black and white
+        let (width, height) = (64, 16);
+        let canvas: Vec<Rgba> = (0..width * height)
+            .map(|i| {
+                let v = (i % width * 4) as u8;
+                (v, v, v, 255)
+            })
+            .collect();
+        let mut histogram = HashMap::new();
+        for &(r, g, b, _) in &canvas {
+            *histogram.entry((r, g, b)).or_insert(0) += 1;
+        }
+        let palette = Palette::from_colors(&histogram, &[0, 0, 0, 255, 255, 255]);
+        let indices = |strength| {
+            let mut indices = vec![0; canvas.len()];
+            palette.write_indices(&canvas, width as u32, strength, &mut indices);
+            indices
+        };
+        // How far the mean of every 8x8 tile is off from the gradient
+        let error = |indices: &[u8]| {
+            let mut error = 0;
+            for (tx, ty) in (0..width / 8).flat_map(|tx| (0..height / 8).map(move |ty| (tx, ty))) {
+                let (mut wanted, mut got) = (0, 0);
+                for i in (0..64).map(|j| (ty * 8 + j / 8) * width + tx * 8 + j % 8) {
+                    wanted += canvas[i].0 as i32;
+                    got += indices[i] as i32 * 255;
+                }
+                error += (wanted - got).abs() / 64;
+            }
+            error
+        };
+
+        let plain: Vec<u8> = canvas.iter().map(|&p| palette.index_of(p)).collect();
+        assert_eq!(indices(0.0), plain);
+        let (none, half, full) = (
+            error(&indices(0.0)),
+            error(&indices(0.5)),
+            error(&indices(1.0)),
+        );
+        assert!(full < half && half < none, "{none} {half} {full}");
+    }
+}
@@ -0,0 +1,1836 @@
+//! Drawing dot frames as circles or fields into RGBA images.
+
+use std::sync::{Arc, OnceLock};
+
+use gif::Repeat;
+use image::{
+    RgbaImage,
+    imageops::{self, FilterType},
+};
+
+use crate::{
+    color::{Rgb, Rgba, TRANSPARENT, linear_to_srgb, parse_color, rotate_hue, srgb_to_linear},
+    convert::{ColorSpace, grid_size},
+    dots::DotFrame,
+    timing::TimingCurve,
+};
+
+/// Linearly interpolates the maximum radius for the frame at `index` out of `count` frames,
+/// going from `start_radius` on the first frame to `end_radius` on the last one.
+pub fn frame_radius(index: usize, count: usize, start_radius: u32, end_radius: u32) -> f32 {
+    let t = if count > 1 {
+        index as f32 / (count - 1) as f32
+    } else {
+        0.0
+    };
+    start_radius as f32 + (end_radius as f32 - start_radius as f32) * t
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Response {
+    /// The radius grows with the value
+    Linear,
+    /// Logarithmic, low values already get big circles
+    Log,
+    /// Square root, low values get bigger circles but less so than with log
+    Sqrt,
+    /// The value to the power of --response-exp
+    Pow,
+}
+
+impl Response {
+    /// Maps a value relative to the maximum (0.0..=1.0) to the relative radius (0.0..=1.0).
+    fn apply(self, level: f32, exponent: f32) -> f32 {
+        match self {
+            Response::Linear => level,
+            Response::Log => (1.0 + 9.0 * level).log10(),
+            Response::Sqrt => level.sqrt(),
+            Response::Pow => level.powf(exponent),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum ColorSource {
+    /// The size of the circle mapped through a gradient, grayscale by default
+    Size,
+    /// The average color of the block
+    Original,
+    /// The same color for every circle
+    Fixed,
+}
+
+/// A color gradient, sampled by linearly interpolating between its stops.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gradient {
+    /// Positions in 0.0..=1.0 with their color, sorted by position
+    stops: Vec<(f32, (u8, u8, u8))>,
+}
+
+impl Gradient {
+    /// Creates a gradient with evenly spaced stops.
+    pub fn even(colors: &[(u8, u8, u8)]) -> Gradient {
+        let last = colors.len().saturating_sub(1).max(1) as f32;
+        Gradient {
+            stops: colors
+                .iter()
+                .enumerate()
+                .map(|(i, &c)| (i as f32 / last, c))
+                .collect(),
+        }
+    }
+
+    pub fn grayscale() -> Gradient {
+        Gradient::even(&[(0, 0, 0), (255, 255, 255)])
+    }
+
+    /// Looks up one of the built-in gradients.
+    pub fn preset(name: &str) -> Option<Gradient> {
+        let hex = |colors: &[&str]| {
+            let colors: Vec<_> = colors.iter().map(|c| parse_color(c).unwrap()).collect();
+            Gradient::even(&colors)
+        };
+
+        match name {
+            "grayscale" => Some(Gradient::grayscale()),
+            "viridis" => Some(hex(&[
+                "440154", "482878", "3e4989", "31688e", "26828e", "1f9e89", "35b779", "6ece58",
+                "b5de2b", "fde725",
+            ])),
+            "magma" => Some(hex(&[
+                "000004", "180f3d", "440f76", "721f81", "9e2f7f", "cd4071", "f1605d", "fd9668",
+                "feca8d", "fcfdbf",
+            ])),
+            _ => None,
+        }
+    }
+
+    /// Parses a list of stops, one per line as `RRGGBB` or `POSITION RRGGBB`.
+    /// Stops without a position are spread evenly over the gradient.
+    pub fn parse(text: &str) -> Result<Gradient, String> {
+        let lines: Vec<&str> = text
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect();
+        if lines.is_empty() {
+            return Err("A gradient needs at least one stop".to_string());
+        }
+
+        let last = (lines.len() - 1).max(1) as f32;
+        let mut stops = Vec::with_capacity(lines.len());
+        for (i, line) in lines.iter().enumerate() {
+            let stop = match line.split_once(char::is_whitespace) {
+                Some((position, color)) => {
+                    let position: f32 = position
+                        .parse()
+                        .map_err(|_| format!("Invalid gradient stop position: {}", position))?;
+                    (position.clamp(0.0, 1.0), parse_color(color.trim())?)
+                }
+                None => (i as f32 / last, parse_color(line)?),
+            };
+            stops.push(stop);
+        }
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        Ok(Gradient { stops })
+    }
+
+    /// Accepts either the name of a preset or the path to a file with stops.
+    pub fn from_arg(arg: &str) -> Result<Gradient, String> {
+        if let Some(gradient) = Gradient::preset(arg) {
+            return Ok(gradient);
+        }
+        let text = std::fs::read_to_string(arg)
+            .map_err(|e| format!("Failed to read gradient file: {}", e))?;
+        Gradient::parse(&text)
+    }
+
+    /// Samples the gradient at `t` in 0.0..=1.0.
+    pub fn sample(&self, t: f32) -> (u8, u8, u8) {
+        let t = t.clamp(0.0, 1.0);
+        let after = self.stops.partition_point(|&(position, _)| position < t);
+        if after == 0 {
+            return self.stops[0].1;
+        }
+        if after == self.stops.len() {
+            return self.stops[after - 1].1;
+        }
+
+        let (p0, c0) = self.stops[after - 1];
+        let (p1, c1) = self.stops[after];
+        let f = if p1 > p0 { (t - p0) / (p1 - p0) } else { 0.0 };
+        let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * f).round() as u8;
+        (lerp(c0.0, c1.0), lerp(c0.1, c1.1), lerp(c0.2, c1.2))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Tonemap {
+    /// Keep the colors as they are
+    None,
+    /// Compress the highlights with `c / (1 + c)` per channel in linear light
+    Reinhard,
+}
+
+impl Tonemap {
+    pub fn apply(self, (r, g, b): (u8, u8, u8)) -> (u8, u8, u8) {
+        match self {
+            Tonemap::None => (r, g, b),
+            Tonemap::Reinhard => {
+                let map = |c: u8| {
+                    let linear = srgb_to_linear(c);
+                    linear_to_srgb(linear / (1.0 + linear))
+                };
+                (map(r), map(g), map(b))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Style {
+    /// A circle for every block
+    Dots,
+    /// A smooth field, bilinearly interpolated between the blocks
+    Field,
+    /// A full sized circle for every block that reaches --binary-threshold and nothing for
+    /// the others, like a 1-bit halftone
+    Binary,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum CellShape {
+    /// The dots are drawn whole
+    None,
+    /// The square cell of the grid
+    Square,
+    /// A hexagon with its corners on the top and bottom edge of the cell
+    Hex,
+}
+
+impl CellShape {
+    /// Whether the offset `(dx, dy)` from the center of a cell with sides `spacing` long is
+    /// inside of the shape.
+    fn contains(self, dx: f32, dy: f32, spacing: f32) -> bool {
+        let half = spacing / 2.0;
+        let (dx, dy) = (dx.abs(), dy.abs());
+        match self {
+            CellShape::None => true,
+            CellShape::Square => dx <= half && dy <= half,
+            CellShape::Hex => dx <= half * 3f32.sqrt() / 2.0 && dy + dx / 3f32.sqrt() <= half,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SquareMode {
+    /// Pad the shorter side
+    Pad,
+    /// Crop the longer side
+    Crop,
+}
+
+/// Parameters that control how the dot frames are drawn into the output GIF.
+#[derive(Clone)]
+pub struct RenderOptions {
+    /// Padding between the outermost circles and the image border
+    pub padding: u32,
+    /// Maximum radius of the circles on the first frame
+    pub start_radius: u32,
+    /// Maximum radius of the circles on the last frame
+    pub end_radius: u32,
+    /// Distance between the centers of two neighboring circles
+    pub spacing: u32,
+    /// The dot value that gets drawn with the maximum radius
+    pub max_value: usize,
+    /// The range of values that is mapped to the radius instead of `0..=max_value`
+    pub value_window: Option<(usize, usize)>,
+    /// Values above the window get no dot instead of the largest one
+    pub band_pass: bool,
+    /// How the values relative to `max_value` are mapped to the radius
+    pub response: Response,
+    /// Exponent of [`Response::Pow`]
+    pub response_exp: f32,
+    /// Average delay of the frames in the output GIF
+    pub delay: u16,
+    /// How often the output GIF plays, animations only
+    pub repeat: Repeat,
+    /// How the delays are distributed over the frames
+    pub timing_curve: TimingCurve,
+    /// Where the colors of the circles come from
+    pub dot_color_from: ColorSource,
+    /// Color of the circles with [`ColorSource::Fixed`]
+    pub dot_color: (u8, u8, u8),
+    /// Colors of the circles with a positive and a negative value, instead of the color source
+    pub signed_colors: Option<(Rgb, Rgb)>,
+    /// Gradient for [`ColorSource::Size`]
+    pub gradient: Gradient,
+    /// Tone mapping applied to the colors of the circles
+    pub tonemap: Tonemap,
+    /// Invert the colors of the circles channel by channel, before the tone mapping
+    pub invert: bool,
+    /// Degrees to rotate the hue of the circles by, after the inversion
+    pub hue_rotate: f32,
+    /// How the dot values are drawn
+    pub style: Style,
+    /// What the dots are cut off at
+    pub cell_shape: CellShape,
+    /// How far apart the dots of the two eyes of an anaglyph are at most, in pixels
+    pub stereo: Option<f32>,
+    /// How many times cells get merged into squares of twice the size, see [`adaptive_cells`]
+    pub adaptive_blocks: Option<u32>,
+    /// How far the levels (0.0..=1.0) of merged cells may differ
+    pub adaptive_tolerance: f32,
+    /// Samples per pixel along each axis when drawing the edges of the circles
+    pub antialias_samples: u32,
+    /// Width divided by height of the dots, the longer axis gets the radius
+    pub dot_aspect: f32,
+    /// How much of what's below a dot it covers, 1.0 is opaque
+    pub dot_opacity: f32,
+    /// Fraction of a pixel (-0.5..=0.5) that every dot center is moved by
+    pub center_shift: (f32, f32),
+    /// Superellipse exponents of the dots at the lowest and highest level, ellipses if not set
+    pub shape_exponents: Option<(f32, f32)>,
+    /// Where the colors of the field are mixed
+    pub color_space: ColorSpace,
+    /// How many times larger the image gets rendered before shrinking it to its size
+    pub render_scale: u32,
+    /// How much of the previous frame stays visible under the next one, 0.0 clears it
+    pub accumulate: f32,
+    /// With [`Style::Field`], only draw where the field reaches this value (0.0..=1.0)
+    pub field_threshold: Option<f32>,
+    /// With [`Style::Binary`], the level (0.0..=1.0) a block needs to get a dot
+    pub binary_threshold: f32,
+    /// Make the image square by padding or cropping it
+    pub square: Option<SquareMode>,
+    /// Color of the padding added by [`SquareMode::Pad`], transparent if not set
+    pub square_color: Option<(u8, u8, u8)>,
+    /// Color of the lines drawn between the cells of the grid
+    pub grid_lines: Option<(u8, u8, u8)>,
+    /// Solid frame drawn around the whole image
+    pub border: Option<Border>,
+    /// Give every frame of a GIF a palette of its own instead of sharing a global one
+    pub local_palettes: bool,
+    /// Only encode what changed between the frames of a GIF, see [`FrameDiff`](crate::encode::FrameDiff)
+    pub optimize: bool,
+    /// Make the transparent pixels of a GIF black instead
+    pub no_transparent: bool,
+    /// Store the rows of the GIF frames in interlaced order, see [`interlace`](crate::encode::interlace)
+    pub interlaced: bool,
+    /// How much of the error of mapping to the GIF palette is diffused (0.0..=1.0)
+    pub palette_dither: f32,
+    /// RGB triples to use as the global GIF palette instead of picking the colors
+    pub source_palette: Option<Vec<u8>>,
+    /// Write indexed PNGs with this many bits per pixel instead of RGBA ones
+    pub png_bit_depth: Option<u8>,
+    /// Written into a comment extension at the start of a GIF
+    pub comment: Option<String>,
+    /// Image the frames are drawn over
+    pub background: Option<Arc<Background>>,
+    /// Color of the cells behind their circles
+    pub cell_background: Option<(u8, u8, u8)>,
+    /// Clear the cells whose blocks are fully transparent in the input
+    pub transparency_from_source: bool,
+}
+
+impl Default for RenderOptions {
+    /// The options of the pointillist binary without any arguments, circles with a radius of 8
+    /// and a padding of 2, before `max_value` is set to the largest dot value.
+    fn default() -> RenderOptions {
+        RenderOptions {
+            padding: 2,
+            start_radius: 8,
+            end_radius: 8,
+            spacing: 18,
+            max_value: 1,
+            value_window: None,
+            band_pass: false,
+            response: Response::Linear,
+            response_exp: 2.0,
+            delay: 5,
+            repeat: Repeat::Infinite,
+            timing_curve: TimingCurve::Linear,
+            dot_color_from: ColorSource::Fixed,
+            dot_color: (255, 255, 255),
+            signed_colors: None,
+            gradient: Gradient::grayscale(),
+            tonemap: Tonemap::None,
+            invert: false,
+            hue_rotate: 0.0,
+            style: Style::Dots,
+            cell_shape: CellShape::None,
+            stereo: None,
+            adaptive_blocks: None,
+            adaptive_tolerance: 0.1,
+            antialias_samples: 1,
+            dot_aspect: 1.0,
+            dot_opacity: 1.0,
+            center_shift: (0.0, 0.0),
+            shape_exponents: None,
+            color_space: ColorSpace::Srgb,
+            render_scale: 1,
+            accumulate: 0.0,
+            field_threshold: None,
+            binary_threshold: 0.5,
+            square: None,
+            square_color: None,
+            grid_lines: None,
+            border: None,
+            local_palettes: false,
+            optimize: false,
+            no_transparent: false,
+            interlaced: false,
+            palette_dither: 0.0,
+            source_palette: None,
+            png_bit_depth: None,
+            comment: None,
+            background: None,
+            cell_background: None,
+            transparency_from_source: false,
+        }
+    }
+}
+
+impl RenderOptions {
+    /// Where a dot value lies in the range of values (0.0..=1.0) that is mapped to the radius.
+    fn level(&self, value: f32) -> f32 {
+        match self.value_window {
+            Some((_, high)) if self.band_pass && value > high as f32 => 0.0,
+            Some((low, high)) => ((value - low as f32) / (high - low) as f32).clamp(0.0, 1.0),
+            None => value / self.max_value.max(1) as f32,
+        }
+    }
+}
+
+/// An image behind the circles, resized to the output once it is first drawn.
+pub struct Background {
+    image: RgbaImage,
+    resized: OnceLock<Vec<Rgba>>,
+}
+
+impl Background {
+    pub fn new(image: RgbaImage) -> Background {
+        Background {
+            image,
+            resized: OnceLock::new(),
+        }
+    }
+
+    /// The pixels of the image at the size of the layout, which is the same for every frame.
+    fn pixels(&self, layout: &Layout) -> &[Rgba] {
+        self.resized.get_or_init(|| {
+            imageops::resize(
+                &self.image,
+                layout.width,
+                layout.height,
+                FilterType::Triangle,
+            )
+            .pixels()
+            .map(|p| (p[0], p[1], p[2], p[3]))
+            .collect()
+        })
+    }
+}
+
+/// A solid frame around the output image, see `--border`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Border {
+    pub width: u32,
+    pub color: (u8, u8, u8),
+}
+
+/// Where the cells of the dot grid end up in the output image.
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+    pub width: u32,
+    pub height: u32,
+    pub grid_w: u32,
+    pub grid_h: u32,
+    /// The largest radius over the whole animation
+    pub max_radius: u32,
+    /// The area covered by the dot grid, which can reach past the image when cropping
+    pub content_x: i32,
+    pub content_y: i32,
+    pub content_width: u32,
+    pub content_height: u32,
+    /// Width of the border around the image, part of `width` and `height`
+    pub border: u32,
+    padding: u32,
+    spacing: u32,
+    shift: (f32, f32),
+}
+
+impl Layout {
+    pub fn new(frame: &DotFrame, options: &RenderOptions) -> Layout {
+        Layout::for_grid(frame.width as u32, frame.height as u32, options)
+    }
+
+    /// The layout of a dot grid with `grid_w` columns and `grid_h` rows.
+    pub fn for_grid(grid_w: u32, grid_h: u32, options: &RenderOptions) -> Layout {
+        // The border has to fit the largest circle of the whole animation, the circles
+        // themselves may overlap their neighbors if the spacing is smaller than their diameter
+        let max_radius = options.start_radius.max(options.end_radius);
+        let content_width = (grid_w - 1) * options.spacing + 2 * (max_radius + options.padding);
+        let content_height = (grid_h - 1) * options.spacing + 2 * (max_radius + options.padding);
+
+        let (width, height) = match options.square {
+            None => (content_width, content_height),
+            Some(SquareMode::Pad) => {
+                let side = content_width.max(content_height);
+                (side, side)
+            }
+            Some(SquareMode::Crop) => {
+                let side = content_width.min(content_height);
+                (side, side)
+            }
+        };
+        let border = options.border.map_or(0, |b| b.width);
+
+        Layout {
+            width: width + 2 * border,
+            height: height + 2 * border,
+            grid_w,
+            grid_h,
+            max_radius,
+            // Keep the content centered, a negative position crops it
+            content_x: (width as i32 - content_width as i32) / 2 + border as i32,
+            content_y: (height as i32 - content_height as i32) / 2 + border as i32,
+            content_width,
+            content_height,
+            border,
+            padding: options.padding,
+            spacing: options.spacing,
+            shift: options.center_shift,
+        }
+    }
+
+    /// The center of the cell in column `col` and row `row`.
+    pub fn center(&self, col: u32, row: u32) -> (f32, f32) {
+        let origin = (self.padding + self.max_radius) as f32;
+        (
+            self.content_x as f32 + origin + (col * self.spacing) as f32 + self.shift.0,
+            self.content_y as f32 + origin + (row * self.spacing) as f32 + self.shift.1,
+        )
+    }
+
+    /// Whether an image position lies on the area covered by the dot grid.
+    pub fn in_content(&self, x: u32, y: u32) -> bool {
+        let (x, y) = (x as i32 - self.content_x, y as i32 - self.content_y);
+        x >= 0 && y >= 0 && (x as u32) < self.content_width && (y as u32) < self.content_height
+    }
+
+    /// Whether an image position lies on the border around the image.
+    pub fn in_border(&self, x: u32, y: u32) -> bool {
+        x < self.border
+            || y < self.border
+            || x >= self.width - self.border
+            || y >= self.height - self.border
+    }
+
+    /// Maps an image position to fractional grid coordinates, clamped to the grid.
+    fn grid_position(&self, x: u32, y: u32) -> (f32, f32) {
+        let origin = (self.padding + self.max_radius) as f32;
+        let spacing = self.spacing.max(1) as f32;
+        let x = x as f32 - self.content_x as f32 - origin;
+        let y = y as f32 - self.content_y as f32 - origin;
+        (
+            (x / spacing).clamp(0.0, (self.grid_w - 1) as f32),
+            (y / spacing).clamp(0.0, (self.grid_h - 1) as f32),
+        )
+    }
+}
+
+/// The size of the image rendered from a frame of `width`x`height` pixels.
+pub fn output_dimensions(
+    width: u16,
+    height: u16,
+    block_size: usize,
+    grid_offset: (i32, i32),
+    options: &RenderOptions,
+) -> (u32, u32) {
+    let (grid_w, grid_h) = grid_size(width, height, block_size, grid_offset);
+    let layout = Layout::for_grid(grid_w as u32, grid_h as u32, options);
+    (layout.width, layout.height)
+}
+
+/// Whether an image of this size can be stored in a GIF.
+pub fn fits_gif((width, height): (u32, u32)) -> bool {
+    width <= u16::MAX as u32 && height <= u16::MAX as u32
+}
+
+/// Fails for images too large for a GIF, instead of silently truncating their size.
+pub fn check_gif_size(layout: &Layout) -> Result<(), String> {
+    if fits_gif((layout.width, layout.height)) {
+        Ok(())
+    } else {
+        Err(format!(
+            "The output would be {}x{}, GIFs can't be larger than 65535x65535 (see --clamp-output-size)",
+            layout.width, layout.height
+        ))
+    }
+}
+
+/// The color of the dot in cell `idx`.
+fn dot_color(frame: &DotFrame, idx: usize, options: &RenderOptions) -> (u8, u8, u8) {
+    let color = match (options.signed_colors, options.dot_color_from) {
+        (Some((_, negative)), _) if frame.negative.get(idx) == Some(&true) => negative,
+        (Some((positive, _)), _) => positive,
+        (None, ColorSource::Size) => options
+            .gradient
+            .sample(options.level(frame.buffer[idx] as f32)),
+        (None, ColorSource::Original) => frame.colors[idx],
+        (None, ColorSource::Fixed) => options.dot_color,
+    };
+    let color = if options.invert {
+        let (r, g, b) = color;
+        (255 - r, 255 - g, 255 - b)
+    } else {
+        color
+    };
+    let color = if options.hue_rotate != 0.0 {
+        rotate_hue(color, options.hue_rotate)
+    } else {
+        color
+    };
+    options.tonemap.apply(color)
+}
+
+/// Renders the dot frame at `index` out of `count` frames into an RGBA image.
+pub fn render_frame(
+    frame: &DotFrame,
+    index: usize,
+    count: usize,
+    options: &RenderOptions,
+) -> Vec<Rgba> {
+    if options.render_scale > 1 {
+        // Every length of the layout grows with the scale, so the size is an exact multiple
+        let scale = options.render_scale;
+        let large = RenderOptions {
+            padding: options.padding * scale,
+            start_radius: options.start_radius * scale,
+            end_radius: options.end_radius * scale,
+            spacing: options.spacing * scale,
+            stereo: options.stereo.map(|separation| separation * scale as f32),
+            center_shift: (
+                options.center_shift.0 * scale as f32,
+                options.center_shift.1 * scale as f32,
+            ),
+            border: options.border.map(|b| Border {
+                width: b.width * scale,
+                ..b
+            }),
+            render_scale: 1,
+            background: None,
+            cell_background: None,
+            transparency_from_source: false,
+            ..options.clone()
+        };
+        let layout = Layout::new(frame, options);
+        let canvas = shrink(&render_frame(frame, index, count, &large), &layout, scale);
+        return draw_over_background(canvas, frame, &layout, options);
+    }
+
+    let layout = Layout::new(frame, options);
+    let mut canvas = vec![TRANSPARENT; (layout.width * layout.height) as usize];
+    let radius = frame_radius(index, count, options.start_radius, options.end_radius);
+
+    if let Some((r, g, b)) = options.square_color {
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                if !layout.in_content(x, y) {
+                    canvas[(y * layout.width + x) as usize] = (r, g, b, 255);
+                }
+            }
+        }
+    }
+
+    match options.style {
+        Style::Dots | Style::Binary => match options.stereo {
+            Some(separation) => {
+                draw_anaglyph(&mut canvas, frame, &layout, radius, options, separation)
+            }
+            None => draw_dots(&mut canvas, frame, &layout, radius, options, 0.0),
+        },
+        Style::Field => draw_field(&mut canvas, frame, &layout, radius, options),
+    }
+
+    if let Some(color) = options.grid_lines {
+        draw_grid_lines(&mut canvas, &layout, color);
+    }
+
+    // Drawn last, so cropped circles don't reach into it
+    if let Some(Border {
+        color: (r, g, b), ..
+    }) = options.border
+    {
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                if layout.in_border(x, y) {
+                    canvas[(y * layout.width + x) as usize] = (r, g, b, 255);
+                }
+            }
+        }
+    }
+
+    draw_over_background(canvas, frame, &layout, options)
+}
+
+/// Puts the cell background and the background image behind the canvas, and clears the cells
+/// of transparent blocks with [`RenderOptions::transparency_from_source`].
+fn draw_over_background(
+    mut canvas: Vec<Rgba>,
+    frame: &DotFrame,
+    layout: &Layout,
+    options: &RenderOptions,
+) -> Vec<Rgba> {
+    if options.cell_background.is_some() || options.transparency_from_source {
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                if !layout.in_content(x, y) || layout.in_border(x, y) {
+                    continue;
+                }
+                let (col, row) = layout.grid_position(x, y);
+                let cell = row.round() as usize * layout.grid_w as usize + col.round() as usize;
+                let pixel = &mut canvas[(y * layout.width + x) as usize];
+                if options.transparency_from_source && frame.alpha[cell] == 0 {
+                    *pixel = TRANSPARENT;
+                } else if let Some((r, g, b)) = options.cell_background {
+                    *pixel = match *pixel {
+                        (_, _, _, 0) => (r, g, b, 255),
+                        (pr, pg, pb, a) => blend((r, g, b, 255), (pr, pg, pb), a as f32 / 255.0),
+                    };
+                }
+            }
+        }
+    }
+
+    if let Some(background) = &options.background {
+        for (pixel, &behind) in canvas.iter_mut().zip(background.pixels(layout)) {
+            *pixel = match *pixel {
+                (_, _, _, 0) => behind,
+                (r, g, b, a) => blend(behind, (r, g, b), a as f32 / 255.0),
+            };
+        }
+    }
+    canvas
+}
+
+/// Averages every `scale`x`scale` square of a canvas into a single pixel of the smaller
+/// `layout`, weighting the colors by their alpha.
+fn shrink(canvas: &[Rgba], layout: &Layout, scale: u32) -> Vec<Rgba> {
+    let large_width = layout.width * scale;
+    let samples = scale * scale;
+    let mut shrunk = Vec::with_capacity((layout.width * layout.height) as usize);
+
+    for y in 0..layout.height {
+        for x in 0..layout.width {
+            let mut total = [0u32; 4];
+            for sy in y * scale..(y + 1) * scale {
+                for sx in x * scale..(x + 1) * scale {
+                    let (r, g, b, a) = canvas[(sy * large_width + sx) as usize];
+                    let a32 = a as u32;
+                    total[0] += r as u32 * a32;
+                    total[1] += g as u32 * a32;
+                    total[2] += b as u32 * a32;
+                    total[3] += a32;
+                }
+            }
+
+            let [r, g, b] = [0, 1, 2].map(|c| total[c].checked_div(total[3]).unwrap_or(0) as u8);
+            shrunk.push((r, g, b, (total[3] as f32 / samples as f32).round() as u8));
+        }
+    }
+    shrunk
+}
+
+/// Draws the dots once for every eye, moved apart by their level, and takes the red channel
+/// from the left and the others from the right eye.
+fn draw_anaglyph(
+    canvas: &mut [Rgba],
+    frame: &DotFrame,
+    layout: &Layout,
+    radius: f32,
+    options: &RenderOptions,
+    separation: f32,
+) {
+    // Seen through the red filter on the left eye, the near dots move right
+    let mut right = canvas.to_vec();
+    draw_dots(canvas, frame, layout, radius, options, separation / 2.0);
+    draw_dots(
+        &mut right,
+        frame,
+        layout,
+        radius,
+        options,
+        -separation / 2.0,
+    );
+    for (left, right) in canvas.iter_mut().zip(right) {
+        *left = match (*left, right) {
+            ((_, _, _, 0), (_, _, _, 0)) => TRANSPARENT,
+            ((r, _, _, a), (_, g, b, ra)) => (r, g, b, a.max(ra)),
+        };
+    }
+}
+
+/// Splits the grid into squares of `2^depth` cells and keeps splitting every square into
+/// four until the levels of its cells differ by at most `tolerance` or it is a single cell.
+///
+/// Returns the column and row of the top left cell and the side length of every square.
+pub fn adaptive_cells(
+    frame: &DotFrame,
+    depth: u32,
+    tolerance: f32,
+    options: &RenderOptions,
+) -> Vec<(u32, u32, u32)> {
+    let (grid_w, grid_h) = (frame.width as u32, frame.height as u32);
+    let top = 1 << depth;
+    let mut squares: Vec<_> = (0..grid_h)
+        .step_by(top as usize)
+        .flat_map(|row| {
+            (0..grid_w)
+                .step_by(top as usize)
+                .map(move |col| (col, row, top))
+        })
+        .collect();
+    squares.reverse();
+
+    let mut cells = Vec::new();
+    while let Some((col, row, size)) = squares.pop() {
+        if col >= grid_w || row >= grid_h {
+            continue;
+        }
+        let fits = col + size <= grid_w && row + size <= grid_h;
+        let flat = size == 1
+            || fits && {
+                let levels = (row..row + size).flat_map(|r| {
+                    (col..col + size)
+                        .map(move |c| options.level(frame.buffer[(r * grid_w + c) as usize] as f32))
+                });
+                let (low, high) = levels.fold((f32::MAX, f32::MIN), |(low, high), level| {
+                    (low.min(level), high.max(level))
+                });
+                high - low <= tolerance
+            };
+        if flat {
+            cells.push((col, row, size));
+        } else {
+            let half = size / 2;
+            // Pushed in reverse, so the squares come out row by row
+            for (dc, dr) in [(half, half), (0, half), (half, 0), (0, 0)] {
+                squares.push((col + dc, row + dr, half));
+            }
+        }
+    }
+    cells
+}
+
+/// The average value and dot color of the square of `size` cells starting at `col` and `row`.
+fn merged_cell(
+    frame: &DotFrame,
+    (col, row, size): (u32, u32, u32),
+    options: &RenderOptions,
+) -> (f32, (u8, u8, u8)) {
+    let idx = |c: u32, r: u32| (r * frame.width as u32 + c) as usize;
+    if size == 1 {
+        let idx = idx(col, row);
+        return (frame.buffer[idx] as f32, dot_color(frame, idx, options));
+    }
+
+    let (mut value, mut rgb) = (0.0, [0.0f32; 3]);
+    for r in row..row + size {
+        for c in col..col + size {
+            value += frame.buffer[idx(c, r)] as f32;
+            let (cr, cg, cb) = dot_color(frame, idx(c, r), options);
+            for (total, channel) in rgb.iter_mut().zip([cr, cg, cb]) {
+                *total += channel as f32;
+            }
+        }
+    }
+    let count = (size * size) as f32;
+    let [r, g, b] = rgb.map(|c| (c / count).round() as u8);
+    (value / count, (r, g, b))
+}
+
+/// Draws a circle for every block, moved sideways by `parallax` pixels times how much its
+/// level differs from the average level of the frame.
+fn draw_dots(
+    canvas: &mut [Rgba],
+    frame: &DotFrame,
+    layout: &Layout,
+    radius: f32,
+    options: &RenderOptions,
+    parallax: f32,
+) {
+    let (img_w, img_h) = (layout.width, layout.height);
+    let samples = options.antialias_samples.max(1);
+    // Spread the samples evenly over the pixel, a single one sits right on its center
+    let offsets: Vec<f32> = (0..samples)
+        .map(|i| (i as f32 + 0.5) / samples as f32 - 0.5)
+        .collect();
+    let spacing = layout.spacing as f32;
+    let mean_level = if parallax == 0.0 {
+        0.0
+    } else {
+        let levels = frame.buffer.iter().map(|&v| options.level(v as f32));
+        levels.sum::<f32>() / frame.buffer.len().max(1) as f32
+    };
+
+    let cells = match options.adaptive_blocks {
+        Some(depth) => adaptive_cells(frame, depth, options.adaptive_tolerance, options),
+        None => (0..layout.grid_h)
+            .flat_map(|row| (0..layout.grid_w).map(move |col| (col, row, 1)))
+            .collect(),
+    };
+
+    for (col, row, size) in cells {
+        let (value, color) = merged_cell(frame, (col, row, size), options);
+        let level = options.level(value);
+        if level <= 0.0 {
+            continue; // Would otherwise leave a single pixel in the center
+        }
+        // Merged cells get a dot as big as their square
+        let radius = radius * size as f32;
+        let spacing = spacing * size as f32;
+        let r = match options.style {
+            Style::Binary if level >= options.binary_threshold => radius,
+            Style::Binary => continue,
+            _ => options.response.apply(level, options.response_exp) * radius,
+        };
+        let (rx, ry) = if options.dot_aspect >= 1.0 {
+            (r, r / options.dot_aspect)
+        } else {
+            (r * options.dot_aspect, r)
+        };
+        if rx <= 0.0 || ry <= 0.0 {
+            continue;
+        }
+        let exponent = options
+            .shape_exponents
+            .map(|(from, to)| from + (to - from) * level);
+
+        let (first, last) = (
+            layout.center(col, row),
+            layout.center(col + size - 1, row + size - 1),
+        );
+        let (cx, cy) = ((first.0 + last.0) / 2.0, (first.1 + last.1) / 2.0);
+        let cx = cx + parallax * (level - mean_level);
+
+        let x0 = ((cx - rx).max(0.0).floor()) as u32;
+        let x1 = ((cx + rx).min((img_w - 1) as f32).ceil()) as u32;
+        let y0 = ((cy - ry).max(0.0).floor()) as u32;
+        let y1 = ((cy + ry).min((img_h - 1) as f32).ceil()) as u32;
+
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let mut inside = 0;
+                for oy in &offsets {
+                    for ox in &offsets {
+                        let (px, py) = (x as f32 + ox - cx, y as f32 + oy - cy);
+                        if !options.cell_shape.contains(px, py, spacing) {
+                            continue;
+                        }
+                        let (dx, dy) = (px / rx, py / ry);
+                        let distance = match exponent {
+                            Some(n) => dx.abs().powf(n) + dy.abs().powf(n),
+                            None => dx * dx + dy * dy,
+                        };
+                        if distance <= 1.0 {
+                            inside += 1;
+                        }
+                    }
+                }
+
+                if inside > 0 {
+                    let pix_idx = (y * img_w + x) as usize;
+                    let coverage = inside as f32 / (samples * samples) as f32 * options.dot_opacity;
+                    canvas[pix_idx] = blend(canvas[pix_idx], color, coverage);
+                }
+            }
+        }
+    }
+}
+
+/// Draws `color` over a pixel, covering the given fraction of it.
+///
+/// GIFs only know fully transparent pixels, so smooth edges need an opaque background
+/// to blend into there, PNGs keep the partial alpha.
+fn blend(dst: Rgba, (r, g, b): (u8, u8, u8), coverage: f32) -> Rgba {
+    if coverage >= 1.0 {
+        return (r, g, b, 255);
+    }
+
+    let dst_alpha = dst.3 as f32 / 255.0 * (1.0 - coverage);
+    let alpha = coverage + dst_alpha;
+    let mix =
+        |src: u8, dst: u8| ((src as f32 * coverage + dst as f32 * dst_alpha) / alpha).round() as u8;
+    (
+        mix(r, dst.0),
+        mix(g, dst.1),
+        mix(b, dst.2),
+        (alpha * 255.0).round() as u8,
+    )
+}
+
+/// Draws one pixel wide lines halfway between neighboring cell centers, including around
+/// the outer cells.
+fn draw_grid_lines(canvas: &mut [Rgba], layout: &Layout, (r, g, b): (u8, u8, u8)) {
+    let (img_w, img_h) = (layout.width as i32, layout.height as i32);
+    let half = layout.spacing as f32 / 2.0;
+    let (left, top) = layout.center(0, 0);
+    let (right, bottom) = layout.center(layout.grid_w - 1, layout.grid_h - 1);
+    let (x0, x1) = ((left - half).round() as i32, (right + half).round() as i32);
+    let (y0, y1) = ((top - half).round() as i32, (bottom + half).round() as i32);
+
+    let mut put = |x: i32, y: i32| {
+        if x >= 0 && y >= 0 && x < img_w && y < img_h {
+            canvas[(y * img_w + x) as usize] = (r, g, b, 255);
+        }
+    };
+
+    for col in 0..=layout.grid_w {
+        let x = (left - half + (col * layout.spacing) as f32).round() as i32;
+        (y0..=y1).for_each(|y| put(x, y));
+    }
+    for row in 0..=layout.grid_h {
+        let y = (top - half + (row * layout.spacing) as f32).round() as i32;
+        (x0..=x1).for_each(|x| put(x, y));
+    }
+}
+
+/// Treats the dot values as a low resolution field and bilinearly upsamples it to the
+/// whole image, which gives soft blobs instead of discrete circles.
+fn draw_field(
+    canvas: &mut [Rgba],
+    frame: &DotFrame,
+    layout: &Layout,
+    radius: f32,
+    options: &RenderOptions,
+) {
+    // Scale the field like the circles so the radius options still apply
+    let scale = radius / layout.max_radius.max(1) as f32;
+    let encoding = options.color_space.encoding();
+    let grid_w = layout.grid_w as usize;
+
+    for y in 0..layout.height {
+        for x in 0..layout.width {
+            if !layout.in_content(x, y) {
+                continue;
+            }
+
+            let (gx, gy) = layout.grid_position(x, y);
+            let (x0, y0) = (gx.floor() as usize, gy.floor() as usize);
+            let x1 = (x0 + 1).min(grid_w - 1);
+            let y1 = (y0 + 1).min(layout.grid_h as usize - 1);
+            let (tx, ty) = (gx - x0 as f32, gy - y0 as f32);
+
+            let cells = [
+                y0 * grid_w + x0,
+                y0 * grid_w + x1,
+                y1 * grid_w + x0,
+                y1 * grid_w + x1,
+            ];
+            let weights = [
+                (1.0 - tx) * (1.0 - ty),
+                tx * (1.0 - ty),
+                (1.0 - tx) * ty,
+                tx * ty,
+            ];
+
+            let mut value = 0.0;
+            let mut rgb = [0.0f32; 3];
+            for (&cell, &weight) in cells.iter().zip(&weights) {
+                value += frame.buffer[cell] as f32 * weight;
+                let (r, g, b) = dot_color(frame, cell, options);
+                for (total, c) in rgb.iter_mut().zip([r, g, b]) {
+                    *total += encoding[c as usize] as f32 * weight;
+                }
+            }
+            let rgb = match options.color_space {
+                ColorSpace::Srgb => rgb,
+                space => rgb.map(|c| space.decode(c.round() as u64) as f32),
+            };
+            let level = options.level(value);
+            let value =
+                (options.response.apply(level, options.response_exp) * scale).clamp(0.0, 1.0);
+
+            canvas[(y * layout.width + x) as usize] = match options.field_threshold {
+                Some(threshold) if value < threshold => TRANSPARENT,
+                Some(_) => (rgb[0] as u8, rgb[1] as u8, rgb[2] as u8, 255),
+                None => {
+                    let [r, g, b] = rgb.map(|c| (c * value).round() as u8);
+                    (r, g, b, 255)
+                }
+            };
+        }
+    }
+}
+
+/// Keeps the previous frame around to draw the next one over it, see `--accumulate`.
+#[derive(Clone)]
+pub struct Trails {
+    decay: f32,
+    previous: Vec<Rgba>,
+}
+
+impl Trails {
+    pub fn new(decay: f32) -> Trails {
+        Trails {
+            decay,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Draws `canvas` over the faded previous frame, which is the result as well.
+    ///
+    /// Only the alpha fades, so in a GIF the trails disappear at once when it drops below half.
+    pub fn composite(&mut self, mut canvas: Vec<Rgba>) -> Vec<Rgba> {
+        if self.decay <= 0.0 {
+            return canvas;
+        }
+
+        if self.previous.len() == canvas.len() {
+            for (pixel, &(r, g, b, a)) in canvas.iter_mut().zip(&self.previous) {
+                let faded = (r, g, b, (a as f32 * self.decay) as u8);
+                *pixel = match *pixel {
+                    (_, _, _, 0) => faded,
+                    (r, g, b, a) => blend(faded, (r, g, b), a as f32 / 255.0),
+                };
+            }
+        }
+        self.previous.clone_from(&canvas);
+        canvas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether the red channel, seen by the left eye, lines up with the others of the right.
+    fn eyes_coincide(canvas: &[Rgba]) -> bool {
+        canvas.iter().all(|&(r, g, b, _)| r == g && g == b)
+    }
+
+    /// How many pixels of the row and column through the center of the first dot it covers at
+    /// least half of.
+    fn dot_size(canvas: &[Rgba], layout: &Layout) -> (usize, usize) {
+        let (cx, cy) = layout.center(0, 0);
+        let covered = |x: u32, y: u32| canvas[(y * layout.width + x) as usize].3 >= 128;
+        let (cx, cy) = (cx as u32, cy as u32);
+        let size = layout.spacing.min(layout.width.min(layout.height));
+        let (x0, y0) = (cx.saturating_sub(size), cy.saturating_sub(size));
+        (
+            (x0..(cx + size).min(layout.width))
+                .filter(|&x| covered(x, cy))
+                .count(),
+            (y0..(cy + size).min(layout.height))
+                .filter(|&y| covered(cx, y))
+                .count(),
+        )
+    }
+
+    #[test]
+    fn flat_depth_puts_both_eyes_on_the_same_dots() {
+        let frame = DotFrame::from_values(3, 2, &[128; 6]);
+        let options = RenderOptions {
+            max_value: 255,
+            stereo: Some(8.0),
+            ..RenderOptions::default()
+        };
+        let anaglyph = render_frame(&frame, 0, 1, &options);
+        assert!(eyes_coincide(&anaglyph));
+
+        let flat = RenderOptions {
+            stereo: None,
+            ..options
+        };
+        assert_eq!(anaglyph, render_frame(&frame, 0, 1, &flat));
+    }
+
+    #[test]
+    fn depth_moves_the_eyes_apart() {
+        let frame = DotFrame::from_values(3, 2, &[255, 64, 255, 64, 255, 64]);
+        let options = RenderOptions {
+            max_value: 255,
+            stereo: Some(8.0),
+            ..RenderOptions::default()
+        };
+        assert!(!eyes_coincide(&render_frame(&frame, 0, 1, &options)));
+    }
+
+    #[test]
+    fn radius_goes_from_the_start_to_the_end() {
+        assert_eq!(frame_radius(0, 5, 2, 10), 2.0);
+        assert_eq!(frame_radius(2, 5, 2, 10), 6.0);
+        assert_eq!(frame_radius(4, 5, 2, 10), 10.0);
+        // A single frame keeps the start radius
+        assert_eq!(frame_radius(0, 1, 2, 10), 2.0);
+
+        let frame = DotFrame::from_values(1, 1, &[1]);
+        let options = RenderOptions {
+            start_radius: 2,
+            end_radius: 10,
+            spacing: 22,
+            ..RenderOptions::default()
+        };
+        let layout = Layout::new(&frame, &options);
+        let first = render_frame(&frame, 0, 5, &options);
+        let last = render_frame(&frame, 4, 5, &options);
+        // The pixels are sampled at their corner, so both ends of the diameter are in
+        assert_eq!(dot_size(&first, &layout), (5, 5));
+        assert_eq!(dot_size(&last, &layout), (21, 21));
+    }
+
+    #[test]
+    fn overlapping_dots_draw_the_union_of_their_disks() {
+        // Without clipping the radius, dots of radius 10 reach past the cells 12 pixels apart
+        let frame = DotFrame::from_values(2, 1, &[1, 1]);
+        let options = RenderOptions {
+            start_radius: 10,
+            end_radius: 10,
+            spacing: 12,
+            ..RenderOptions::default()
+        };
+        let layout = Layout::new(&frame, &options);
+        let canvas = render_frame(&frame, 0, 1, &options);
+
+        let centers = [layout.center(0, 0), layout.center(1, 0)];
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                let inside = centers
+                    .iter()
+                    .any(|&(cx, cy)| (x as f32 - cx).powi(2) + (y as f32 - cy).powi(2) <= 100.0);
+                let alpha = canvas[(y * layout.width + x) as usize].3;
+                assert_eq!(alpha == 255, inside, "pixel {x},{y}");
+                assert!(alpha == 255 || alpha == 0);
+            }
+        }
+    }
+
+    #[test]
+    fn reinhard_compresses_the_highlights() {
+        let (r, g, b) = Tonemap::Reinhard.apply((255, 250, 240));
+        assert!(r < 200 && g < 200 && b < 200, "{r},{g},{b}");
+
+        let (r, g, b) = Tonemap::Reinhard.apply((64, 60, 56));
+        assert!(r.abs_diff(64) <= 3 && g.abs_diff(60) <= 3 && b.abs_diff(56) <= 3);
+        assert_eq!(Tonemap::None.apply((255, 250, 240)), (255, 250, 240));
+    }
+
+    #[test]
+    fn field_of_a_smooth_grid_is_smooth() {
+        let values: Vec<usize> = (0..16).map(|i| 10 * (i % 4 + i / 4)).collect();
+        let frame = DotFrame::from_values(4, 4, &values);
+        let options = RenderOptions {
+            max_value: 60,
+            style: Style::Field,
+            ..RenderOptions::default()
+        };
+        let layout = Layout::new(&frame, &options);
+        let canvas = render_frame(&frame, 0, 1, &options);
+
+        // The values grow by a sixth of the range from cell to cell, 18 pixels apart
+        let step = (255.0_f32 / 6.0 / 18.0).ceil() as u8;
+        let red = |x: u32, y: u32| canvas[(y * layout.width + x) as usize].0;
+        for y in 0..layout.height - 1 {
+            for x in 0..layout.width - 1 {
+                assert!(red(x, y).abs_diff(red(x + 1, y)) <= step, "{x},{y}");
+                assert!(red(x, y).abs_diff(red(x, y + 1)) <= step, "{x},{y}");
+            }
+        }
+        assert!(red(0, 0) < red(layout.width - 1, layout.height - 1));
+    }
+
+    /// The color at the center of the first dot.
+    fn center_color(frame: &DotFrame, options: &RenderOptions) -> Rgba {
+        let layout = Layout::new(frame, options);
+        let (cx, cy) = layout.center(0, 0);
+        render_frame(frame, 0, 1, options)[(cy as u32 * layout.width + cx as u32) as usize]
+    }
+
+    #[test]
+    fn color_sources_give_the_dot_colors() {
+        let mut frame = DotFrame::from_values(2, 1, &[64, 128]);
+        frame.colors = vec![(200, 40, 10), (0, 0, 0)];
+        let options = |dot_color_from| RenderOptions {
+            max_value: 128,
+            dot_color_from,
+            dot_color: (10, 20, 30),
+            gradient: Gradient::even(&[(0, 0, 0), (255, 0, 0)]),
+            ..RenderOptions::default()
+        };
+
+        // Half the largest value is halfway through the gradient
+        let size = center_color(&frame, &options(ColorSource::Size));
+        assert_eq!(size, (128, 0, 0, 255));
+        let original = center_color(&frame, &options(ColorSource::Original));
+        assert_eq!(original, (200, 40, 10, 255));
+        let fixed = center_color(&frame, &options(ColorSource::Fixed));
+        assert_eq!(fixed, (10, 20, 30, 255));
+    }
+
+    #[test]
+    fn gradient_runs_from_the_smallest_to_the_largest_dots() {
+        let gradient = Gradient::parse("ff0000\n00ff00\n0000ff").unwrap();
+        assert_eq!(gradient.sample(0.0), (255, 0, 0));
+        assert_eq!(gradient.sample(0.5), (0, 255, 0));
+        assert_eq!(gradient.sample(1.0), (0, 0, 255));
+
+        let options = RenderOptions {
+            max_value: 255,
+            dot_color_from: ColorSource::Size,
+            gradient,
+            ..RenderOptions::default()
+        };
+        let smallest = center_color(&DotFrame::from_values(1, 1, &[1]), &options);
+        assert_eq!(smallest, (253, 2, 0, 255));
+        let largest = center_color(&DotFrame::from_values(1, 1, &[255]), &options);
+        assert_eq!(largest, (0, 0, 255, 255));
+    }
+
+    #[test]
+    fn square_output_centers_the_dots() {
+        // A 16:9 grid of dots, 290x164 pixels
+        let frame = DotFrame::from_values(16, 9, &[1; 16 * 9]);
+        let options = |square| RenderOptions {
+            square: Some(square),
+            square_color: Some((255, 0, 0)),
+            ..RenderOptions::default()
+        };
+
+        let padded = Layout::new(&frame, &options(SquareMode::Pad));
+        assert_eq!((padded.width, padded.height), (290, 290));
+        assert_eq!((padded.content_x, padded.content_y), (0, 63));
+        let canvas = render_frame(&frame, 0, 1, &options(SquareMode::Pad));
+        // The padding above and below the dots has the square color
+        assert_eq!(canvas[62 * 290 + 20], (255, 0, 0, 255));
+        assert_eq!(canvas[(63 + 164) * 290 + 20], (255, 0, 0, 255));
+        assert_eq!(canvas[63 * 290 + 20], TRANSPARENT);
+        let (cx, cy) = padded.center(0, 0);
+        assert_eq!((cx, cy), (10.0, 73.0));
+
+        let cropped = Layout::new(&frame, &options(SquareMode::Crop));
+        assert_eq!((cropped.width, cropped.height), (164, 164));
+        // The columns on the left and right are cut off evenly
+        assert_eq!((cropped.content_x, cropped.content_y), (-63, 0));
+        let middle = (cropped.center(7, 4).0 + cropped.center(8, 4).0) / 2.0;
+        assert_eq!((middle, cropped.center(7, 4).1), (82.0, 82.0));
+    }
+
+    #[test]
+    fn border_surrounds_the_image() {
+        let frame = DotFrame::from_values(3, 2, &[1; 6]);
+        let plain = Layout::new(&frame, &RenderOptions::default());
+        let options = RenderOptions {
+            border: Some(Border {
+                width: 10,
+                color: (255, 0, 0),
+            }),
+            ..RenderOptions::default()
+        };
+        let layout = Layout::new(&frame, &options);
+        assert_eq!(
+            (layout.width, layout.height),
+            (plain.width + 20, plain.height + 20)
+        );
+
+        let canvas = render_frame(&frame, 0, 1, &options);
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                let edge = x
+                    .min(y)
+                    .min(layout.width - 1 - x)
+                    .min(layout.height - 1 - y);
+                let pixel = canvas[(y * layout.width + x) as usize];
+                assert_eq!(pixel == (255, 0, 0, 255), edge < 10, "pixel {x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn more_samples_give_smoother_edges() {
+        let frame = DotFrame::from_values(1, 1, &[1]);
+        let alphas = |samples| {
+            let options = RenderOptions {
+                antialias_samples: samples,
+                ..RenderOptions::default()
+            };
+            let canvas = render_frame(&frame, 0, 1, &options);
+            canvas.iter().map(|p| p.3 as i32).collect::<Vec<_>>()
+        };
+        let levels = |alphas: &[i32]| {
+            let mut partial: Vec<_> = alphas.iter().filter(|&&a| a > 0 && a < 255).collect();
+            partial.sort();
+            partial.dedup();
+            partial.len()
+        };
+        // How far the coverage of the edge pixels is from a far finer sampling
+        let reference = alphas(32);
+        let error = |alphas: &[i32]| -> i32 {
+            alphas
+                .iter()
+                .zip(&reference)
+                .map(|(a, r)| (a - r).abs())
+                .sum()
+        };
+        let (coarse, fine) = (alphas(2), alphas(8));
+
+        // 4 samples can only cover a pixel by a quarter, half or three quarters
+        assert!(levels(&coarse) <= 3);
+        assert!(levels(&fine) > 3);
+        assert!(error(&fine) * 2 < error(&coarse));
+    }
+
+    #[test]
+    fn grid_lines_run_between_the_cells() {
+        let frame = DotFrame::from_values(3, 2, &[1; 6]);
+        let options = RenderOptions {
+            grid_lines: Some((0, 255, 0)),
+            ..RenderOptions::default()
+        };
+        let layout = Layout::new(&frame, &options);
+        let canvas = render_frame(&frame, 0, 1, &options);
+        let green = |x: u32, y: u32| canvas[(y * layout.width + x) as usize] == (0, 255, 0, 255);
+
+        // Half the spacing of 18 before the first center at 10 and after every center
+        let (columns, rows) = ([1, 19, 37, 55], [1, 19, 37]);
+        for y in 1..=37 {
+            for x in 1..=55 {
+                let on_line = columns.contains(&x) || rows.contains(&y);
+                assert_eq!(green(x, y), on_line, "pixel {x},{y}");
+            }
+        }
+    }
+
+    #[test]
+    fn inverted_colors_swap_white_and_black() {
+        let mut frame = DotFrame::from_values(1, 1, &[1]);
+        let options = RenderOptions {
+            dot_color_from: ColorSource::Original,
+            invert: true,
+            ..RenderOptions::default()
+        };
+        assert_eq!(center_color(&frame, &options), (0, 0, 0, 255));
+        frame.colors = vec![(0, 0, 0)];
+        assert_eq!(center_color(&frame, &options), (255, 255, 255, 255));
+        frame.colors = vec![(200, 100, 0)];
+        assert_eq!(center_color(&frame, &options), (55, 155, 255, 255));
+    }
+
+    #[test]
+    fn half_a_turn_makes_red_cyan() {
+        let mut frame = DotFrame::from_values(1, 1, &[1]);
+        frame.colors = vec![(255, 0, 0)];
+        let options = RenderOptions {
+            dot_color_from: ColorSource::Original,
+            hue_rotate: 180.0,
+            ..RenderOptions::default()
+        };
+        let (r, g, b, _) = center_color(&frame, &options);
+        assert!(r <= 2 && g >= 253 && b >= 253, "{r},{g},{b}");
+    }
+
+    #[test]
+    fn larger_render_scale_gives_smoother_edges() {
+        let frame = DotFrame::from_values(2, 1, &[1, 2]);
+        let render = |scale| {
+            let options = RenderOptions {
+                max_value: 2,
+                dot_color_from: ColorSource::Size,
+                render_scale: scale,
+                ..RenderOptions::default()
+            };
+            render_frame(&frame, 0, 1, &options)
+        };
+        let shades = |canvas: &[Rgba]| {
+            let mut partial: Vec<_> = canvas.iter().filter(|p| p.3 > 0 && p.3 < 255).collect();
+            partial.sort();
+            partial.dedup();
+            partial.len()
+        };
+        let (sharp, smooth) = (render(1), render(4));
+
+        assert_eq!(sharp.len(), smooth.len());
+        assert_eq!(shades(&sharp), 0);
+        assert!(shades(&smooth) > 4);
+    }
+
+    #[test]
+    fn moving_dots_leave_a_fading_trail() {
+        let options = RenderOptions::default();
+        let moving = |i| {
+            let mut values = [0; 3];
+            values[i] = 1;
+            DotFrame::from_values(3, 1, &values)
+        };
+        let layout = Layout::new(&moving(0), &options);
+        let alpha_at = |canvas: &[Rgba], column| {
+            let (x, y) = layout.center(column, 0);
+            canvas[(y as u32 * layout.width + x as u32) as usize].3
+        };
+        let (mut trails, mut cleared) = (Trails::new(0.8), Trails::new(0.0));
+        let (mut canvas, mut last) = (Vec::new(), Vec::new());
+        for i in 0..3 {
+            canvas = trails.composite(render_frame(&moving(i), i, 3, &options));
+            last = cleared.composite(render_frame(&moving(i), i, 3, &options));
+        }
+
+        let trail: Vec<u8> = (0..3).map(|column| alpha_at(&canvas, column)).collect();
+        assert_eq!(trail, [163, 204, 255]);
+        let trail: Vec<u8> = (0..3).map(|column| alpha_at(&last, column)).collect();
+        assert_eq!(trail, [0, 0, 255]);
+    }
+
+    #[test]
+    fn background_shows_between_the_dots() {
+        // A 4x4 image, stretched over the layout
+        let image = RgbaImage::from_fn(4, 4, |x, y| {
+            image::Rgba([x as u8 * 60, y as u8 * 60, 90, 255])
+        });
+        let frame = DotFrame::from_values(3, 2, &[1, 0, 1, 1, 0, 1]);
+        let plain = RenderOptions::default();
+        let behind = RenderOptions {
+            background: Some(Arc::new(Background::new(image.clone()))),
+            ..RenderOptions::default()
+        };
+        let without = render_frame(&frame, 0, 1, &plain);
+        let with = render_frame(&frame, 0, 1, &behind);
+
+        let layout = Layout::new(&frame, &plain);
+        let resized = imageops::resize(&image, layout.width, layout.height, FilterType::Triangle);
+        let mut uncovered = 0;
+        for ((dot, pixel), expected) in without.iter().zip(&with).zip(resized.pixels()) {
+            if dot.3 == 0 {
+                assert_eq!(*pixel, (expected[0], expected[1], expected[2], expected[3]));
+                uncovered += 1;
+            } else {
+                assert_eq!(pixel, dot);
+            }
+        }
+        assert!(uncovered > with.len() / 2);
+    }
+
+    #[test]
+    fn cells_cleared_by_a_callback_get_no_dot() {
+        let mut frames: Vec<DotFrame> = (0..2)
+            .map(|_| DotFrame::from_values(3, 1, &[1; 3]))
+            .collect();
+        crate::dots::process_frames(&mut frames, |index, frame| {
+            if index == 1 {
+                frame.buffer[2] = 0;
+            }
+        });
+
+        let options = RenderOptions::default();
+        let layout = Layout::new(&frames[0], &options);
+        for (index, frame) in frames.iter().enumerate() {
+            let canvas = render_frame(frame, index, frames.len(), &options);
+            let drawn: Vec<bool> = (0..3)
+                .map(|column| {
+                    let (x, y) = layout.center(column, 0);
+                    canvas[(y as u32 * layout.width + x as u32) as usize].3 > 0
+                })
+                .collect();
+            assert_eq!(drawn, [true, true, index == 0]);
+        }
+    }
+
+    #[test]
+    fn sqrt_response_grows_the_mid_values() {
+        let frame = DotFrame::from_values(1, 1, &[1]);
+        let size = |response| {
+            let options = RenderOptions {
+                max_value: 4,
+                response,
+                ..RenderOptions::default()
+            };
+            dot_size(
+                &render_frame(&frame, 0, 1, &options),
+                &Layout::new(&frame, &options),
+            )
+        };
+        // A quarter of the maximum gets a quarter of the radius of 8, or the root of it
+        assert_eq!(size(Response::Linear), (5, 5));
+        assert_eq!(size(Response::Sqrt), (9, 9));
+    }
+
+    #[test]
+    fn value_window_spans_the_radius_range() {
+        let options = RenderOptions {
+            max_value: 255,
+            value_window: Some((50, 200)),
+            ..RenderOptions::default()
+        };
+        let size = |value| {
+            let frame = DotFrame::from_values(1, 1, &[value]);
+            dot_size(
+                &render_frame(&frame, 0, 1, &options),
+                &Layout::new(&frame, &options),
+            )
+        };
+        assert_eq!(size(20), (0, 0));
+        assert_eq!(size(50), (0, 0));
+        assert_eq!(size(125), (9, 9));
+        assert_eq!(size(200), (17, 17));
+        assert_eq!(size(255), (17, 17));
+    }
+
+    #[test]
+    fn wide_dots_are_twice_as_wide_as_tall() {
+        let frame = DotFrame::from_values(1, 1, &[1]);
+        let size = |dot_aspect| {
+            let options = RenderOptions {
+                dot_aspect,
+                ..RenderOptions::default()
+            };
+            dot_size(
+                &render_frame(&frame, 0, 1, &options),
+                &Layout::new(&frame, &options),
+            )
+        };
+        // Diameters of 16 and 8 pixels, with both ends in
+        assert_eq!(size(2.0), (17, 9));
+        assert_eq!(size(0.5), (9, 17));
+        assert_eq!(size(1.0), (17, 17));
+    }
+
+    #[test]
+    fn shapes_go_from_circles_to_squares() {
+        // The response keeps the smallest dot large enough to see its shape
+        let frame = DotFrame::from_values(2, 1, &[1, 100]);
+        let options = RenderOptions {
+            max_value: 100,
+            start_radius: 16,
+            end_radius: 16,
+            spacing: 34,
+            response: Response::Pow,
+            response_exp: 0.1,
+            shape_exponents: Some((2.0, 20.0)),
+            ..RenderOptions::default()
+        };
+        let layout = Layout::new(&frame, &options);
+        let canvas = render_frame(&frame, 0, 1, &options);
+        // How much of the square around a dot it fills
+        let fill = |column: usize, radius: f32| {
+            let covered = canvas
+                .iter()
+                .enumerate()
+                .filter(|&(i, p)| (i % layout.width as usize) / 36 == column && p.3 >= 128)
+                .count();
+            covered as f32 / (2.0 * radius).powi(2)
+        };
+        let small_radius = 0.01f32.powf(0.1) * 16.0;
+        // A quarter of the square is outside of a circle
+        assert!((fill(0, small_radius) - std::f32::consts::FRAC_PI_4).abs() < 0.05);
+        assert!(fill(1, 16.0) > 0.95);
+    }
+
+    #[test]
+    fn half_pixel_shifts_change_the_coverage() {
+        let frame = DotFrame::from_values(2, 2, &[1, 2, 2, 1]);
+        let render = |center_shift| {
+            let options = RenderOptions {
+                max_value: 2,
+                antialias_samples: 4,
+                center_shift,
+                ..RenderOptions::default()
+            };
+            render_frame(&frame, 0, 1, &options)
+        };
+        let coverage = |canvas: &[Rgba]| canvas.iter().map(|p| p.3 as u32).sum::<u32>();
+        let (still, shifted) = (render((0.0, 0.0)), render((0.5, 0.0)));
+
+        assert_ne!(still, shifted);
+        // The dots only moved, so they cover about as much as before
+        assert!(coverage(&still).abs_diff(coverage(&shifted)) * 50 < coverage(&still));
+    }
+
+    #[test]
+    fn transparent_blocks_stay_transparent() {
+        let mut frame = DotFrame::from_values(2, 2, &[1, 0, 1, 1]);
+        frame.alpha = vec![255, 255, 255, 0];
+        let options = RenderOptions {
+            cell_background: Some((0, 0, 80)),
+            transparency_from_source: true,
+            ..RenderOptions::default()
+        };
+        let layout = Layout::new(&frame, &options);
+        let canvas = render_frame(&frame, 0, 1, &options);
+
+        for y in 0..layout.height {
+            for x in 0..layout.width {
+                if !layout.in_content(x, y) {
+                    continue;
+                }
+                let (col, row) = layout.grid_position(x, y);
+                let cell = row.round() as usize * 2 + col.round() as usize;
+                let alpha = canvas[(y * layout.width + x) as usize].3;
+                assert_eq!(alpha == 0, cell == 3, "pixel {x}, {y} of cell {cell}");
+            }
+        }
+    }
+
+    #[test]
+    fn half_opaque_dots_show_the_black_behind() {
+        let frame = DotFrame::from_values(1, 1, &[1]);
+        let options = RenderOptions {
+            dot_color: (200, 100, 50),
+            dot_opacity: 0.5,
+            cell_background: Some((0, 0, 0)),
+            ..RenderOptions::default()
+        };
+        assert_eq!(center_color(&frame, &options), (100, 50, 25, 255));
+        let opaque = RenderOptions {
+            dot_opacity: 1.0,
+            ..options
+        };
+        assert_eq!(center_color(&frame, &opaque), (200, 100, 50, 255));
+    }
+
+    #[test]
+    fn square_cells_keep_the_dots_inside() {
+        // Dots of radius 12 are larger than their cells of 18 pixels
+        let frame = DotFrame::from_values(3, 2, &[1; 6]);
+        let outside = |cell_shape| {
+            let options = RenderOptions {
+                start_radius: 12,
+                end_radius: 12,
+                spacing: 18,
+                cell_shape,
+                ..RenderOptions::default()
+            };
+            let layout = Layout::new(&frame, &options);
+            let canvas = render_frame(&frame, 0, 1, &options);
+            let mut outside = 0;
+            for (i, _) in canvas.iter().enumerate().filter(|(_, p)| p.3 > 0) {
+                let (x, y) = (
+                    (i as u32 % layout.width) as f32,
+                    (i as u32 / layout.width) as f32,
+                );
+                let (col, row) = layout.grid_position(x as u32, y as u32);
+                let (cx, cy) = layout.center(col.round() as u32, row.round() as u32);
+                if (x - cx).abs() > 9.0 || (y - cy).abs() > 9.0 {
+                    outside += 1;
+                }
+            }
+            outside
+        };
+        assert!(outside(CellShape::None) > 0);
+        assert_eq!(outside(CellShape::Square), 0);
+    }
+
+    #[test]
+    fn binary_dots_are_all_the_same_size() {
+        let frame = DotFrame::from_values(4, 1, &[10, 49, 50, 100]);
+        let options = RenderOptions {
+            max_value: 100,
+            style: Style::Binary,
+            binary_threshold: 0.5,
+            ..RenderOptions::default()
+        };
+        let canvas = render_frame(&frame, 0, 1, &options);
+        let width = Layout::new(&frame, &options).width as usize;
+        let mut areas = [0; 4];
+        for (i, _) in canvas.iter().enumerate().filter(|(_, p)| p.3 > 0) {
+            areas[((i % width).saturating_sub(2) / 18).min(3)] += 1;
+        }
+
+        assert_eq!(areas[..2], [0, 0]);
+        assert!(areas[2] > 0);
+        assert_eq!(areas[2], areas[3]);
+    }
+
+    #[test]
+    fn flat_regions_merge_into_larger_dots() {
+        // A flat left half and a checkerboard of values on the right
+        let values: Vec<usize> = (0..32)
+            .map(|i| match i % 8 < 4 {
+                true => 5,
+                false if (i % 8 + i / 8) % 2 == 0 => 1,
+                false => 9,
+            })
+            .collect();
+        let frame = DotFrame::from_values(8, 4, &values);
+        let options = RenderOptions {
+            max_value: 9,
+            ..RenderOptions::default()
+        };
+        let cells = adaptive_cells(&frame, 2, 0.1, &options);
+
+        let (flat, detailed): (Vec<_>, Vec<_>) = cells.iter().partition(|&&(col, _, _)| col < 4);
+        assert_eq!(flat, [&(0, 0, 4)]);
+        assert_eq!(detailed.len(), 16);
+        assert!(detailed.iter().all(|&&(_, _, size)| size == 1));
+
+        // The merged dot spans its square of cells
+        let merged = RenderOptions {
+            adaptive_blocks: Some(2),
+            ..options
+        };
+        let layout = Layout::new(&frame, &merged);
+        let canvas = render_frame(&frame, 0, 1, &merged);
+        let ((x0, y0), (x1, y1)) = (layout.center(0, 0), layout.center(3, 3));
+        let middle = ((y0 + y1) / 2.0) as u32 * layout.width + ((x0 + x1) / 2.0) as u32;
+        assert_eq!(canvas[middle as usize].3, 255);
+    }
+
+    #[test]
+    fn band_pass_drops_the_values_outside_the_band() {
+        let options = RenderOptions {
+            max_value: 255,
+            value_window: Some((50, 200)),
+            band_pass: true,
+            ..RenderOptions::default()
+        };
+        let size = |value| {
+            let frame = DotFrame::from_values(1, 1, &[value]);
+            dot_size(
+                &render_frame(&frame, 0, 1, &options),
+                &Layout::new(&frame, &options),
+            )
+        };
+        assert_eq!(size(20), (0, 0));
+        assert_eq!(size(125), (9, 9));
+        assert_eq!(size(200), (17, 17));
+        // Clamped to the largest dots without the band pass
+        assert_eq!(size(201), (0, 0));
+        assert_eq!(size(255), (0, 0));
+    }
+
+    #[test]
+    fn signed_dots_are_colored_by_the_direction() {
+        let mut frames = [
+            DotFrame::from_values(3, 1, &[10, 90, 50]),
+            DotFrame::from_values(3, 1, &[90, 10, 60]),
+        ];
+        crate::dots::motion_frames(&mut frames);
+        let (green, red) = ((0, 255, 0), (255, 0, 0));
+        let options = RenderOptions {
+            max_value: 80,
+            signed_colors: Some((green, red)),
+            ..RenderOptions::default()
+        };
+        let layout = Layout::new(&frames[1], &options);
+        let canvas = render_frame(&frames[1], 1, 2, &options);
+
+        let mut areas = [0; 3];
+        for (i, _) in canvas.iter().enumerate().filter(|(_, p)| p.3 > 0) {
+            areas[((i as u32 % layout.width).saturating_sub(2) / 18).min(2) as usize] += 1;
+        }
+        assert_eq!(areas[0], areas[1]);
+        assert!(0 < areas[2] && areas[2] < areas[0]);
+        let color_at = |column| {
+            let (x, y) = layout.center(column, 0);
+            let (r, g, b, _) = canvas[(y as u32 * layout.width + x as u32) as usize];
+            (r, g, b)
+        };
+        assert_eq!([color_at(0), color_at(1), color_at(2)], [green, red, green]);
+    }
+}
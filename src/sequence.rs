@@ -0,0 +1,407 @@
+//! Writing the rendered frames as a numbered sequence of PNGs.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    color::Rgba,
+    dots::DotFrame,
+    encode::RenderSummary,
+    palette::{Palette, count_colors},
+    render::{Layout, RenderOptions, render_frame},
+};
+
+/// What to do about frames of a PNG sequence that already exist.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExistingFrames {
+    /// Refuse to overwrite them
+    Fail,
+    /// Render them again
+    Overwrite,
+    /// Keep the ones that decode fine, e.g. to continue an interrupted render
+    Skip,
+}
+
+/// Whether `path` asks for a PNG sequence instead of a GIF.
+pub fn is_png_sequence<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("png"))
+}
+
+/// The path of frame `index` of a PNG sequence, `out.png` becomes `out.0000.png`, `out.0001.png`, ...
+pub fn sequence_path<P: AsRef<Path>>(path: P, index: usize) -> PathBuf {
+    let path = path.as_ref();
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.{:04}.png", stem, index))
+}
+
+/// The file names of a PNG sequence, see `--name-template`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameTemplate {
+    prefix: String,
+    /// How many digits the index is padded to with zeros
+    width: usize,
+    suffix: String,
+}
+
+impl NameTemplate {
+    /// Parses a template like `frame_{n:05}.png` with exactly one index placeholder.
+    pub fn parse(s: &str) -> Result<NameTemplate, String> {
+        let Some(start) = s.find("{n") else {
+            return Err("The template needs a {n} placeholder for the frame index".to_string());
+        };
+        let end = start
+            + s[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed placeholder in {}", s))?;
+        let suffix = &s[end + 1..];
+        if suffix.contains("{n") {
+            return Err("The template can only have one {n} placeholder".to_string());
+        }
+
+        let width = match &s[start + 2..end] {
+            "" => 0,
+            spec => spec
+                .strip_prefix(":0")
+                .and_then(|w| w.parse().ok())
+                .ok_or_else(|| {
+                    format!("Expected {{n}} or {{n:0WIDTH}}, got {}", &s[start..=end])
+                })?,
+        };
+        Ok(NameTemplate {
+            prefix: s[..start].to_string(),
+            width,
+            suffix: suffix.to_string(),
+        })
+    }
+
+    /// The path of frame `index`, in the directory of `path`.
+    pub fn path<P: AsRef<Path>>(&self, path: P, index: usize) -> PathBuf {
+        let name = format!(
+            "{}{:0width$}{}",
+            self.prefix,
+            index,
+            self.suffix,
+            width = self.width
+        );
+        path.as_ref().with_file_name(name)
+    }
+}
+
+/// Checks that a previously written frame decodes completely and has the expected size,
+/// so frames of an interrupted render that were only partially written get rendered again.
+pub(crate) fn is_complete_png(path: &Path, layout: &Layout) -> bool {
+    let Ok(file) = File::open(path) else {
+        return false;
+    };
+    let Ok(mut reader) = png::Decoder::new(BufReader::new(file)).read_info() else {
+        return false;
+    };
+    let Some(size) = reader.output_buffer_size() else {
+        return false;
+    };
+
+    let info = reader.info();
+    if info.width != layout.width || info.height != layout.height {
+        return false;
+    }
+    let mut buffer = vec![0; size];
+    reader.next_frame(&mut buffer).is_ok()
+}
+
+/// Renders every dot frame into its own numbered RGBA PNG, see [`sequence_path`], or named
+/// after `template`.
+///
+/// The frames are independent files, so a pool of `threads` threads renders and compresses
+/// them at once.
+/// Every frame only depends on its own dots and the shared palette, so the files are the same
+/// for any number of workers.
+pub fn write_png_sequence(
+    path: &str,
+    frames: &[DotFrame],
+    options: &RenderOptions,
+    existing: ExistingFrames,
+    threads: usize,
+    template: Option<&NameTemplate>,
+) -> Result<RenderSummary, Box<dyn std::error::Error>> {
+    assert!(!frames.is_empty(), "Need at least one frame");
+
+    let layout = Layout::new(&frames[0], options);
+    let paths: Vec<PathBuf> = (0..frames.len())
+        .map(|i| match template {
+            Some(template) => template.path(path, i),
+            None => sequence_path(path, i),
+        })
+        .collect();
+
+    // Refuse before writing anything, rather than leaving half a sequence behind
+    if existing == ExistingFrames::Fail
+        && let Some(frame_path) = paths.iter().find(|p| p.exists())
+    {
+        return Err(format!(
+            "{} already exists, use --resume or --force",
+            frame_path.display()
+        )
+        .into());
+    }
+
+    // Indexed frames share one palette, found by rendering everything once like for GIFs
+    let palette = options.png_bit_depth.map(|bits| {
+        let mut histogram = HashMap::new();
+        for (i, df) in frames.iter().enumerate() {
+            count_colors(&mut histogram, &render_frame(df, i, frames.len(), options));
+        }
+        (bits, Palette::with_max_colors(&histogram, (1 << bits) - 1))
+    });
+
+    // The whole time of every frame and the part of it spent rendering
+    let write_frame = |i: usize| -> Result<(Duration, Duration), String> {
+        let start = Instant::now();
+        let frame_path = &paths[i];
+        if existing == ExistingFrames::Skip && is_complete_png(frame_path, &layout) {
+            return Ok((start.elapsed(), Duration::ZERO));
+        }
+
+        let canvas = render_frame(&frames[i], i, frames.len(), options);
+        let render_time = start.elapsed();
+        let data: Vec<u8> = match &palette {
+            Some((bits, palette)) => pack_indices(&canvas, palette, layout.width, *bits),
+            None => canvas
+                .iter()
+                .flat_map(|&(r, g, b, a)| [r, g, b, a])
+                .collect(),
+        };
+
+        let failed =
+            |e: &dyn std::fmt::Display| format!("Failed to write {}: {}", frame_path.display(), e);
+        let file = File::create(frame_path).map_err(|e| failed(&e))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), layout.width, layout.height);
+        match &palette {
+            Some((bits, palette)) => {
+                // The transparent index comes right after the colors, like in the GIFs
+                let mut colors = palette.colors.clone();
+                colors.extend_from_slice(&[0, 0, 0]);
+                let mut alpha = vec![255; palette.colors.len() / 3];
+                alpha.push(0);
+
+                encoder.set_color(png::ColorType::Indexed);
+                encoder.set_depth(png::BitDepth::from_u8(*bits).expect("Checked by the parser"));
+                encoder.set_palette(colors);
+                encoder.set_trns(alpha);
+            }
+            None => {
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+            }
+        }
+        let mut writer = encoder.write_header().map_err(|e| failed(&e))?;
+        writer.write_image_data(&data).map_err(|e| failed(&e))?;
+        writer.finish().map_err(|e| failed(&e))?;
+        Ok((start.elapsed(), render_time))
+    };
+
+    // Collecting in order keeps the times in the order of the frames for any number of threads
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads.max(1))
+        .build()?;
+    let (frame_times, render_times) = pool
+        .install(|| {
+            (0..frames.len())
+                .into_par_iter()
+                .map(write_frame)
+                .collect::<Result<Vec<_>, _>>()
+        })?
+        .into_iter()
+        .unzip();
+    Ok(RenderSummary {
+        layout,
+        palette: Vec::new(),
+        frame_times,
+        render_times,
+        delays: Vec::new(),
+        paths,
+    })
+}
+
+/// Maps a canvas to palette indices with `bits` per pixel, every row starting on a new byte.
+fn pack_indices(canvas: &[Rgba], palette: &Palette, width: u32, bits: u8) -> Vec<u8> {
+    let per_byte = 8 / bits as usize;
+    let mut data = Vec::with_capacity(canvas.len() / per_byte + 1);
+    for row in canvas.chunks(width as usize) {
+        for pixels in row.chunks(per_byte) {
+            let mut byte = 0u8;
+            for (i, &pixel) in pixels.iter().enumerate() {
+                byte |= palette.index_of(pixel) << (8 - bits as usize * (i + 1));
+            }
+            data.push(byte);
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{input::read_png, testing::TempDir};
+
+    fn frames(count: usize) -> Vec<DotFrame> {
+        (0..count)
+            .map(|i| DotFrame::from_values(2, 2, &[i, 0, count - i, 1]))
+            .collect()
+    }
+
+    fn options(max_value: usize) -> RenderOptions {
+        RenderOptions {
+            start_radius: 3,
+            end_radius: 3,
+            spacing: 8,
+            max_value,
+            ..RenderOptions::default()
+        }
+    }
+
+    #[test]
+    fn parallel_frames_keep_their_numbers() {
+        let dir = TempDir::new("sequence-numbers");
+        let path = dir.join("out.png");
+        let (frames, options) = (frames(7), options(7));
+        let summary =
+            write_png_sequence(&path, &frames, &options, ExistingFrames::Fail, 4, None).unwrap();
+
+        assert_eq!(summary.frame_times.len(), 7);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(
+                summary.paths[i],
+                Path::new(&dir.join(&format!("out.{:04}.png", i)))
+            );
+            let rgba: Vec<u8> = render_frame(frame, i, frames.len(), &options)
+                .into_iter()
+                .flat_map(|(r, g, b, a)| [r, g, b, a])
+                .collect();
+            assert_eq!(read_png(&summary.paths[i]).unwrap().into_raw(), rgba);
+        }
+    }
+
+    #[test]
+    fn failed_frames_are_reported() {
+        let dir = TempDir::new("sequence-failure");
+        let path = dir.join("missing/out.png");
+        let result = write_png_sequence(
+            &path,
+            &frames(5),
+            &options(5),
+            ExistingFrames::Overwrite,
+            4,
+            None,
+        );
+        let Err(err) = result else {
+            panic!("Wrote into a missing directory")
+        };
+        assert!(err.to_string().starts_with("Failed to write"), "{err}");
+    }
+
+    #[test]
+    fn output_is_the_same_for_any_number_of_threads() {
+        let dir = TempDir::new("sequence-threads");
+        let (frames, options) = (frames(9), options(9));
+        let files = |threads: usize| -> Vec<Vec<u8>> {
+            let path = dir.join(&format!("threads{}.png", threads));
+            let existing = ExistingFrames::Fail;
+            write_png_sequence(&path, &frames, &options, existing, threads, None)
+                .unwrap()
+                .paths
+                .iter()
+                .map(|path| std::fs::read(path).unwrap())
+                .collect()
+        };
+        assert_eq!(files(1), files(8));
+    }
+
+    #[test]
+    fn resuming_skips_the_frames_that_exist() {
+        let dir = TempDir::new("sequence-resume");
+        let path = dir.join("out.png");
+        let path = &path;
+        let write = |frames: &[DotFrame], existing| {
+            write_png_sequence(path, frames, &options(5), existing, 2, None).unwrap()
+        };
+        let first = write(&frames(5), ExistingFrames::Fail);
+        let old: Vec<Vec<u8>> = first
+            .paths
+            .iter()
+            .map(|p| std::fs::read(p).unwrap())
+            .collect();
+        for i in [0, 2, 4] {
+            std::fs::remove_file(&first.paths[i]).unwrap();
+        }
+
+        // Other values, so the frames that get written again look different
+        let other: Vec<DotFrame> = (0..5)
+            .map(|i| DotFrame::from_values(2, 2, &[5, 5, i, 0]))
+            .collect();
+        let resumed = write(&other, ExistingFrames::Skip);
+        for (i, path) in resumed.paths.iter().enumerate() {
+            let kept = std::fs::read(path).unwrap() == old[i];
+            assert_eq!(kept, i % 2 == 1, "frame {i}");
+            assert_eq!(resumed.render_times[i].is_zero(), i % 2 == 1, "frame {i}");
+        }
+    }
+
+    #[test]
+    fn one_bit_frames_are_indexed() {
+        let dir = TempDir::new("sequence-bit-depth");
+        let path = dir.join("out.png");
+        // White dots on a transparent background, two colors with the transparent one
+        let options = RenderOptions {
+            png_bit_depth: Some(1),
+            ..options(3)
+        };
+        let frames = frames(3);
+        let summary =
+            write_png_sequence(&path, &frames, &options, ExistingFrames::Fail, 2, None).unwrap();
+
+        for (i, path) in summary.paths.iter().enumerate() {
+            let decoder = png::Decoder::new(BufReader::new(File::open(path).unwrap()));
+            let info = decoder.read_info().unwrap().info().clone();
+            assert_eq!(info.color_type, png::ColorType::Indexed);
+            assert_eq!(info.bit_depth, png::BitDepth::One);
+            assert_eq!(info.palette.unwrap().len(), 2 * 3);
+
+            let rgba: Vec<u8> = render_frame(&frames[i], i, frames.len(), &options)
+                .into_iter()
+                .flat_map(|(r, g, b, a)| [r, g, b, a])
+                .collect();
+            assert_eq!(read_png(path).unwrap().into_raw(), rgba);
+        }
+    }
+
+    #[test]
+    fn templates_name_the_frames() {
+        let template = NameTemplate::parse("frame_{n:05}.png").unwrap();
+        let names: Vec<PathBuf> = (0..3).map(|i| template.path("out/x.png", i)).collect();
+        assert_eq!(
+            names,
+            [
+                "out/frame_00000.png",
+                "out/frame_00001.png",
+                "out/frame_00002.png"
+            ]
+            .map(PathBuf::from)
+        );
+        let unpadded = NameTemplate::parse("{n}-dots.png").unwrap();
+        assert_eq!(unpadded.path("x.png", 12), PathBuf::from("12-dots.png"));
+
+        assert!(NameTemplate::parse("frame.png").is_err());
+        assert!(NameTemplate::parse("{n}_{n}.png").is_err());
+        assert!(NameTemplate::parse("{n:5}.png").is_err());
+        assert!(NameTemplate::parse("{n.png").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Converting a GIF frame by frame in a pipeline of threads, without keeping all of them in
+//! memory.
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    sync::{Arc, Mutex, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use gif::{Encoder, Frame};
+
+use crate::{
+    dots::DotFrame,
+    encode::{RenderSummary, interlace, local_palette_frame, write_comment},
+    input::GifFrame,
+    render::{Layout, RenderOptions, check_gif_size, render_frame},
+    timing::TimingCurve,
+};
+
+/// A frame that a worker of [`stream_circles_gif`] finished.
+struct EncodedFrame {
+    index: usize,
+    layout: Layout,
+    /// Already LZW compressed
+    frame: Frame<'static>,
+    time: Duration,
+}
+
+/// Converts the frames in a pipeline: a decoder thread feeds them to `threads` workers that
+/// convert, render and compress them, while the calling thread writes them out in order.
+/// The stages are connected by bounded channels, so memory stays flat no matter how long the GIF is.
+///
+/// Since the frames are never all in memory, `options.max_value` has to be known up front and
+/// every frame gets a local palette of its own.
+///
+/// A frame is only ever handled by one worker and nothing is shared between them, so together
+/// with the ordered writing the output is the same byte for byte for any number of threads.
+pub fn stream_circles_gif(
+    frames: impl Iterator<Item = Result<GifFrame, String>> + Send,
+    writer: impl Write,
+    convert: &(impl Fn(&GifFrame) -> DotFrame + Sync),
+    options: &RenderOptions,
+    threads: usize,
+) -> Result<RenderSummary, Box<dyn std::error::Error>> {
+    if options.start_radius != options.end_radius || options.timing_curve != TimingCurve::Linear {
+        return Err(
+            "Streaming can't vary the radius or delays, the frame count isn't known".into(),
+        );
+    }
+
+    let threads = threads.max(1);
+    let (frame_tx, frame_rx) = mpsc::sync_channel::<(usize, GifFrame)>(threads);
+    let frame_rx = Arc::new(Mutex::new(frame_rx));
+    let (encoded_tx, encoded_rx) = mpsc::sync_channel::<EncodedFrame>(threads);
+
+    thread::scope(|scope| {
+        let decoder = scope.spawn(move || -> Result<(), String> {
+            for (index, frame) in frames.enumerate() {
+                // The workers only hang up if writing failed, which gets reported instead
+                if frame_tx.send((index, frame?)).is_err() {
+                    break;
+                }
+            }
+            Ok(())
+        });
+
+        for _ in 0..threads {
+            let frame_rx = Arc::clone(&frame_rx);
+            let encoded_tx = encoded_tx.clone();
+            scope.spawn(move || {
+                loop {
+                    let received = frame_rx.lock().unwrap().recv();
+                    let Ok((index, frame)) = received else {
+                        break;
+                    };
+
+                    let start = Instant::now();
+                    let dots = convert(&frame);
+                    let layout = Layout::new(&dots, options);
+                    let mut frame =
+                        local_palette_frame(&render_frame(&dots, 0, 1, options), &layout, options);
+                    frame.delay = dots.delay.unwrap_or(options.delay);
+                    if options.interlaced {
+                        frame = interlace(&frame);
+                    }
+                    frame.make_lzw_pre_encoded();
+
+                    let encoded = EncodedFrame {
+                        index,
+                        layout,
+                        frame,
+                        time: start.elapsed(),
+                    };
+                    if encoded_tx.send(encoded).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // Only the workers may keep the channels open, otherwise they never shut down
+        drop(frame_rx);
+        drop(encoded_tx);
+
+        let written = write_in_order(writer, encoded_rx, options);
+        let decoded = decoder.join().expect("Decoder thread panicked");
+        let summary = written?;
+        decoded?;
+        Ok(summary)
+    })
+}
+
+/// Writes the frames coming out of the workers in their original order.
+fn write_in_order<W: Write>(
+    writer: W,
+    encoded: mpsc::Receiver<EncodedFrame>,
+    options: &RenderOptions,
+) -> Result<RenderSummary, Box<dyn std::error::Error>> {
+    let mut writer = Some(writer);
+    let mut encoder: Option<(Encoder<W>, Layout)> = None;
+    let mut pending = BTreeMap::new();
+    let mut frame_times = Vec::new();
+    let mut delays = Vec::new();
+
+    for frame in encoded {
+        pending.insert(frame.index, frame);
+
+        while let Some(next) = pending.remove(&frame_times.len()) {
+            let (encoder, _) = match &mut encoder {
+                Some(encoder) => encoder,
+                None => {
+                    check_gif_size(&next.layout)?;
+                    let (w, h) = (next.layout.width as u16, next.layout.height as u16);
+                    let writer = writer.take().expect("The encoder is only created once");
+                    let mut new = Encoder::new(writer, w, h, &[])?;
+                    new.set_repeat(options.repeat)?;
+                    write_comment(&mut new, options)?;
+                    encoder.insert((new, next.layout))
+                }
+            };
+
+            encoder.write_lzw_pre_encoded_frame(&next.frame)?;
+            frame_times.push(next.time);
+            delays.push(next.frame.delay);
+        }
+    }
+
+    let (_, layout) = encoder.ok_or("The GIF has no frames")?;
+    Ok(RenderSummary {
+        layout,
+        palette: Vec::new(),
+        frame_times,
+        render_times: Vec::new(),
+        delays,
+        paths: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color::Rgba,
+        convert::{ColorMode, ColorSpace, Metric, Reduction, convert_frame},
+    };
+
+    /// Frames of 24x16 pixels with a gradient that moves a little on every frame.
+    fn frames(count: usize) -> impl Iterator<Item = Result<GifFrame, String>> + Send {
+        (0..count).map(|i| {
+            let buffer: Vec<Rgba> = (0..24 * 16)
+                .map(|p| {
+                    let value = ((p % 24 + p / 24 + i) * 10 % 256) as u8;
+                    (value, 255 - value, 128, 255)
+                })
+                .collect();
+            Ok(GifFrame {
+                width: 24,
+                height: 16,
+                buffer,
+                delay: 10,
+            })
+        })
+    }
+
+    fn stream(threads: usize) -> Vec<u8> {
+        let convert = |frame: &GifFrame| {
+            let key = |pixel: &Rgba| Metric::Brightness.key(pixel) as usize;
+            let (mode, space) = (ColorMode::Average, ColorSpace::Srgb);
+            convert_frame(frame, 4, (0, 0), Reduction::Mean, mode, space, &key)
+        };
+        let options = RenderOptions {
+            max_value: 255,
+            ..RenderOptions::default()
+        };
+        let mut output = Vec::new();
+        let summary = stream_circles_gif(frames(12), &mut output, &convert, &options, threads);
+        assert_eq!(summary.unwrap().delays.len(), 12);
+        output
+    }
+
+    #[test]
+    fn output_is_the_same_for_any_number_of_threads() {
+        assert_eq!(stream(1), stream(8));
+    }
+}
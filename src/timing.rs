@@ -0,0 +1,127 @@
+//! The delays of the output frames.
+
+use crate::input::GifFrame;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum TimingCurve {
+    /// Every frame is shown for the same amount of time
+    Linear,
+    /// Starts slow and speeds up towards the end
+    EaseIn,
+    /// Starts fast and slows down towards the end
+    EaseOut,
+    /// Starts and ends slow, fast in the middle
+    EaseInOut,
+}
+
+impl TimingCurve {
+    /// Maps the progress through the animation (0.0..=1.0) to the point in time (0.0..=1.0)
+    /// at which that progress is reached, i.e. the inverse of the quadratic easing function.
+    fn time_at(self, progress: f32) -> f32 {
+        match self {
+            TimingCurve::Linear => progress,
+            TimingCurve::EaseIn => progress.sqrt(),
+            TimingCurve::EaseOut => 1.0 - (1.0 - progress).sqrt(),
+            TimingCurve::EaseInOut if progress < 0.5 => (progress / 2.0).sqrt(),
+            TimingCurve::EaseInOut => 1.0 - ((1.0 - progress) / 2.0).sqrt(),
+        }
+    }
+}
+
+/// The shortest delay that viewers reliably play, in hundredths of a second.
+pub const MIN_DELAY: u16 = 2;
+
+/// Returns the delay and the step between the frames to keep to play frames of `delay`
+/// `speed` times as fast, dropping frames where the delay would get shorter than [`MIN_DELAY`].
+pub fn speed_up(delay: u16, speed: f32) -> (u16, usize) {
+    let target = delay as f32 / speed;
+    let min_delay = MIN_DELAY.min(delay) as f32;
+    let step = if target < min_delay {
+        (min_delay / target).ceil() as usize
+    } else {
+        1
+    };
+    let delay = (target * step as f32).round().clamp(1.0, u16::MAX as f32) as u16;
+    (delay, step)
+}
+
+/// Plays frames with delays of their own `speed` times as fast, see `--retime-from-input`.
+///
+/// The time is rounded as it accumulates, so the total stays intact even though every delay is
+/// a whole number of hundredths.
+pub struct Retimer {
+    speed: f64,
+    /// The scaled time of the frames so far, including the dropped ones
+    elapsed: f64,
+    /// The time taken up by the frames that were kept
+    shown: u64,
+    /// The last frame that was dropped while no frame was kept yet
+    dropped: Option<GifFrame>,
+}
+
+impl Retimer {
+    pub fn new(speed: f32) -> Retimer {
+        Retimer {
+            speed: speed as f64,
+            elapsed: 0.0,
+            shown: 0,
+            dropped: None,
+        }
+    }
+
+    /// Gives the frame its scaled delay, or drops it if that would be shorter than [`MIN_DELAY`].
+    pub fn retime(&mut self, mut frame: GifFrame) -> Option<GifFrame> {
+        self.elapsed += frame.delay as f64 / self.speed;
+        let delay = (self.elapsed.round() as u64).saturating_sub(self.shown);
+        if delay < MIN_DELAY.min(frame.delay) as u64 {
+            if self.shown == 0 {
+                self.dropped = Some(frame);
+            }
+            return None;
+        }
+        self.shown += delay;
+        self.dropped = None;
+        frame.delay = delay.min(u16::MAX as u64) as u16;
+        Some(frame)
+    }
+
+    /// Returns the last frame with the time that is left if every frame was dropped, so that
+    /// inputs too short to speed up still give a GIF.
+    pub fn finish(&mut self) -> Option<GifFrame> {
+        let mut frame = self.dropped.take()?;
+        let delay = (self.elapsed.round() as u64).max(MIN_DELAY.min(frame.delay) as u64);
+        self.shown += delay;
+        frame.delay = delay.min(u16::MAX as u64) as u16;
+        Some(frame)
+    }
+}
+
+/// Computes the delay of each of `count` frames so that the whole animation takes
+/// `count * base_delay` and the playback speed follows the given curve.
+pub fn frame_delays(count: usize, base_delay: u16, curve: TimingCurve) -> Vec<u16> {
+    let total = count as f32 * base_delay as f32;
+    // Rounding the accumulated time instead of the single delays keeps the total intact
+    let at = |i: usize| (curve.time_at(i as f32 / count as f32) * total).round() as i64;
+
+    (0..count)
+        .map(|i| (at(i + 1) - at(i)).clamp(1, u16::MAX as i64) as u16)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ease_in_delays_get_shorter_and_keep_the_total() {
+        let delays = frame_delays(20, 10, TimingCurve::EaseIn);
+        // Up to the hundredth that rounding the accumulated time moves a delay by
+        assert!(delays.windows(2).all(|w| w[0] + 1 >= w[1]), "{delays:?}");
+        assert!(delays[0] > delays[19]);
+        assert_eq!(delays.iter().map(|&d| d as u32).sum::<u32>(), 200);
+
+        let linear = frame_delays(20, 10, TimingCurve::Linear);
+        assert_eq!(linear, [10; 20]);
+    }
+}
@@ -0,0 +1,214 @@
+//! A terminal preview of the first frame, for tuning the parameters before converting everything.
+
+use clap::ValueEnum;
+use pointillist::{color::Rgba, convert::Metric};
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style},
+    text::{Line, Span},
+    widgets::Paragraph,
+};
+
+use crate::{Args, Preview, convert_gif, run};
+
+const HELP: &str = "b/B block  r/R radius  p/P padding  m/M metric  s save  q quit";
+
+/// Shows the first frame rendered with the current arguments and rerenders it on every change
+/// until the user quits, saving the full conversion with `s`.
+pub fn run_tui(args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let mut terminal = ratatui::init();
+    let result = tune(&mut terminal, args);
+    ratatui::restore();
+    result
+}
+
+fn tune(terminal: &mut DefaultTerminal, mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    let mut status = String::new();
+    loop {
+        let preview = render_preview(&args);
+        terminal.draw(|frame| draw(frame, &args, &preview, &status))?;
+
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        status.clear();
+        match key.code {
+            KeyCode::Char('b') => change_block_size(&mut args, -1),
+            KeyCode::Char('B') => change_block_size(&mut args, 1),
+            KeyCode::Char('r') => change_radius(&mut args, -1),
+            KeyCode::Char('R') => change_radius(&mut args, 1),
+            KeyCode::Char('p') => args.padding = args.padding.saturating_sub(1),
+            KeyCode::Char('P') => args.padding += 1,
+            KeyCode::Char('m') => args.metric = cycle_metric(args.metric, 1),
+            KeyCode::Char('M') => {
+                args.metric = cycle_metric(args.metric, Metric::value_variants().len() - 1)
+            }
+            KeyCode::Char('s') => {
+                status = match run(&args) {
+                    Ok(()) => format!("Saved {}", args.out_path.as_deref().unwrap_or_default()),
+                    Err(err) => format!("Error: {err}"),
+                }
+            }
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            _ => {}
+        }
+    }
+}
+
+/// Changes the block size, which takes over from a grid density.
+fn change_block_size(args: &mut Args, delta: isize) {
+    args.block_size = args.block_size.saturating_add_signed(delta).max(1);
+    args.grid_density = None;
+}
+
+/// Changes the radius of all circles, dropping a radius range so the change is visible.
+fn change_radius(args: &mut Args, delta: i32) {
+    args.radius = args.radius.saturating_add_signed(delta).max(1);
+    args.start_radius = None;
+    args.end_radius = None;
+}
+
+fn cycle_metric(metric: Metric, step: usize) -> Metric {
+    let metrics = Metric::value_variants();
+    let index = metrics.iter().position(|&m| m == metric).unwrap_or(0);
+    metrics[(index + step) % metrics.len()]
+}
+
+/// Renders the first frame through the regular pipeline without writing anything, or returns
+/// the error of the conversion.
+fn render_preview(args: &Args) -> Result<Preview, String> {
+    let preview_args = Args {
+        first_frame_only: true,
+        input_loop: 1,
+        benchmark_mode: false,
+        output_palette_file: None,
+        stats_json: None,
+        emit_manifest: None,
+        profile: None,
+        verify: false,
+        ..args.clone()
+    };
+    let mut preview = (0, 0, Vec::new());
+    convert_gif(&preview_args, Some(&mut preview)).map_err(|err| err.to_string())?;
+    Ok(preview)
+}
+
+/// Draws the preview scaled to the terminal with the parameters and key bindings below.
+fn draw(frame: &mut Frame, args: &Args, preview: &Result<Preview, String>, status: &str) {
+    let [image_area, text_area] =
+        Layout::vertical([Constraint::Min(1), Constraint::Length(3)]).areas(frame.area());
+
+    match preview {
+        Ok((width, height, canvas)) => {
+            let lines = half_blocks(*width as usize, *height as usize, canvas, image_area);
+            frame.render_widget(Paragraph::new(lines), image_area);
+        }
+        Err(err) => frame.render_widget(Paragraph::new(format!("Error: {err}")), image_area),
+    }
+
+    let metric = args
+        .metric
+        .to_possible_value()
+        .map(|v| v.get_name().to_owned());
+    let text = vec![
+        Line::from(format!(
+            "block {}  radius {}  padding {}  metric {}",
+            args.block_size,
+            args.radius,
+            args.padding,
+            metric.unwrap_or_default(),
+        )),
+        Line::from(HELP),
+        Line::from(status.to_owned()),
+    ];
+    frame.render_widget(Paragraph::new(text), text_area);
+}
+
+/// The image scaled into `area` keeping its aspect ratio, two pixels per character with a half
+/// block, the upper one in front and the lower one behind, as characters are twice as high as
+/// wide.
+fn half_blocks(width: usize, height: usize, canvas: &[Rgba], area: Rect) -> Vec<Line<'static>> {
+    let (cols, rows) = (area.width.max(1) as usize, area.height.max(1) as usize);
+    let scale = (width as f32 / cols as f32).max(height as f32 / (rows * 2) as f32);
+    let (out_w, out_h) = (
+        ((width as f32 / scale) as usize).max(1),
+        ((height as f32 / scale) as usize / 2).max(1),
+    );
+    let pixel = |x: usize, y: usize| {
+        let (sx, sy) = ((x as f32 * scale) as usize, (y as f32 * scale) as usize);
+        let (r, g, b, a) = canvas[sy.min(height - 1) * width + sx.min(width - 1)];
+        // Transparent pixels are shown against a black terminal
+        let [r, g, b] = [r, g, b].map(|c| (c as u16 * a as u16 / 255) as u8);
+        Color::Rgb(r, g, b)
+    };
+
+    (0..out_h)
+        .map(|y| {
+            (0..out_w)
+                .map(|x| {
+                    let style = Style::new().fg(pixel(x, y * 2)).bg(pixel(x, y * 2 + 1));
+                    Span::styled("▀", style)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        testing::TempDir,
+        tests::{gradient, write_gif},
+    };
+
+    #[test]
+    fn preview_is_the_first_frame_without_writing_it() {
+        let dir = TempDir::new("tui-preview");
+        let input = dir.join("in.gif");
+        write_gif(&input, (16, 8), 3, gradient);
+        let out = dir.join("out.gif");
+        let args = Args::try_parse_from(["pointillist", "-i", &input, "-o", &out, "-b", "4"]);
+
+        let (width, height, canvas) = render_preview(&args.unwrap()).unwrap();
+        // 4x2 circles of radius 8, 18 pixels apart, with a padding of 2
+        assert_eq!((width, height), (3 * 18 + 20, 18 + 20));
+        assert_eq!(canvas.len(), (width * height) as usize);
+        assert!(!std::path::Path::new(&out).exists());
+    }
+
+    #[test]
+    fn half_blocks_show_two_rows_per_line() {
+        let canvas = [
+            (255, 0, 0, 255),
+            (0, 0, 255, 255),
+            (0, 255, 0, 255),
+            (0, 0, 0, 0),
+        ];
+        let lines = half_blocks(2, 2, &canvas, Rect::new(0, 0, 2, 1));
+
+        assert_eq!(lines.len(), 1);
+        let styles: Vec<_> = lines[0].spans.iter().map(|span| span.style).collect();
+        assert_eq!(
+            styles[0],
+            Style::new()
+                .fg(Color::Rgb(255, 0, 0))
+                .bg(Color::Rgb(0, 255, 0))
+        );
+        // The transparent pixel shows the black terminal
+        assert_eq!(
+            styles[1],
+            Style::new()
+                .fg(Color::Rgb(0, 0, 255))
+                .bg(Color::Rgb(0, 0, 0))
+        );
+    }
+}
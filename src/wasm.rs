@@ -0,0 +1,175 @@
+//! Converting a GIF in memory, for running the converter in a browser.
+//!
+//! Everything happens on the bytes of the GIFs, without files or threads, so this builds for
+//! `wasm32-unknown-unknown`, where JavaScript calls it as `stippleGif(bytes, params)`.
+
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    convert::{ColorMode, ColorSpace, Metric, convert_frame, neighborhood_values},
+    dots::{DotFrame, motion_frames},
+    encode::encode_circles_gif,
+    input::GifFrameReader,
+    render::RenderOptions,
+};
+
+/// The parameters of [`stipple_gif`], named like the arguments of the binary.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct StippleParams {
+    /// Size of the square blocks of pixels that become one dot
+    pub block_size: usize,
+    /// Radius of the dots with the largest value
+    pub radius: u32,
+    /// Padding between the outermost dots and the image border
+    pub padding: u32,
+    /// Delay of the output frames, in hundredths of a second
+    pub delay: u16,
+    /// What the size of the dots shows
+    pub metric: Metric,
+}
+
+impl Default for StippleParams {
+    fn default() -> StippleParams {
+        StippleParams {
+            block_size: 8,
+            radius: 8,
+            padding: 2,
+            delay: 5,
+            metric: Metric::Brightness,
+        }
+    }
+}
+
+/// Converts the bytes of a GIF into the bytes of its pointillist version.
+///
+/// The metrics that need more than the pixels, [`Metric::Channel`] and [`Metric::Blend`], are
+/// only available in the binary.
+pub fn stipple_gif(input: &[u8], params: &StippleParams) -> Result<Vec<u8>, String> {
+    let metric = params.metric;
+    if matches!(metric, Metric::Channel | Metric::Blend) {
+        return Err(format!("The {:?} metric needs the binary", metric));
+    }
+    if params.block_size == 0 || params.radius == 0 {
+        return Err("The block size and radius have to be positive".to_string());
+    }
+
+    let key_func = |pixel: &(u8, u8, u8, u8)| metric.key(pixel) as usize;
+    let mut dot_frames = GifFrameReader::new(input)?
+        .map(|frame| {
+            let frame = frame?;
+            let mut dots = convert_frame(
+                &frame,
+                params.block_size,
+                (0, 0),
+                metric.reduction(),
+                ColorMode::Average,
+                ColorSpace::Srgb,
+                &key_func,
+            );
+            neighborhood_values(
+                &frame,
+                &mut dots,
+                metric,
+                params.block_size,
+                (0, 0),
+                &key_func,
+            );
+            Ok(dots)
+        })
+        .collect::<Result<Vec<DotFrame>, String>>()?;
+    if dot_frames.is_empty() {
+        return Err("The GIF has no frames".to_string());
+    }
+    if metric == Metric::Motion {
+        motion_frames(&mut dot_frames);
+    }
+
+    let options = RenderOptions {
+        padding: params.padding,
+        start_radius: params.radius,
+        end_radius: params.radius,
+        spacing: 2 * params.radius + params.padding,
+        delay: params.delay,
+        max_value: dot_frames
+            .iter()
+            .flat_map(|f| f.buffer.iter())
+            .cloned()
+            .max()
+            .unwrap_or(1),
+        ..RenderOptions::default()
+    };
+    let mut output = Vec::new();
+    encode_circles_gif(&mut output, &dot_frames, &options).map_err(|err| err.to_string())?;
+    Ok(output)
+}
+
+/// [`stipple_gif`] for JavaScript, with the parameters as an object like `{ blockSize: 8 }`.
+#[wasm_bindgen(js_name = stippleGif)]
+pub fn stipple_gif_js(input: &[u8], params: JsValue) -> Result<Vec<u8>, JsError> {
+    let params: StippleParams = if params.is_undefined() {
+        StippleParams::default()
+    } else {
+        serde_wasm_bindgen::from_value(params)?
+    };
+    stipple_gif(input, &params).map_err(|err| JsError::new(&err))
+}
+
+#[cfg(test)]
+mod tests {
+    use gif::{DecodeOptions, Encoder, Frame, Repeat};
+
+    use super::*;
+
+    /// A GIF of `frames` frames of 16x8 pixels, with a white square moving to the right.
+    fn moving_square(frames: u16) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut encoder = Encoder::new(&mut bytes, 16, 8, &[0, 0, 0, 255, 255, 255]).unwrap();
+        encoder.set_repeat(Repeat::Infinite).unwrap();
+        for i in 0..frames {
+            let pixels: Vec<u8> = (0..16 * 8)
+                .map(|p| (p % 16 / 4 == i % 4 && p / 16 < 4) as u8)
+                .collect();
+            let frame = Frame {
+                width: 16,
+                height: 8,
+                buffer: pixels.into(),
+                delay: 10,
+                ..Frame::default()
+            };
+            encoder.write_frame(&frame).unwrap();
+        }
+        drop(encoder);
+        bytes
+    }
+
+    #[test]
+    fn stipple_gif_converts_every_frame() {
+        let params = StippleParams {
+            block_size: 4,
+            radius: 3,
+            padding: 1,
+            ..StippleParams::default()
+        };
+        let output = stipple_gif(&moving_square(3), &params).unwrap();
+
+        let mut decoder = DecodeOptions::new().read_info(output.as_slice()).unwrap();
+        // A grid of 4x2 dots, 7 pixels apart, with the radius and padding around it
+        assert_eq!((decoder.width(), decoder.height()), (29, 15));
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        assert_eq!(delays, [5, 5, 5]);
+    }
+
+    #[test]
+    fn stipple_gif_rejects_metrics_of_the_binary() {
+        let params = StippleParams {
+            metric: Metric::Blend,
+            ..StippleParams::default()
+        };
+        assert!(stipple_gif(&moving_square(1), &params).is_err());
+    }
+}
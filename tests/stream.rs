@@ -0,0 +1,124 @@
+//! Streams a long GIF from a file into another one and checks that the memory used along the
+//! way doesn't grow with the number of frames.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs::File,
+    io::BufWriter,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use gif::{Encoder, Frame, Repeat};
+use pointillist::{
+    color::Rgba,
+    convert::{ColorMode, ColorSpace, Metric, Reduction, convert_frame},
+    input::{GifFrame, GifFrameReader},
+    render::RenderOptions,
+    stream::stream_circles_gif,
+};
+use testing::TempDir;
+
+#[path = "../src/testing.rs"]
+mod testing;
+
+/// Counts the bytes allocated right now and the most there were at once.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const WIDTH: u16 = 32;
+const HEIGHT: u16 = 24;
+
+/// A GIF of gray diagonal stripes that move by a pixel on every frame.
+fn write_long_gif(path: &str, frames: usize) {
+    let palette: Vec<u8> = (0..=255).flat_map(|v| [v, v, v]).collect();
+    let mut encoder = Encoder::new(File::create(path).unwrap(), WIDTH, HEIGHT, &palette).unwrap();
+    encoder.set_repeat(Repeat::Infinite).unwrap();
+    for i in 0..frames {
+        let buffer: Vec<u8> = (0..HEIGHT as usize)
+            .flat_map(|y| (0..WIDTH as usize).map(move |x| ((x + y + i) * 8 % 256) as u8))
+            .collect();
+        let frame = Frame {
+            width: WIDTH,
+            height: HEIGHT,
+            buffer: buffer.into(),
+            delay: 10,
+            ..Frame::default()
+        };
+        encoder.write_frame(&frame).unwrap();
+    }
+}
+
+/// Streams the GIF at `input` into `output` and returns the most memory that was allocated
+/// at once, over what was allocated before.
+fn stream(input: &str, output: &str) -> usize {
+    let convert = |frame: &GifFrame| {
+        let key = |pixel: &Rgba| Metric::Brightness.key(pixel) as usize;
+        let (mode, space) = (ColorMode::Average, ColorSpace::Srgb);
+        convert_frame(frame, 4, (0, 0), Reduction::Mean, mode, space, &key)
+    };
+    let options = RenderOptions {
+        max_value: 255,
+        ..RenderOptions::default()
+    };
+
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let frames = GifFrameReader::open(input).unwrap();
+    let writer = BufWriter::new(File::create(output).unwrap());
+    let summary = stream_circles_gif(frames, writer, &convert, &options, 4).unwrap();
+    let peak = PEAK.load(Ordering::SeqCst) - before;
+    drop(summary);
+    peak
+}
+
+#[test]
+fn long_gifs_stream_in_flat_memory() {
+    let dir = TempDir::new("stream");
+    let (short, long, output) = (
+        dir.join("short.gif"),
+        dir.join("long.gif"),
+        dir.join("out.gif"),
+    );
+    write_long_gif(&short, 40);
+    write_long_gif(&long, 400);
+
+    let short_peak = stream(&short, &output);
+    let long_peak = stream(&long, &output);
+
+    let mut frames = 0;
+    let mut reader = GifFrameReader::open(&output).unwrap();
+    // 8x6 dots 18 pixels apart, with the radius and padding around them
+    assert_eq!(reader.screen_size(), (7 * 18 + 20, 5 * 18 + 20));
+    for frame in reader.by_ref() {
+        assert!(frame.unwrap().buffer.iter().any(|&(_, _, _, a)| a > 0));
+        frames += 1;
+    }
+    assert_eq!(frames, 400);
+
+    // Keeping the frames around would take ten times as much for ten times the frames
+    assert!(
+        long_peak < 2 * short_peak,
+        "{long_peak} bytes for 400 frames, {short_peak} for 40"
+    );
+}